@@ -0,0 +1,92 @@
+use std::collections::VecDeque;
+
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use transport_layer::command::Command;
+use transport_layer::frame::{handshake, read_frame, write_frame, ProtocolError};
+use transport_layer::response::Response;
+
+// What the reader task hands back to the app, keyed by request id
+pub enum Reply {
+    Response(u32, Response),
+    Disconnected(ProtocolError),
+}
+
+// Long lived connection bound to one user's session. Requests are written
+// as they are sent, replies are collected by a background reader so many
+// requests can be in flight at once. Dropping the connection stops the reader
+pub struct Connection {
+    writer: OwnedWriteHalf,
+    replies: UnboundedReceiver<Reply>,
+    // Replies read while waiting on a specific request, handed out later
+    stashed: VecDeque<Reply>,
+    next_request_id: u32,
+}
+impl Connection {
+    pub async fn open(address: &str, user_id: u8) -> Result<Self, ProtocolError> {
+        let mut stream = TcpStream::connect(address).await?;
+        handshake(&mut stream, user_id).await?;
+        let (mut reader, writer) = stream.into_split();
+        let (sender, replies) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let reply = match read_frame(&mut reader).await {
+                    Ok(frame) => match Response::from_frame(&frame) {
+                        Ok(response) => Reply::Response(frame.request_id, response),
+                        Err(err) => Reply::Disconnected(err),
+                    },
+                    Err(err) => Reply::Disconnected(err),
+                };
+                let done = matches!(reply, Reply::Disconnected(..));
+                if sender.send(reply).is_err() || done {
+                    break;
+                }
+            }
+        });
+        Ok(Self {
+            writer,
+            replies,
+            stashed: VecDeque::new(),
+            next_request_id: 1,
+        })
+    }
+    // Next reply that has arrived, if any, without waiting
+    pub fn try_recv(&mut self) -> Option<Reply> {
+        self.stashed
+            .pop_front()
+            .or_else(|| self.replies.try_recv().ok())
+    }
+    // Waits for the reply to one request, keeping any others for try_recv.
+    // Replies come in whatever order requests finish, it may already be kept
+    pub async fn wait_for(&mut self, request_id: u32) -> Result<Response, ProtocolError> {
+        let kept = self
+            .stashed
+            .iter()
+            .position(|reply| matches!(reply, Reply::Response(id, ..) if *id == request_id));
+        if let Some(Reply::Response(_, response)) = kept.and_then(|at| self.stashed.remove(at)) {
+            return Ok(response);
+        }
+        loop {
+            match self.replies.recv().await {
+                Some(Reply::Response(id, response)) if id == request_id => return Ok(response),
+                Some(Reply::Disconnected(err)) => return Err(err),
+                Some(reply) => self.stashed.push_back(reply),
+                None => return Err(ProtocolError::Malformed("connection closed")),
+            }
+        }
+    }
+    pub async fn request(&mut self, command: &Command) -> Result<Response, ProtocolError> {
+        let request_id = self.send(command).await?;
+        self.wait_for(request_id).await
+    }
+    // Sends a command without waiting for its reply, returning its request id
+    pub async fn send(&mut self, command: &Command) -> Result<u32, ProtocolError> {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        let frame = command.to_frame().with_request_id(request_id);
+        write_frame(&mut self.writer, &frame).await?;
+        Ok(request_id)
+    }
+}
@@ -1,7 +1,8 @@
-use std::str;
 /// This example is taken from https://raw.githubusercontent.com/fdehau/tui-rs/master/examples/user_input.rs
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+mod connection;
+mod transfer;
+
+use connection::{Connection, Reply};
 
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
@@ -16,11 +17,14 @@ use ratatui::{
     widgets::{Block, Borders, List, ListItem, Paragraph},
     Frame, Terminal,
 };
-use std::{error::Error, io};
+use std::{collections::HashMap, error::Error, io, path::Path, time::Duration};
 use tui_input::backend::crossterm::EventHandler;
 use tui_input::Input;
 
-use transport_layer::command::{Command, WRITE_DELIM};
+use transport_layer::command::Command;
+use transport_layer::response::{Body, Response};
+
+const SERVER_ADDRESS: &str = "127.0.0.1:8888";
 
 enum InputMode {
     Normal,
@@ -37,6 +41,10 @@ struct App {
     messages: Vec<String>,
     /// Active User id
     user: String,
+    /// Connection bound to the active user, opened on first use
+    connection: Option<Connection>,
+    /// Input that produced each request still awaiting a reply
+    pending: HashMap<u32, String>,
 }
 
 impl Default for App {
@@ -46,8 +54,88 @@ impl Default for App {
             input_mode: InputMode::Normal,
             messages: Vec::new(),
             user: "1".to_string(),
+            connection: None,
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl App {
+    fn user_code(&self) -> u8 {
+        match self.user.as_str() {
+            "1" => 1u8,
+            "2" => 2u8,
+            "3" => 3u8,
+            _ => u8::MAX,
+        }
+    }
+    // Opens the connection for the active user if there isn't one yet
+    async fn connection(&mut self) -> Option<&mut Connection> {
+        if self.connection.is_none() {
+            match Connection::open(SERVER_ADDRESS, self.user_code()).await {
+                Ok(connection) => self.connection = Some(connection),
+                Err(err) => {
+                    self.messages.push(format!("Error: {}", err));
+                    return None;
+                }
+            }
+        }
+        self.connection.as_mut()
+    }
+    // Sends without waiting, the reply is picked up by `drain_replies`
+    async fn submit(&mut self, command: &Command, input: String) {
+        let Some(connection) = self.connection().await else {
+            return;
+        };
+        match connection.send(command).await {
+            Ok(request_id) => {
+                self.pending.insert(request_id, input);
+            }
+            Err(err) => {
+                self.messages.push(format!("Error: {}", err));
+                self.disconnect();
+            }
+        }
+    }
+    // `put <host file> <path>` and `get <path> <host file>` stream whole files
+    async fn transfer(&mut self, direction: &str, from: &str, to: &str) {
+        let Some(connection) = self.connection().await else {
+            return;
+        };
+        let result = match direction {
+            "put" => transfer::upload(connection, Path::new(from), to).await,
+            _ => transfer::download(connection, from, Path::new(to)).await,
+        };
+        match result {
+            Ok(bytes) => self.messages.push(format!("Transferred {} bytes", bytes)),
+            Err(err) => self.messages.push(format!("Error: {}", err)),
         }
     }
+    fn drain_replies(&mut self) {
+        let mut replies = Vec::new();
+        if let Some(connection) = self.connection.as_mut() {
+            while let Some(reply) = connection.try_recv() {
+                replies.push(reply);
+            }
+        }
+        for reply in replies {
+            match reply {
+                Reply::Response(request_id, response) => {
+                    let input = self.pending.remove(&request_id).unwrap_or_default();
+                    self.messages
+                        .push(format!("Recieved ({}): {}", input, render(&response)));
+                }
+                Reply::Disconnected(err) => {
+                    self.messages.push(format!("Disconnected: {}", err));
+                    self.disconnect();
+                }
+            }
+        }
+    }
+    fn disconnect(&mut self) {
+        self.connection = None;
+        self.pending.clear();
+    }
 }
 
 #[tokio::main]
@@ -81,8 +169,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
 async fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Result<()> {
     loop {
-        terminal.draw(|f| ui::<B>(f, &app))?;
+        app.drain_replies();
+        terminal.draw(|f| ui(f, &app))?;
 
+        // Poll so replies keep landing while nothing is typed
+        if !event::poll(Duration::from_millis(50))? {
+            continue;
+        }
         if let Event::Key(key) = event::read()? {
             match app.input_mode {
                 InputMode::Normal => match key.code {
@@ -96,7 +189,6 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Re
                 },
                 InputMode::Editing => match key.code {
                     KeyCode::Enter => {
-                        let mut stream = TcpStream::connect("127.0.0.1:8888").await.unwrap();
                         app.messages.push(app.input.value().into());
                         //let mut parts_iter = app.input.value().clone();
 
@@ -115,64 +207,39 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Re
                             .collect::<Vec<&str>>()
                             .join(" ");
                         if !maybe_write_part.is_empty() {
-                            parts.push(&maybe_write_part.as_str());
+                            parts.push(maybe_write_part.as_str());
                         }
 
+                        if parts.len() == 3 && (parts[0] == "put" || parts[0] == "get") {
+                            let (direction, from, to) = (
+                                parts[0].to_string(),
+                                parts[1].to_string(),
+                                parts[2].to_string(),
+                            );
+                            app.transfer(&direction, &from, &to).await;
+                            app.input.reset();
+                            continue;
+                        }
                         let command = match parts.len() {
                             0 => Command::UNKNOWN,
                             1 => Command::from(app.input.value()),
-                            3 => {
-                                if parts[0] == "write" || parts[0] == "cp" || parts[0] =="mv"{
-                                    let mut combined = String::new();
-                                    combined.push_str(parts[1]);
-                                    combined.push_str(WRITE_DELIM);
-                                    combined.push_str(parts[2]);
-                                    Command::from((parts[0], combined.as_str()))
-                                } else {
-                                    Command::UNKNOWN
-                                }
-                            }
+                            3 => Command::from((parts[0], parts[1], parts[2])),
                             _ => Command::from((parts[0], parts[1])),
                         };
                         //For debugging
-                        //app.messages.push(format!("{:?}", &command.to_bytes()));
+                        //app.messages.push(format!("{:?}", &command.to_frame()));
                         match command {
                             Command::UNKNOWN => {
-                                app.messages.push(format!("command unknown"));
+                                app.messages.push("command unknown".to_string());
                             }
-                            Command::SU(user) =>{
+                            Command::SU(user) => {
+                                // Sessions are bound per connection, rebind on next send
                                 app.user = user;
+                                app.disconnect();
                             }
                             _ => {
-                                let user_code = match app.user.as_str() {
-                                    "1" => 1u8,
-                                    "2" => 2u8,
-                                    "3" => 3u8,
-                                    _ =>u8::MAX,
-                                };
-                                stream.write_all(&command.to_bytes(user_code)).await.unwrap();
-                                let mut buff = [0; 1];
-                                let read_payload = stream
-                                    .read_exact(&mut buff)
-                                    .await
-                                    .expect("Failed to read data from socket");
-                                if read_payload > 0 {
-                                    let payload_len = buff[0];
-                                    let mut payload_buffer = vec![0u8; payload_len as usize];
-                                    let read_data_len = stream
-                                        .read_exact(&mut payload_buffer)
-                                        .await
-                                        .expect("Failed to read data from socket");
-                                    if read_data_len > 0 {
-                                        let s = match str::from_utf8(
-                                            &payload_buffer[0..payload_len as usize],
-                                        ) {
-                                            Ok(v) => v,
-                                            Err(_) => "error",
-                                        };
-                                        app.messages.push(format!("Recieved: {}", s));
-                                    }
-                                }
+                                let input = app.input.value().to_string();
+                                app.submit(&command, input).await;
                             }
                         }
 
@@ -190,7 +257,67 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Re
     }
 }
 
-fn ui<B: Backend>(f: &mut Frame, app: &App) {
+fn render(response: &Response) -> String {
+    if !response.status.is_ok() {
+        return format!("{}: {}", response.status, response.message);
+    }
+    match &response.body {
+        Some(Body::Listing(entries)) if entries.is_empty() => response.message.clone(),
+        Some(Body::Listing(entries)) => entries.join("\n"),
+        Some(Body::Bytes(data)) => String::from_utf8_lossy(data).into_owned(),
+        Some(Body::Path(value)) | Some(Body::User(value)) => value.clone(),
+        Some(Body::Handle(handle)) => handle.to_string(),
+        Some(Body::Position(offset)) => offset.to_string(),
+        Some(Body::Stats(stats)) => stats
+            .iter()
+            .map(|stat| {
+                let line = format!(
+                    "{} {} {} {} {:>8} {} {}",
+                    stat.mode_string(),
+                    stat.links,
+                    stat.owner,
+                    stat.group,
+                    stat.size,
+                    format_time(stat.modified),
+                    stat.name
+                );
+                if stat.target.is_empty() {
+                    line
+                } else {
+                    format!("{} -> {}", line, stat.target)
+                }
+            })
+            .collect::<Vec<String>>()
+            .join("\n"),
+        None => response.message.clone(),
+    }
+}
+
+// UTC "YYYY-MM-DD HH:MM" from nanoseconds since the epoch
+fn format_time(nanos: u64) -> String {
+    let secs = nanos / 1_000_000_000;
+    let (days, rest) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since 1970-01-01, after Howard Hinnant's algorithm
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        rest / 3_600,
+        rest % 3_600 / 60
+    )
+}
+
+fn ui(f: &mut Frame, app: &App) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .margin(2)
@@ -263,7 +390,11 @@ fn ui<B: Backend>(f: &mut Frame, app: &App) {
         .iter()
         .enumerate()
         .map(|(i, m)| {
-            let content = vec![Line::from(Span::raw(format!("{}: {}", i, m)))];
+            // Multi line replies such as `ls -l` get a row each
+            let content = format!("{}: {}", i, m)
+                .lines()
+                .map(|line| Line::from(Span::raw(line.to_string())))
+                .collect::<Vec<Line>>();
             ListItem::new(content)
         })
         .collect();
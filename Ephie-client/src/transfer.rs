@@ -0,0 +1,119 @@
+use std::collections::VecDeque;
+use std::path::Path;
+
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::connection::Connection;
+use transport_layer::command::{Command, CHUNK_SIZE};
+use transport_layer::response::{Body, Response};
+
+// Turns a failed response into an error carrying its message
+fn check(response: Response) -> Result<Response, String> {
+    if response.status.is_ok() {
+        Ok(response)
+    } else {
+        Err(format!("{}: {}", response.status, response.message))
+    }
+}
+
+// Chunks sent ahead of their replies, the server only queues a few
+// requests and stops reading the rest
+const CHUNKS_IN_FLIGHT: usize = 8;
+
+// Streams a host file into the server a chunk at a time. Chunks carry their
+// offset so several are sent before their replies are awaited
+pub async fn upload(
+    connection: &mut Connection,
+    host_path: &Path,
+    target: &str,
+) -> Result<u64, String> {
+    let mut file = File::open(host_path).await.map_err(|err| err.to_string())?;
+    let opened = connection
+        .request(&Command::UPLOAD(target.to_string()))
+        .await
+        .map_err(|err| err.to_string())?;
+    let Some(Body::Handle(transfer)) = check(opened)?.body else {
+        return Err("server did not open a transfer".to_string());
+    };
+    let mut in_flight = VecDeque::new();
+    let mut offset = 0u64;
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .await
+            .map_err(|err| err.to_string())?;
+        if read == 0 {
+            break;
+        }
+        let chunk = Command::CHUNK {
+            transfer,
+            offset,
+            data: buffer[..read].to_vec(),
+        };
+        in_flight.push_back(
+            connection
+                .send(&chunk)
+                .await
+                .map_err(|err| err.to_string())?,
+        );
+        offset += read as u64;
+        if in_flight.len() == CHUNKS_IN_FLIGHT {
+            if let Some(request_id) = in_flight.pop_front() {
+                settle(connection, request_id).await?;
+            }
+        }
+    }
+    for request_id in in_flight {
+        settle(connection, request_id).await?;
+    }
+    let committed = connection
+        .request(&Command::COMMIT(transfer))
+        .await
+        .map_err(|err| err.to_string())?;
+    check(committed)?;
+    Ok(offset)
+}
+
+// Waits for a chunk to be written
+async fn settle(connection: &mut Connection, request_id: u32) -> Result<(), String> {
+    let response = connection
+        .wait_for(request_id)
+        .await
+        .map_err(|err| err.to_string())?;
+    check(response).map(|_| ())
+}
+
+// Pulls a server file down to the host a chunk at a time
+pub async fn download(
+    connection: &mut Connection,
+    target: &str,
+    host_path: &Path,
+) -> Result<u64, String> {
+    let mut file = File::create(host_path)
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut offset = 0u64;
+    loop {
+        let command = Command::DOWNLOAD {
+            path: target.to_string(),
+            offset,
+            len: CHUNK_SIZE as u32,
+        };
+        let response = connection
+            .request(&command)
+            .await
+            .map_err(|err| err.to_string())?;
+        let Some(Body::Bytes(data)) = check(response)?.body else {
+            return Err("server did not send file data".to_string());
+        };
+        file.write_all(&data).await.map_err(|err| err.to_string())?;
+        offset += data.len() as u64;
+        if data.len() < CHUNK_SIZE {
+            break;
+        }
+    }
+    file.flush().await.map_err(|err| err.to_string())?;
+    Ok(offset)
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use transport_layer::response::{Response, Status};
+
+// Failures raised by the trie and sessions, each carrying the offending path
+// (or handle, for operations that don't name one)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsError {
+    NotFound(PathBuf),
+    NotADirectory(PathBuf),
+    IsADirectory(PathBuf),
+    InvalidPath(PathBuf),
+    FileTooLarge(PathBuf),
+    InvalidHandle(u32),
+    // The file behind an open handle has been removed
+    StaleHandle(u32),
+    // A seek before the start of the file
+    InvalidSeek(u32),
+    LinkLoop(PathBuf),
+    AlreadyExists(PathBuf),
+    NotALink(PathBuf),
+    NotPermitted(PathBuf),
+    IntoDescendant(PathBuf),
+    SameFile(PathBuf),
+    NotEmpty(PathBuf),
+    NoMatch(PathBuf),
+    // Lock held by someone else past the wait, or waiting for it would
+    // never end
+    WouldBlock(PathBuf),
+    Deadlock(PathBuf),
+    // A write into a snapshot
+    ReadOnly(PathBuf),
+    // A search pattern that doesn't compile, with the reason why
+    InvalidPattern(String),
+    // A change the log couldn't take, so it wasn't made
+    NotLogged(String),
+}
+impl FsError {
+    pub fn path(&self) -> Option<&PathBuf> {
+        match self {
+            Self::NotFound(path)
+            | Self::NotADirectory(path)
+            | Self::IsADirectory(path)
+            | Self::InvalidPath(path)
+            | Self::FileTooLarge(path)
+            | Self::LinkLoop(path)
+            | Self::AlreadyExists(path)
+            | Self::NotALink(path)
+            | Self::NotPermitted(path)
+            | Self::IntoDescendant(path)
+            | Self::SameFile(path)
+            | Self::NotEmpty(path)
+            | Self::NoMatch(path)
+            | Self::WouldBlock(path)
+            | Self::Deadlock(path)
+            | Self::ReadOnly(path) => Some(path),
+            Self::InvalidHandle(..)
+            | Self::StaleHandle(..)
+            | Self::InvalidSeek(..)
+            | Self::InvalidPattern(..)
+            | Self::NotLogged(..) => None,
+        }
+    }
+    pub fn status(&self) -> Status {
+        match self {
+            Self::NotFound(..) => Status::NotFound,
+            Self::NotADirectory(..) => Status::NotADirectory,
+            Self::IsADirectory(..) => Status::IsADirectory,
+            Self::InvalidPath(..) => Status::InvalidPath,
+            Self::FileTooLarge(..) => Status::TooLarge,
+            Self::InvalidHandle(..) => Status::InvalidInput,
+            Self::StaleHandle(..) => Status::NotFound,
+            Self::InvalidSeek(..) => Status::InvalidInput,
+            Self::LinkLoop(..) => Status::TooManyLinks,
+            Self::AlreadyExists(..) => Status::AlreadyExists,
+            Self::NotALink(..) => Status::InvalidInput,
+            Self::NotPermitted(..) => Status::PermissionDenied,
+            Self::IntoDescendant(..) | Self::SameFile(..) => Status::InvalidInput,
+            // POSIX lets rename report this one as either
+            Self::NotEmpty(..) => Status::AlreadyExists,
+            Self::NoMatch(..) => Status::NotFound,
+            Self::WouldBlock(..) | Self::Deadlock(..) => Status::WouldBlock,
+            Self::ReadOnly(..) => Status::ReadOnly,
+            Self::InvalidPattern(..) => Status::InvalidInput,
+            Self::NotLogged(..) => Status::Internal,
+        }
+    }
+}
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Self::NotFound(..) => "No such file or directory",
+            Self::NotADirectory(..) => "Not a directory",
+            Self::IsADirectory(..) => "Is a directory",
+            Self::InvalidPath(..) => "Invalid path",
+            Self::FileTooLarge(..) => "File too large",
+            Self::InvalidHandle(..) => "Invalid handle",
+            Self::StaleHandle(..) => "Stale file handle",
+            Self::InvalidSeek(..) => "Invalid seek",
+            Self::LinkLoop(..) => "Too many levels of symbolic links",
+            Self::AlreadyExists(..) => "File exists",
+            Self::NotALink(..) => "Not a symbolic link",
+            Self::NotPermitted(..) => "Operation not permitted",
+            Self::IntoDescendant(..) => "Cannot move a directory into itself",
+            Self::SameFile(..) => "Source and destination are the same file",
+            Self::NotEmpty(..) => "Directory not empty",
+            Self::NoMatch(..) => "No matches found",
+            Self::WouldBlock(..) => "Resource temporarily unavailable",
+            Self::Deadlock(..) => "Resource deadlock avoided",
+            Self::ReadOnly(..) => "Read-only file system",
+            Self::InvalidPattern(..) => "Invalid pattern",
+            Self::NotLogged(..) => "Change not logged, nothing was changed",
+        };
+        match self {
+            Self::InvalidHandle(handle) | Self::StaleHandle(handle) | Self::InvalidSeek(handle) => {
+                write!(f, "handle {}: {}", handle, reason)
+            }
+            Self::InvalidPattern(why) | Self::NotLogged(why) => write!(f, "{}: {}", reason, why),
+            _ => write!(f, "{}: {}", self.path().unwrap().display(), reason),
+        }
+    }
+}
+impl std::error::Error for FsError {}
+impl From<FsError> for Response {
+    fn from(value: FsError) -> Self {
+        Response::error(value.status(), value.to_string())
+    }
+}
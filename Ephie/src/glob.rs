@@ -0,0 +1,209 @@
+/*
+Shell style wildcards.
+`*` is any run of characters, `?` any one, `[abc]`, `[a-z]` and `[!a]` a set,
+and `\` takes the next character literally. In a path, `**` on its own spans
+any number of directories. Like a shell, wildcards skip names starting with
+`.` unless the pattern itself does.
+ */
+use std::path::{Component, Path, PathBuf};
+
+use crate::trie::{FsLike, Trie};
+
+// Whether text holds an unescaped wildcard, plain names are taken literally
+pub fn is_pattern(text: &str) -> bool {
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' | '?' | '[' => return true,
+            '\\' => {
+                chars.next();
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+// Pattern matching path and nothing else, for a path that's expanded
+pub fn escape(path: &Path) -> String {
+    let text = path.to_string_lossy();
+    if !is_pattern(&text) {
+        return text.into_owned();
+    }
+    let mut out = String::new();
+    for c in text.chars() {
+        match c {
+            '*' | '?' | '[' => out.extend(['[', c, ']']),
+            '\\' => out.push_str("\\\\"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// Every existing path matching the absolute pattern, sorted. Links are
+// followed on the way down but `**` never descends through one
+pub fn expand(fs: &Trie, pattern: &Path) -> Vec<PathBuf> {
+    let parts = pattern
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let mut found = vec![PathBuf::from("/")];
+    for (index, part) in parts.iter().enumerate() {
+        let last = index + 1 == parts.len();
+        let mut next = Vec::new();
+        for path in found {
+            if part == "**" {
+                // Zero directories too, unless nothing would follow
+                if !last {
+                    next.push(path.clone());
+                }
+                descend(fs, &path, last, &mut next);
+            } else if is_pattern(part) {
+                for (name, _) in entries(fs, &path) {
+                    if visible(part, &name) && matches(part, &name) {
+                        next.push(path.join(name));
+                    }
+                }
+            } else {
+                let path = path.join(unescape(part));
+                if fs.get_link(&path).is_some() {
+                    next.push(path);
+                }
+            }
+        }
+        found = next;
+    }
+    found.sort();
+    found.dedup();
+    found
+}
+
+// Entries of the directory at path and whether each is one too, following
+// a link to it
+fn entries(fs: &Trie, path: &Path) -> Vec<(String, bool)> {
+    let Some(dir) = fs.get(path) else {
+        return Vec::new();
+    };
+    let Some(children) = dir.children() else {
+        return Vec::new();
+    };
+    children
+        .iter()
+        .filter_map(|(name, id)| {
+            let is_dir = matches!(*fs.node(*id)?, FsLike::DirectoryLike { .. });
+            Some((name.to_string_lossy().into_owned(), is_dir))
+        })
+        .collect()
+}
+
+// Adds everything visible below path, or only directories when more of
+// the pattern follows
+fn descend(fs: &Trie, path: &Path, everything: bool, out: &mut Vec<PathBuf>) {
+    for (name, is_dir) in entries(fs, path) {
+        if name.starts_with('.') {
+            continue;
+        }
+        let child = path.join(name);
+        if everything || is_dir {
+            out.push(child.clone());
+        }
+        if is_dir {
+            descend(fs, &child, everything, out);
+        }
+    }
+}
+
+fn visible(pattern: &str, name: &str) -> bool {
+    !name.starts_with('.') || pattern.starts_with('.')
+}
+
+fn unescape(part: &str) -> String {
+    let mut out = String::new();
+    let mut chars = part.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let name = name.chars().collect::<Vec<char>>();
+    matches_from(&pattern, &name)
+}
+
+// Walks both once, on a mismatch only the last `*` seen takes one more
+// character, so the cost stays at pattern length times name length
+fn matches_from(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, n));
+        } else if let Some(next) = step(pattern, p, name[n]) {
+            p = next;
+            n += 1;
+        } else if let Some((after, taken)) = star {
+            p = after;
+            n = taken + 1;
+            star = Some((after, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// Where the pattern continues if the item at p matches c
+fn step(pattern: &[char], p: usize, c: char) -> Option<usize> {
+    match pattern.get(p)? {
+        '*' => None,
+        '?' => Some(p + 1),
+        '[' => match set_end(&pattern[p..]) {
+            Some(end) => in_set(&pattern[p + 1..p + end], c).then_some(p + end + 1),
+            // An unclosed bracket is just a bracket
+            None => (c == '[').then_some(p + 1),
+        },
+        '\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        literal => (*literal == c).then_some(p + 1),
+    }
+}
+
+// Index of the `]` closing the set opened at pattern[0], a `]` right after
+// the opening (or its negation) is part of the set
+fn set_end(pattern: &[char]) -> Option<usize> {
+    let mut start = 1;
+    if matches!(pattern.get(start), Some('!' | '^')) {
+        start += 1;
+    }
+    (start + 1..pattern.len())
+        .find(|index| pattern[*index] == ']')
+        .filter(|_| pattern.len() > start)
+}
+
+fn in_set(set: &[char], c: char) -> bool {
+    let (negated, set) = match set.first() {
+        Some('!' | '^') => (true, &set[1..]),
+        _ => (false, set),
+    };
+    let mut found = false;
+    let mut index = 0;
+    while index < set.len() {
+        if index + 2 < set.len() && set[index + 1] == '-' {
+            found |= set[index] <= c && c <= set[index + 2];
+            index += 3;
+        } else {
+            found |= set[index] == c;
+            index += 1;
+        }
+    }
+    found != negated
+}
@@ -0,0 +1,149 @@
+/*
+Inverted trigram index over file contents.
+Every run of three bytes in a file, ASCII lowercased, maps to the inodes
+holding it, so a search only reads files holding every trigram of the text
+its pattern requires. Partial writes only add trigrams, the index may list a
+file that no longer matches but never misses one that does.
+ */
+use std::collections::{HashMap, HashSet};
+
+use crate::trie::InodeId;
+
+type Trigram = [u8; 3];
+
+#[derive(Debug, Clone, Default)]
+pub struct TrigramIndex {
+    postings: HashMap<Trigram, HashSet<InodeId>>,
+    // Trigrams recorded for each file, so they can be taken back out
+    grams: HashMap<InodeId, HashSet<Trigram>>,
+}
+impl TrigramIndex {
+    // Replaces whatever was recorded for id with the trigrams of data
+    pub fn set(&mut self, id: InodeId, data: &[u8]) {
+        self.forget(id);
+        self.add(id, data);
+    }
+    // Records the trigrams of data for id on top of those already there
+    pub fn add(&mut self, id: InodeId, data: &[u8]) {
+        let grams = self.grams.entry(id).or_default();
+        for gram in trigrams(data) {
+            if grams.insert(gram) {
+                self.postings.entry(gram).or_default().insert(id);
+            }
+        }
+    }
+    pub fn forget(&mut self, id: InodeId) {
+        let Some(grams) = self.grams.remove(&id) else {
+            return;
+        };
+        for gram in grams {
+            if let Some(ids) = self.postings.get_mut(&gram) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.postings.remove(&gram);
+                }
+            }
+        }
+    }
+    // Files that may hold every literal, None when the literals are too
+    // short to rule any file out
+    pub fn candidates(&self, literals: &[String]) -> Option<HashSet<InodeId>> {
+        let mut found: Option<HashSet<InodeId>> = None;
+        for gram in literals
+            .iter()
+            .flat_map(|literal| trigrams(literal.as_bytes()))
+        {
+            let ids = self.postings.get(&gram).cloned().unwrap_or_default();
+            found = Some(match found {
+                Some(found) => found.intersection(&ids).copied().collect(),
+                None => ids,
+            });
+        }
+        found
+    }
+}
+
+fn trigrams(data: &[u8]) -> impl Iterator<Item = Trigram> + '_ {
+    data.windows(3).map(|window| {
+        [
+            window[0].to_ascii_lowercase(),
+            window[1].to_ascii_lowercase(),
+            window[2].to_ascii_lowercase(),
+        ]
+    })
+}
+
+// Runs of plain text every match of the regex pattern must contain. Groups,
+// classes, escapes and anything made optional by a quantifier are skipped.
+// None when no such text can be told, like with alternation or inline flags
+pub fn required_literals(pattern: &str, ignore_case: bool) -> Option<Vec<String>> {
+    if pattern.contains('|') || pattern.contains("(?") || !pattern.is_ascii() {
+        return None;
+    }
+    let mut literals = Vec::new();
+    let mut run = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                // Code points and Unicode classes run past the next character
+                if let Some('x' | 'u' | 'U' | 'p' | 'P') = chars.next() {
+                    return None;
+                }
+            }
+            '[' => skip_class(&mut chars),
+            '(' => {
+                let mut depth = 1;
+                for c in chars.by_ref() {
+                    match c {
+                        '(' => depth += 1,
+                        ')' if depth == 1 => break,
+                        ')' => depth -= 1,
+                        _ => {}
+                    }
+                }
+            }
+            '?' | '*' => {
+                run.pop();
+            }
+            '{' => {
+                run.pop();
+                chars.by_ref().find(|c| *c == '}');
+            }
+            '.' | '^' | '$' | '+' => {}
+            // Case folding takes these to the Kelvin sign and long s, which
+            // the index doesn't lowercase
+            'k' | 'K' | 's' | 'S' if ignore_case => {}
+            c => {
+                run.push(c);
+                continue;
+            }
+        }
+        // Anything but plain text ends the run
+        literals.push(std::mem::take(&mut run));
+    }
+    literals.push(run);
+    literals.retain(|literal| literal.len() >= 3);
+    Some(literals)
+}
+
+// Moves past the class just opened, nested ones like `[[:alpha:]]` included.
+// A `]` first in a class, or right after its `^`, is part of it
+fn skip_class(chars: &mut std::str::Chars) {
+    let mut depth = 1;
+    let mut first = true;
+    while let Some(c) = chars.next() {
+        match c {
+            '^' if first => continue,
+            ']' if first => {}
+            '\\' => {
+                chars.next();
+            }
+            '[' => depth += 1,
+            ']' if depth == 1 => return,
+            ']' => depth -= 1,
+            _ => {}
+        }
+        first = false;
+    }
+}
@@ -0,0 +1,160 @@
+/*
+Advisory locks shared by every session, like flock.
+Locks are held per connection on inodes, so a path and a handle to the same
+file contend and a rename changes nothing. Nothing stops I/O on a locked
+file, it's up to clients to ask first.
+ */
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use transport_layer::command::LockKind;
+
+use crate::error::FsError;
+use crate::session::ConnectionId;
+use crate::system::Recover;
+use crate::trie::InodeId;
+
+// Longest a lock request waits when the client doesn't say
+pub const MAX_LOCK_WAIT: Duration = Duration::from_secs(60);
+
+pub type Locks = Arc<LockTable>;
+
+#[derive(Debug)]
+enum Held {
+    Shared(HashSet<ConnectionId>),
+    Exclusive(ConnectionId),
+}
+impl Held {
+    fn holders(&self) -> Vec<ConnectionId> {
+        match self {
+            Self::Shared(holders) => holders.iter().copied().collect(),
+            Self::Exclusive(holder) => vec![*holder],
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    held: HashMap<InodeId, Held>,
+    // The inode each blocked connection is waiting on
+    waiting: HashMap<ConnectionId, InodeId>,
+}
+impl State {
+    // Takes the lock if nothing in the way, a holder may convert its own
+    fn grant(&mut self, inode: InodeId, kind: LockKind, owner: ConnectionId) -> bool {
+        let held = match (self.held.get_mut(&inode), kind) {
+            (None, LockKind::Shared) => Held::Shared(HashSet::from([owner])),
+            (None, LockKind::Exclusive) => Held::Exclusive(owner),
+            (Some(Held::Exclusive(holder)), LockKind::Shared) if *holder == owner => {
+                Held::Shared(HashSet::from([owner]))
+            }
+            (Some(Held::Exclusive(holder)), LockKind::Exclusive) => return *holder == owner,
+            (Some(Held::Exclusive(..)), LockKind::Shared) => return false,
+            (Some(Held::Shared(holders)), LockKind::Shared) => {
+                holders.insert(owner);
+                return true;
+            }
+            (Some(Held::Shared(holders)), LockKind::Exclusive) => {
+                if holders.iter().any(|holder| *holder != owner) {
+                    return false;
+                }
+                Held::Exclusive(owner)
+            }
+        };
+        self.held.insert(inode, held);
+        true
+    }
+    // Whether owner waiting on inode closes a cycle of connections each
+    // waiting on a lock the next one holds
+    fn would_deadlock(&self, inode: InodeId, owner: ConnectionId) -> bool {
+        let holders = |inode: &InodeId| self.held.get(inode).map(Held::holders);
+        let mut pending = holders(&inode).unwrap_or_default();
+        pending.retain(|holder| *holder != owner);
+        let mut seen = HashSet::new();
+        while let Some(holder) = pending.pop() {
+            if holder == owner {
+                return true;
+            }
+            if !seen.insert(holder) {
+                continue;
+            }
+            if let Some(next) = self.waiting.get(&holder).and_then(holders) {
+                pending.extend(next.into_iter().filter(|next| *next != holder));
+            }
+        }
+        false
+    }
+    fn release(&mut self, inode: InodeId, owner: ConnectionId) {
+        let emptied = match self.held.get_mut(&inode) {
+            Some(Held::Shared(holders)) => {
+                holders.remove(&owner);
+                holders.is_empty()
+            }
+            Some(Held::Exclusive(holder)) => *holder == owner,
+            None => false,
+        };
+        if emptied {
+            self.held.remove(&inode);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LockTable {
+    state: Mutex<State>,
+    // Signalled whenever a lock is let go
+    released: Condvar,
+}
+impl LockTable {
+    // Blocks until owner holds the lock or wait runs out, a zero wait only
+    // tries once. Waiting that could never end fails straight away. Path
+    // only names the file in errors
+    pub fn lock(
+        &self,
+        inode: InodeId,
+        kind: LockKind,
+        owner: ConnectionId,
+        wait: Duration,
+        path: &Path,
+    ) -> Result<(), FsError> {
+        let deadline = Instant::now() + wait;
+        let mut state = self.state.lock().recover();
+        let result = loop {
+            if state.grant(inode, kind, owner) {
+                break Ok(());
+            }
+            if state.would_deadlock(inode, owner) {
+                break Err(FsError::Deadlock(path.to_path_buf()));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break Err(FsError::WouldBlock(path.to_path_buf()));
+            }
+            state.waiting.insert(owner, inode);
+            state = self
+                .released
+                .wait_timeout(state, deadline - now)
+                .recover()
+                .0;
+        };
+        state.waiting.remove(&owner);
+        result
+    }
+    // Letting go of a lock that isn't held is fine, like flock
+    pub fn unlock(&self, inode: InodeId, owner: ConnectionId) {
+        self.state.lock().recover().release(inode, owner);
+        self.released.notify_all();
+    }
+    // Every lock owner holds, called once its connection is gone
+    pub fn release_all(&self, owner: ConnectionId) {
+        let mut state = self.state.lock().recover();
+        let inodes = state.held.keys().copied().collect::<Vec<_>>();
+        for inode in inodes {
+            state.release(inode, owner);
+        }
+        drop(state);
+        self.released.notify_all();
+    }
+}
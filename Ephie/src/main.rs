@@ -1,167 +1,574 @@
+mod error;
+mod glob;
+mod index;
+mod locks;
+mod path;
 mod session;
+mod snapshot;
 mod system;
+#[cfg(test)]
 mod test;
 mod trie;
+mod wal;
 use dashmap::DashMap;
-use session::Session;
-use std::path::PathBuf;
-use std::str;
-use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use error::FsError;
+use locks::{Locks, MAX_LOCK_WAIT};
+use session::{ConnectionId, Session};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use system::{FileSystem, Recover};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
-use transport_layer::command::{Command, WRITE_DELIM};
-use trie::FsLike;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, Notify};
+use transport_layer::command::{Command, LockKind, LockTarget, SnapshotAction};
+use transport_layer::frame::{
+    legacy_rejection, read_frame, write_frame, Frame, ProtocolError, MAX_PAYLOAD_LEN, OP_HELLO,
+    PROTOCOL_VERSION,
+};
+use transport_layer::response::{Body, Response, Stat, Status};
+use trie::Trie;
+use wal::{Checkpoint, Journal, Log, Record, Wal};
+
+// Tells connections apart, several may share one user's session
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+// How often the tree is saved when it has changed, unless told otherwise
+const SNAPSHOT_EVERY: Duration = Duration::from_secs(60);
+// Requests read ahead of those running, the socket isn't read while it's full
+const QUEUED: usize = 8;
+// Requests of one connection running at once
+const IN_FLIGHT: u32 = 8;
+
+// Sessions by user. An entry is only held long enough to take its session
+// out, the session itself is locked off the runtime like the tree under it
+type Sessions = Arc<DashMap<u8, Arc<Mutex<Session>>>>;
+
 #[tokio::main]
 async fn main() {
     let listener = TcpListener::bind("127.0.0.1:8888").await.unwrap();
 
-    let mut system = FsLike::new();
-    let mut sessions = DashMap::<u8, Session>::new();
-    system
-        .insert(PathBuf::from("/"), FsLike::new())
-        .expect("Failed to insert");
-    let db = Arc::new(Mutex::new(system));
-    let session = Session::new("TestUser".to_string(), db.clone());
-
-    sessions.insert(1, session);
-    sessions.insert(2, Session::new("Liz".to_string(), db.clone()));
-    sessions.insert(3, Session::new("Emily".to_string(), db.clone()));
+    // Content search reads every file unless asked to keep an index
+    let indexed = std::env::args().any(|arg| arg == "--index");
+    // Everything is lost on exit unless the tree is kept in a data file
+    let data_file = arg_value("--data-file").map(PathBuf::from);
+    let (system, checkpoint) = match &data_file {
+        Some(path) if path.exists() => snapshot::load(path, indexed).unwrap_or_else(|err| {
+            eprintln!("failed to load {}: {err}", path.display());
+            std::process::exit(1)
+        }),
+        _ if indexed => (Trie::with_index(), Checkpoint::whole(0)),
+        _ => (Trie::new(), Checkpoint::whole(0)),
+    };
+    let sessions = DashMap::new();
+    let db = Arc::new(RwLock::new(system));
+    // Changes since the last snapshot are in the log beside it
+    let journal = data_file.as_ref().map(|path| {
+        let log = wal::path_for(path);
+        let (wal, records) = Wal::open(&log, checkpoint).unwrap_or_else(|err| {
+            eprintln!("failed to open {}: {err}", log.display());
+            std::process::exit(1)
+        });
+        let saved = db.read().recover().changes();
+        replay(&db, records);
+        let journal = Arc::new(Log::new(wal));
+        let every = arg_value("--snapshot-every")
+            .and_then(|secs| secs.parse().ok())
+            .map_or(SNAPSHOT_EVERY, Duration::from_secs);
+        tokio::spawn(snapshots(
+            db.clone(),
+            journal.clone(),
+            path.clone(),
+            every,
+            saved,
+        ));
+        journal
+    });
+    let locks = Locks::default();
+    for (user, name) in [(1, "TestUser"), (2, "Liz"), (3, "Emily")] {
+        let mut session = Session::new(name.to_string(), db.clone(), locks.clone());
+        session.journal = journal.clone();
+        sessions.insert(user, Arc::new(Mutex::new(session)));
+    }
 
-    let users = Arc::new(sessions);
+    let users: Sessions = Arc::new(sessions);
     //TODO hashmap of sessions
 
+    let shutdown = shutdown();
+    tokio::pin!(shutdown);
     loop {
-        let (socket, _) = listener.accept().await.unwrap();
+        let (socket, _) = tokio::select! {
+            accepted = listener.accept() => accepted.unwrap(),
+            _ = &mut shutdown => break,
+        };
         let local_sessions = users.clone();
         tokio::spawn(async move {
             process(socket, local_sessions).await;
         });
     }
+    if let (Some(path), Some(journal)) = (data_file, journal) {
+        let _ = tokio::task::spawn_blocking(move || save(&db, &journal, &path, None)).await;
+    }
+}
+
+// Value given after flag on the command line
+fn arg_value(flag: &str) -> Option<String> {
+    let mut args = std::env::args();
+    args.find(|arg| arg == flag)?;
+    args.next()
 }
 
-async fn process(mut socket: TcpStream, session_ref: Arc<DashMap<u8, Session>>) {
+// Resolves on ctrl-c or a plain kill
+async fn shutdown() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+// Saves the tree to path every period it has changed in
+async fn snapshots(
+    fs: FileSystem,
+    journal: Journal,
+    path: PathBuf,
+    every: Duration,
+    mut saved: u64,
+) {
+    let mut ticks = tokio::time::interval(every);
+    // The first tick is right away
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        let (fs, journal, path) = (fs.clone(), journal.clone(), path.clone());
+        let since = Some(saved);
+        let saving = tokio::task::spawn_blocking(move || save(&fs, &journal, &path, since));
+        if let Ok(Some(now)) = saving.await {
+            saved = now;
+        }
+    }
+}
+
+// Saves the tree to path unless it's as it was at change count since, then
+// starts the log over, and returns the change count now on disk. The tree
+// is only held to take a copy of it and where the log is at, so sessions
+// carry on while the copy is written. What they log meanwhile is kept when
+// the log starts over
+fn save(fs: &FileSystem, journal: &Journal, path: &Path, since: Option<u64>) -> Option<u64> {
+    let _saving = journal.saving();
+    let (copy, checkpoint) = {
+        // Changes are logged holding the tree, so it's taken before the log
+        let mut tree = fs.write().recover();
+        let wal = journal.wal();
+        if since.is_some() && since == Some(tree.changes()) {
+            return since;
+        }
+        let checkpoint = Checkpoint {
+            generation: wal.generation() + 1,
+            end: wal.end(),
+        };
+        (tree.share(), checkpoint)
+    };
+    let changes = copy.changes();
+    let bytes = snapshot::encode(&copy, checkpoint);
+    drop(copy);
+    let saved = snapshot::write(path, &bytes)
+        .and_then(|_| journal.wal().compact(checkpoint))
+        .map(|_| changes);
+    match saved {
+        Ok(changes) => Some(changes),
+        Err(err) => {
+            println!("failed to save snapshot to {}: {err}", path.display());
+            since
+        }
+    }
+}
+
+// A reply too big for one frame is answered with an error saying so, rather
+// than failing to send it and losing the connection
+fn fit(reply: Frame) -> Frame {
+    if reply.payload.len() <= MAX_PAYLOAD_LEN as usize {
+        return reply;
+    }
+    let message = format!(
+        "reply of {} bytes exceeds {MAX_PAYLOAD_LEN}",
+        reply.payload.len()
+    );
+    Response::error(Status::TooLarge, message)
+        .to_frame(reply.opcode)
+        .with_request_id(reply.request_id)
+}
+
+// Serves one connection until the peer hangs up. Requests are read ahead
+// and started in the order they were sent. Reads run beside each other, a
+// change waits for everything before it and holds up everything after, so
+// each request sees what the ones sent before it did. Replies are tagged
+// with the id of the request they answer, in whatever order they finish
+async fn process(mut socket: TcpStream, session_ref: Sessions) {
     println!("Processing");
-    let mut buff = [0; 1];
-    let mut out = String::new();
-    socket
-        .read_exact(&mut buff)
-        .await
-        .expect("failed to read user from socket");
-    let mut session = session_ref.get_mut(&buff[0]).unwrap();
-    let command_code = socket
-        .read_exact(&mut buff)
-        .await
-        .expect("Failed to read data from socket");
-    if command_code > 0 {
-        let command = buff[0];
-        println!("read command:{}", &command);
-        let read_payload = socket
-            .read_exact(&mut buff)
-            .await
-            .expect("Failed to read data from socket");
-        if read_payload > 0 {
-            let payload_len = buff[0];
-            println!("read payload length:{}", &payload_len);
-            let mut payload_buffer = vec![0u8; payload_len as usize];
-            let mut read_data_len = socket
-                .read_exact(&mut payload_buffer)
-                .await
-                .expect("Failed to read data from socket");
-            if read_data_len > 0 {
-                let s = match str::from_utf8(&payload_buffer[0..payload_len as usize]) {
-                    Ok(v) => v,
-                    Err(_) => "error",
+    let Some(user) = handshake(&mut socket, &session_ref).await else {
+        return;
+    };
+    let connection = NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed);
+    let (mut reader, mut writer) = socket.into_split();
+    let (replies, mut outbox) = mpsc::channel::<Frame>(IN_FLIGHT as usize);
+    // Set once a command panics, nothing more is read after
+    let closing = Arc::new(Notify::new());
+    let sender = tokio::spawn(async move {
+        while let Some(frame) = outbox.recv().await {
+            if let Err(err) = write_frame(&mut writer, &fit(frame)).await {
+                println!("failed to send reply: {err}");
+                break;
+            }
+        }
+    });
+    let (requests, mut queue) = mpsc::channel::<Frame>(QUEUED);
+    let sessions = session_ref.clone();
+    let stop = closing.clone();
+    let worker = tokio::spawn(async move {
+        // Reads share it and changes hold it, it's handed out first come
+        // first served so requests start in the order they were sent
+        let order = Arc::new(tokio::sync::RwLock::with_max_readers((), IN_FLIGHT));
+        while let Some(frame) = queue.recv().await {
+            let command = Command::from_frame(&frame);
+            let reads = matches!(&command, Ok(command) if reads_only(command));
+            let (sessions, replies, stop) = (sessions.clone(), replies.clone(), stop.clone());
+            let request = async move {
+                let (response, failed) = match command {
+                    Ok(command) => match respond(&sessions, user, connection, command).await {
+                        Ok(response) => (response, false),
+                        Err(response) => (response, true),
+                    },
+                    Err(err) => (
+                        Response::error(Status::InvalidInput, err.to_string()),
+                        false,
+                    ),
                 };
-                out.push_str(s);
+                let reply = response
+                    .to_frame(frame.opcode)
+                    .with_request_id(frame.request_id);
+                // The connection may already be gone, nobody is left to tell
+                let _ = replies.send(reply).await;
+                if failed {
+                    stop.notify_one();
+                }
+            };
+            if reads {
+                let turn = order.clone().read_owned().await;
+                tokio::spawn(async move {
+                    request.await;
+                    drop(turn);
+                });
+            } else {
+                let _turn = order.write().await;
+                request.await;
             }
         }
-        println!("command:{command}\n {out}");
-        let parsed_command = Command::from((command, out));
-        let message = match parsed_command {
-            Command::CD(target) => match session.change_dir(target) {
-                Err(message) => message.to_string(),
-                Ok(()) => "".to_string(),
-            },
-            Command::PWD => session.current_dir().to_str().unwrap().to_string(),
-            Command::MKDIR(target) => match session.make_dir(target) {
-                Err(message) => message.to_string(),
-                Ok(()) => "".to_string(),
-            },
-            Command::WHO => session.current_user().to_string(),
-            Command::LS => {
-                let out = session
-                    .list()
-                    .into_iter()
-                    .collect::<Vec<String>>()
-                    .join(" | ");
-                println!("out is {:#?}", out);
-                out.clone()
+        // Every request has replied once nothing shares it anymore
+        let _ = order.write().await;
+    });
+    loop {
+        let read = tokio::select! {
+            read = read_frame(&mut reader) => read,
+            _ = closing.notified() => break,
+        };
+        let frame = match read {
+            Ok(frame) => frame,
+            Err(ProtocolError::Io(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => {
+                println!("failed to read command: {err}");
+                break;
             }
-            Command::RM(target) => match session.remove(target) {
-                Err(message) => message.to_string(),
-                Ok(()) => "".to_string(),
-            },
-            Command::TOUCH(target) => match session.touch(target) {
-                Err(message) => message.to_string(),
-                Ok(()) => "".to_string(),
-            },
-            Command::READ(target) => match session.read_file(target) {
-                Err(message) => message.to_string(),
-                Ok(data) => match str::from_utf8(&data) {
-                    Ok(v) => v.to_string(),
-                    Err(_) => "Error Reading out bytes".to_string(),
-                },
-            },
-            Command::WRITE(target) => {
-                let parts = target.split(WRITE_DELIM).collect::<Vec<&str>>();
-                if parts.len() == 2 {
-                    match session.write_file(parts[0].to_string(), parts[1].to_string()) {
-                        Err(message) => message.to_string(),
-                        Ok(()) => "".to_string(),
-                    }
-                } else {
-                    "Mismatched input".to_string()
-                }
+        };
+        println!("read command:{} request:{}", frame.opcode, frame.request_id);
+        if requests.send(frame).await.is_err() {
+            break;
+        }
+    }
+    // The sender finishes once every queued request has replied
+    drop(requests);
+    let _ = worker.await;
+    let _ = sender.await;
+    if let Some(session) = session_of(&session_ref, user) {
+        let _ = blocking(session, move |session| {
+            session.lock().recover().close_connection(connection);
+            Response::ok()
+        })
+        .await;
+    }
+    println!("Connection for user {user} closed");
+}
+
+fn session_of(sessions: &Sessions, user: u8) -> Option<Arc<Mutex<Session>>> {
+    sessions.get(&user).map(|session| session.clone())
+}
+
+// Answers one command from the user's connection, an error means the
+// command panicked and the connection should close once it's sent
+async fn respond(
+    sessions: &Sessions,
+    user: u8,
+    connection: ConnectionId,
+    command: Command,
+) -> Result<Response, Response> {
+    let Some(session) = session_of(sessions, user) else {
+        return Ok(Response::error(Status::InvalidInput, "Unknown user"));
+    };
+    blocking(session, move |session| match command {
+        Command::LOCK {
+            target,
+            kind,
+            wait_ms,
+        } => lock(session, connection, target, kind, wait_ms),
+        // Reads don't hold the session while they run, so its other
+        // requests aren't held up behind a long one
+        command if reads_only(&command) => {
+            let mut reader = session.lock().recover().reader();
+            execute(&mut reader, command, connection)
+        }
+        command => execute(&mut session.lock().recover(), command, connection),
+    })
+    .await
+}
+
+// Redoes logged changes on top of the snapshot they were made after. They
+// went through once, so any that fail did the first time too
+fn replay(fs: &FileSystem, records: Vec<Record>) {
+    let count = records.len();
+    for record in records {
+        let mut session = Session::replaying(record.user, fs.clone(), record.dir);
+        execute(&mut session, record.command, 0);
+    }
+    if count > 0 {
+        println!("replayed {count} logged changes");
+    }
+}
+
+// Runs work on a blocking thread, the tree may be held by someone else and
+// some commands walk all of it. A panic is answered with an internal error
+// once the tree it may have left half changed is repaired
+async fn blocking(
+    session: Arc<Mutex<Session>>,
+    work: impl FnOnce(&Mutex<Session>) -> Response + Send + 'static,
+) -> Result<Response, Response> {
+    let worker = session.clone();
+    match tokio::task::spawn_blocking(move || work(&worker)).await {
+        Ok(response) => Ok(response),
+        Err(err) => {
+            println!("command failed: {err}");
+            let _ = tokio::task::spawn_blocking(move || repair(&session)).await;
+            Err(Response::error(
+                Status::Internal,
+                "Internal error, closing connection",
+            ))
+        }
+    }
+}
+
+// Checks the tree after a panic and lets everyone carry on
+fn repair(session: &Mutex<Session>) {
+    let file_system = session.lock().recover().file_system.clone();
+    session.clear_poison();
+    for problem in file_system.write().recover().repair() {
+        println!("repaired {problem}");
+    }
+    file_system.clear_poison();
+}
+
+// Waiting for a lock can take a while, so it happens without holding the
+// session. The session's own connections may need it to let the lock go
+fn lock(
+    session: &Mutex<Session>,
+    connection: ConnectionId,
+    target: LockTarget,
+    kind: LockKind,
+    wait_ms: Option<u32>,
+) -> Response {
+    let request = {
+        let session = session.lock().recover();
+        session
+            .lockable(&target, connection)
+            .map(|(inode, path)| (session.locks.clone(), inode, path))
+    };
+    let (locks, inode, path) = match request {
+        Ok(request) => request,
+        Err(err) => return err.into(),
+    };
+    let wait = wait_ms
+        .map_or(MAX_LOCK_WAIT, |ms| Duration::from_millis(ms.into()))
+        .min(MAX_LOCK_WAIT);
+    unit(locks.lock(inode, kind, connection, wait, &path))
+}
+
+// Reads the hello that opens every connection and binds it to a session.
+// Peers speaking anything else are answered in a form they can display
+async fn handshake(socket: &mut TcpStream, session_ref: &Sessions) -> Option<u8> {
+    let rejection = match read_frame(socket).await {
+        Ok(frame) if frame.opcode == OP_HELLO => match frame.payload.first() {
+            Some(user) if session_ref.contains_key(user) => {
+                let accepted = Frame::response(OP_HELLO, Vec::new());
+                return write_frame(socket, &accepted).await.ok().map(|_| *user);
             }
-            Command::CP(target) => {
-                let parts = target.split(WRITE_DELIM).collect::<Vec<&str>>();
-                if parts.len() == 2 {
-                    match session.copy(parts[0].to_string(), parts[1].to_string()) {
-                        Err(message) => message.to_string(),
-                        Ok(()) => "".to_string(),
-                    }
-                } else {
-                    "Mismatched input".to_string()
-                }
+            _ => Frame::reject("Unknown user"),
+        },
+        Ok(..) => Frame::reject("Expected hello"),
+        Err(ProtocolError::BadMagic) => {
+            let reason = "Client predates the framed protocol, please upgrade";
+            let _ = socket.write_all(&legacy_rejection(reason)).await;
+            return None;
+        }
+        // Answer in the client's version so it can still read why
+        Err(ProtocolError::UnsupportedVersion(version)) => Frame {
+            version,
+            ..Frame::reject(&format!(
+                "Protocol version {version} unsupported, server speaks {PROTOCOL_VERSION}"
+            ))
+        },
+        Err(err) => {
+            println!("handshake failed: {err}");
+            return None;
+        }
+    };
+    let _ = write_frame(socket, &rejection).await;
+    None
+}
+
+// Commands that change neither the tree nor the session, they can run
+// beside each other
+fn reads_only(command: &Command) -> bool {
+    match command {
+        Command::PWD
+        | Command::WHO
+        | Command::LS { .. }
+        | Command::STAT(..)
+        | Command::READ(..)
+        | Command::READLINK(..)
+        | Command::DOWNLOAD { .. }
+        | Command::GREP { .. } => true,
+        Command::FIND { query, .. } => !query.delete,
+        Command::SNAPSHOT { action, .. } => *action == SnapshotAction::List,
+        _ => false,
+    }
+}
+
+fn execute(session: &mut Session, command: Command, connection: ConnectionId) -> Response {
+    match command {
+        Command::CD(target) => unit(session.change_dir(target)),
+        Command::PWD => Response::with_body(Body::Path(
+            session.current_dir().to_string_lossy().into_owned(),
+        )),
+        Command::MKDIR(target) => unit(session.make_dir(target)),
+        Command::WHO => Response::with_body(Body::User(session.current_user().to_string())),
+        // Short listings are names marked with their kind, `-l` sends
+        // everything
+        Command::LS { path, options } => match session.list(&path, &options) {
+            Err(err) => err.into(),
+            Ok(stats) if options.long => Response::with_body(Body::Stats(stats)),
+            Ok(stats) => {
+                Response::with_body(Body::Listing(stats.iter().map(Stat::marked_name).collect()))
             }
-            Command::MV(target) => {
-                let parts = target.split(WRITE_DELIM).collect::<Vec<&str>>();
-                if parts.len() == 2 {
-                    match session.mv(parts[0].to_string(), parts[1].to_string()) {
-                        Err(message) => message.to_string(),
-                        Ok(()) => "".to_string(),
-                    }
+        },
+        Command::STAT(target) => match session.stat(target) {
+            Err(err) => err.into(),
+            Ok(stat) => Response::with_body(Body::Stats(vec![stat])),
+        },
+        Command::RM(target) => unit(session.remove(target)),
+        Command::TOUCH(target) => unit(session.touch(target)),
+        Command::READ(target) => match session.read_file(target) {
+            Err(err) => err.into(),
+            Ok(data) => Response::with_body(Body::Bytes(data)),
+        },
+        Command::WRITE { path, data } => unit(session.write_file(path, data)),
+        Command::APPEND { path, data } => unit(session.append_file(path, &data)),
+        Command::TRUNCATE { path, size } => unit(session.truncate(path, size)),
+        Command::PWRITE { path, offset, data } => unit(session.write_at(path, offset, &data)),
+        Command::CP {
+            src,
+            dst,
+            recursive,
+        } => unit(session.copy(src, dst, recursive)),
+        Command::MV { src, dst } => unit(session.mv(src, dst)),
+        Command::FIND { root, query } => match session.find(&root, &query) {
+            Err(err) => err.into(),
+            Ok(list) => {
+                let message = if list.is_empty() {
+                    "pattern not found"
                 } else {
-                    "Mismatched input".to_string()
+                    ""
+                };
+                Response {
+                    message: message.to_string(),
+                    ..Response::with_body(Body::Listing(list))
                 }
             }
-            Command::FIND(target) => match session.find_local(target) {
-                Err(mess) => mess.to_string(),
-                Ok(list) => {
-                    if list.is_empty() {
-                        "pattern not found".to_string()
-                    } else {
-                        list.join(" | ")
-                    }
-                }
-            },
-            Command::UNKNOWN | Command::SU(..) => "Unknown Command".to_string(),
-        };
-        let mut payload = Vec::new();
-        payload.push(message.len() as u8);
-        payload.extend(message.as_bytes());
-        socket.write_all(&payload).await.unwrap()
-    } else {
-        println!("no data")
+        },
+        Command::GREP {
+            pattern,
+            path,
+            options,
+        } => match session.grep(&pattern, &path, &options) {
+            Err(err) => err.into(),
+            Ok(lines) => Response::with_body(Body::Listing(lines)),
+        },
+        Command::OPEN { path, options } => match session.open(path, options, connection) {
+            Err(err) => err.into(),
+            Ok(handle) => Response::with_body(Body::Handle(handle)),
+        },
+        Command::FDREAD { handle, len } => match session.read_handle(handle, len, connection) {
+            Err(err) => err.into(),
+            Ok(data) => Response::with_body(Body::Bytes(data)),
+        },
+        Command::FDWRITE { handle, data } => unit(session.write_handle(handle, &data, connection)),
+        Command::SEEK {
+            handle,
+            offset,
+            whence,
+        } => match session.seek(handle, offset, whence, connection) {
+            Err(err) => err.into(),
+            Ok(offset) => Response::with_body(Body::Position(offset)),
+        },
+        Command::CLOSE(handle) => unit(session.close(handle, connection)),
+        Command::UNLOCK(target) => unit(session.unlock(&target, connection)),
+        Command::UPLOAD(target) => match session.begin_upload(target) {
+            Err(err) => err.into(),
+            Ok(transfer) => Response::with_body(Body::Handle(transfer)),
+        },
+        Command::CHUNK {
+            transfer,
+            offset,
+            data,
+        } => unit(session.write_chunk(transfer, offset, &data)),
+        Command::COMMIT(transfer) => unit(session.commit_upload(transfer)),
+        Command::DOWNLOAD { path, offset, len } => match session.read_range(path, offset, len) {
+            Err(err) => err.into(),
+            Ok(data) => Response::with_body(Body::Bytes(data)),
+        },
+        Command::SYMLINK { target, link } => unit(session.symlink(target, link)),
+        Command::LINK { target, link } => unit(session.link(target, link)),
+        Command::READLINK(target) => match session.read_link(target) {
+            Err(err) => err.into(),
+            Ok(target) => Response::with_body(Body::Path(target)),
+        },
+        Command::SNAPSHOT { action, name } => match action {
+            SnapshotAction::List => Response::with_body(Body::Listing(session.list_snapshots())),
+            SnapshotAction::Create => unit(session.create_snapshot(&name)),
+            SnapshotAction::Delete => unit(session.delete_snapshot(&name)),
+            SnapshotAction::Restore => unit(session.restore_snapshot(&name)),
+        },
+        // Opcodes this server doesn't know decode to UNKNOWN
+        Command::UNKNOWN => Response::error(Status::Unsupported, "Unknown Command"),
+        // Locks wait, respond takes them before they get here
+        Command::LOCK { .. } | Command::SU(..) => {
+            Response::error(Status::InvalidInput, "Unknown Command")
+        }
+    }
+}
+
+fn unit(result: Result<(), FsError>) -> Response {
+    match result {
+        Ok(()) => Response::ok(),
+        Err(err) => err.into(),
     }
 }
@@ -0,0 +1,26 @@
+/*
+Lexical path resolution, no lookups in the trie are made.
+ */
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+
+// Resolves target against working_dir into a normalized absolute path.
+// Absolute targets ignore working_dir, `.` is dropped and `..` steps up one
+// level, clamping at `/` the way `cd /..` does in a shell
+pub fn resolve(working_dir: &Path, target: &str) -> PathBuf {
+    let mut parts: Vec<&OsStr> = Vec::new();
+    let joined = working_dir.join(target);
+    for component in joined.components() {
+        match component {
+            Component::RootDir | Component::Prefix(..) => parts.clear(),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                parts.pop();
+            }
+            Component::Normal(name) => parts.push(name),
+        }
+    }
+    let mut out = PathBuf::from("/");
+    out.extend(parts);
+    out
+}
@@ -1,206 +1,1255 @@
-use std::collections::HashSet;
-use std::ffi::{OsStr, OsString};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::system::FileSystem;
-use crate::trie::FsLike::{self, DirectoryLike, FileLike};
-// Creates new path from target and ref
-fn append_to_path(path: impl Into<OsString>, s: impl AsRef<OsStr>) -> PathBuf {
-    let mut p = path.into();
-    p.push(s);
-    p.into()
+use regex::RegexBuilder;
+use transport_layer::command::{
+    Command, FindQuery, GrepOptions, ListOptions, LockTarget, OpenOptions, SnapshotAction, Whence,
+    MAX_READ_LEN,
+};
+use transport_layer::response::{NodeKind, Stat};
+
+use crate::error::FsError;
+use crate::glob;
+use crate::index;
+use crate::locks::Locks;
+use crate::path;
+use crate::system::{FileSystem, Recover};
+use crate::trie::FsLike::{self, DirectoryLike, FileLike, SymlinkLike};
+use crate::trie::{self, InodeId, Trie};
+use crate::wal::{Journal, Record};
+// Identifies one client connection, handles are only usable from their own
+pub type ConnectionId = u64;
+
+// An open handle, bound to the inode so the file can be renamed under it
+#[derive(Debug)]
+struct OpenFile {
+    inode: InodeId,
+    // Name it was opened by, only used in errors
+    path: PathBuf,
+    offset: u64,
+    options: OpenOptions,
+    connection: ConnectionId,
+    // The named snapshot it was opened in, None for the live tree
+    tree: Option<Arc<Trie>>,
 }
+
+// What a change to the shape of the tree holds to make it sharing the tree,
+// see Session::reshape
+#[derive(Debug, PartialEq)]
+struct Hold {
+    // Directories whose entries it checks or changes
+    dirs: Vec<InodeId>,
+    // Files it names or changes, by their turns
+    files: Vec<InodeId>,
+}
+impl Hold {
+    fn new(dirs: Vec<InodeId>, files: Vec<InodeId>) -> Self {
+        Self { dirs, files }
+    }
+    // Also the file at path that a change would replace, None when there's
+    // something other than a file there
+    fn replacing(mut self, fs: &Trie, path: &Path) -> Option<Self> {
+        if let Some(id) = fs.resolve_id(path, false) {
+            if !matches!(fs.node(id).as_deref()?, FileLike { .. }) {
+                return None;
+            }
+            self.files.push(id);
+        }
+        Some(self)
+    }
+}
+
 #[derive(Debug)]
 pub struct Session {
     //TODO resolve ownership to be more efficient.
     working_dir: PathBuf,
     user: String,
     pub file_system: FileSystem,
+    // Advisory locks, shared with every other session
+    pub locks: Locks,
+    // Where changes are logged before they are made, when the tree is kept
+    // on disk
+    pub journal: Option<Journal>,
+    // Open chunked uploads and the file each one writes into
+    transfers: HashMap<u32, PathBuf>,
+    next_transfer: u32,
+    // Descriptor table, open files by handle
+    handles: HashMap<u32, OpenFile>,
+    next_handle: u32,
 }
 impl Session {
-    pub fn new(user: String, fs: FileSystem) -> Self {
+    pub fn new(user: String, fs: FileSystem, locks: Locks) -> Self {
         Self {
             working_dir: PathBuf::from("/"),
             user,
             file_system: fs,
+            locks,
+            journal: None,
+            transfers: HashMap::new(),
+            next_transfer: 1,
+            handles: HashMap::new(),
+            next_handle: 1,
         }
     }
-    //TODO support ls outside of working dir
-    pub fn list(&self) -> HashSet<String> {
-        let fs = self.file_system.lock().unwrap();
-        match fs.get(PathBuf::from(&self.working_dir)) {
-            Some(node) => {
-                match node {
-                    DirectoryLike { children } => {
-                        return children
-                            .keys()
-                            .into_iter()
-                            .map(|path| path.clone().into_os_string().into_string().unwrap())
-                            .collect::<HashSet<String>>()
-                    }
-                    _ => {
-                        //Shouldn't be possible
-                        println!(
-                            "Something went wrong reading {:?}",
-                            self.working_dir.as_os_str()
-                        );
-                        return HashSet::new();
-                    }
+    // Session as it was when a logged change was made, to replay it
+    pub fn replaying(user: String, fs: FileSystem, dir: PathBuf) -> Self {
+        Self {
+            working_dir: dir,
+            ..Self::new(user, fs, Locks::default())
+        }
+    }
+    // Sees the tree from where this session is, for reads that run without
+    // holding it. Handles and transfers stay with the session
+    pub fn reader(&self) -> Self {
+        Self {
+            working_dir: self.working_dir.clone(),
+            ..Self::new(
+                self.user.clone(),
+                self.file_system.clone(),
+                self.locks.clone(),
+            )
+        }
+    }
+    // Entries under target, or target itself when it isn't a directory.
+    // With recursive, entries below are named relative to target. A pattern
+    // lists each match, directories by their entries prefixed with the match
+    pub fn list(&self, target: &str, options: &ListOptions) -> Result<Vec<Stat>, FsError> {
+        let live = self.file_system.read().recover();
+        let fs = live.view(&self.resolve(target));
+        if glob::is_pattern(target) {
+            let mut out = Vec::new();
+            for path in self.expand(fs, target)? {
+                let name = self.display_name(&path, target);
+                let node = fs
+                    .get(&path)
+                    .ok_or_else(|| FsError::NotFound(path.clone()))?;
+                match &*node {
+                    DirectoryLike { .. } => list_into(fs, &node, &name, options, &mut out),
+                    _ => out.push(stat(name.to_string_lossy().into_owned(), &node)),
                 }
             }
-            None => {
-                return HashSet::new();
-            }
+            return Ok(out);
+        }
+        let destination = self.locate(fs, target)?;
+        let node = fs
+            .get(&destination)
+            .ok_or_else(|| FsError::NotFound(destination.clone()))?;
+        let DirectoryLike { .. } = &*node else {
+            return Ok(vec![stat(target.to_string(), &node)]);
+        };
+        let mut out = Vec::new();
+        if options.all {
+            out.push(stat(".".to_string(), &node));
+            let parent = destination.parent().and_then(|parent| fs.get(parent));
+            out.push(stat("..".to_string(), parent.as_deref().unwrap_or(&node)));
+        }
+        list_into(fs, &node, Path::new(""), options, &mut out);
+        Ok(out)
+    }
+    // Like gnu stat, a link is described rather than followed
+    pub fn stat(&self, target: String) -> Result<Stat, FsError> {
+        let live = self.file_system.read().recover();
+        let destination = self.resolve(&target);
+        let node = live.view(&destination).get_link(&destination);
+        match node {
+            Some(node) => Ok(stat(target, &node)),
+            None => Err(FsError::NotFound(destination)),
         }
     }
     pub fn current_dir(&self) -> &Path {
-        return &self.working_dir;
+        &self.working_dir
     }
     pub fn current_user(&self) -> &str {
-        return &self.user;
-    }
-    // Replaces .. with the parent of the current working directory for path navigation
-    // TODO support nested relative .. in a path
-    // probably would require stepping the working directory
-    // TODO make more generic
-    //TODO support `.` sugar
-    fn adjust_target(&self, target: &str) -> Result<String, &'static str> {
-        // if target == "." {
-        //     return Ok(self.working_dir.to_str().unwrap().to_string())
-        // }
-        let parent = self.working_dir.parent();
-        if target.contains("..") {
-            if target.starts_with("/") {
-                return Err("Parent dir isn't supported in absolute paths");
-            }
-            match parent {
-                Some(path) => Ok(target.replace("..", path.as_os_str().to_str().unwrap())),
-                None => return Err("Cannot adjust for .. with no parent"),
+        &self.user
+    }
+    // Absolute, normalized path target refers to from the working directory
+    fn resolve(&self, target: &str) -> PathBuf {
+        path::resolve(&self.working_dir, target)
+    }
+    // Resolved path with every link along it followed, so a missing result
+    // names where a new node should go
+    fn locate(&self, fs: &Trie, target: &str) -> Result<PathBuf, FsError> {
+        fs.canonicalize(&self.resolve(target), true)
+    }
+    // Resolved paths target names, every match when it is a glob pattern.
+    // A pattern matching nothing is an error rather than taken literally
+    fn expand(&self, fs: &Trie, target: &str) -> Result<Vec<PathBuf>, FsError> {
+        let pattern = self.resolve(target);
+        if !glob::is_pattern(target) {
+            return Ok(vec![pattern]);
+        }
+        let found = glob::expand(fs, &pattern);
+        if found.is_empty() {
+            return Err(FsError::NoMatch(pattern));
+        }
+        Ok(found)
+    }
+    // How a match is shown, relative to the working directory when the
+    // pattern was
+    fn display_name(&self, path: &Path, target: &str) -> PathBuf {
+        match path.strip_prefix(&self.working_dir) {
+            Ok(relative) if !target.starts_with('/') => relative.to_path_buf(),
+            _ => path.to_path_buf(),
+        }
+    }
+    // What to log for command as this session runs it, None when the tree
+    // isn't kept on disk
+    fn record(&self, command: impl FnOnce() -> Command) -> Option<Record> {
+        self.journal.as_ref().map(|_| Record {
+            user: self.user.clone(),
+            dir: self.working_dir.clone(),
+            command: command(),
+        })
+    }
+    // Logs records ahead of the changes they describe, which mustn't be made
+    // when this fails
+    fn log(&self, records: impl IntoIterator<Item = Record>) -> Result<(), FsError> {
+        let records = records.into_iter().collect::<Vec<_>>();
+        match &self.journal {
+            Some(journal) if !records.is_empty() => journal
+                .append(&records)
+                .map_err(|err| FsError::NotLogged(err.to_string())),
+            _ => Ok(()),
+        }
+    }
+    // Held while the contents of file id are logged and changed, see Log
+    fn turn(&self, id: InodeId) -> Option<MutexGuard<'_, ()>> {
+        self.journal.as_ref().map(|journal| journal.turn(id))
+    }
+    // Runs change while sharing the tree, holding the turn of the file find
+    // picks out. A file that may be a snapshot's too is copied out of it as
+    // it changes
+    fn change_file<T>(
+        &self,
+        find: impl Fn(&Trie) -> Result<InodeId, FsError>,
+        change: impl FnOnce(&Trie) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        let fs = self.file_system.read().recover();
+        loop {
+            let id = find(&fs)?;
+            let _turn = self.turn(id);
+            // Its name may have gone to another file before the turn came
+            if find(&fs)? == id {
+                return change(&fs);
             }
-        } else {
-            Ok(target.to_string())
         }
     }
-    pub fn change_dir(&mut self, target: String) -> Result<(), &'static str> {
-        let fs = self.file_system.lock().unwrap();
-        let mut destination_dir = self.working_dir.clone();
-        let adjusted_target = self.adjust_target(&target)?;
-
-        // Pushing a relative path extends it, pushing an absolute path replaces
-        destination_dir.push(PathBuf::from(adjusted_target));
-        let maybe_new_dir = fs.get(PathBuf::from(&destination_dir));
-        match maybe_new_dir {
+    // Runs change while sharing the tree, holding the entries of the
+    // directories and the turns of the files plan picks out, so changes in
+    // different directories don't wait on each other. When plan finds the
+    // change needs more than that, or what it picked out moved before it
+    // was held, change runs holding the whole tree instead
+    fn reshape<T>(
+        &self,
+        plan: impl Fn(&Trie) -> Option<Hold>,
+        change: impl FnOnce(&Trie) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        {
+            let fs = self.file_system.read().recover();
+            if let Some(hold) = plan(&fs) {
+                let _turns = self
+                    .journal
+                    .as_ref()
+                    .map(|journal| journal.turns(&hold.files));
+                let _entries = fs.entries(&hold.dirs);
+                if plan(&fs).as_ref() == Some(&hold) {
+                    return change(&fs);
+                }
+            }
+        }
+        change(&self.file_system.write().recover())
+    }
+    pub fn change_dir(&mut self, target: String) -> Result<(), FsError> {
+        let live = self.file_system.read().recover();
+        let fs = live.view(&self.resolve(&target));
+        let destination_dir = self.locate(fs, &target)?;
+        let maybe_new_dir = fs.get(&destination_dir);
+        match maybe_new_dir.as_deref() {
             Some(node) => match node {
                 DirectoryLike { .. } => self.working_dir = destination_dir,
-                FileLike { .. } => return Err("Can't change working directory to a file"),
+                _ => return Err(FsError::NotADirectory(destination_dir)),
             },
-            None => return Err("Directory not found"),
+            None => return Err(FsError::NotFound(destination_dir)),
         };
 
         Ok(())
     }
-    pub fn make_dir(&mut self, target: String) -> Result<(), &'static str> {
-        let mut fs = self.file_system.lock().unwrap();
-        let mut destination_dir = self.working_dir.clone();
-        let adjusted_target = self.adjust_target(&target)?;
-
-        // Pushing a relative path extends it, pushing an absolute path replaces
-        destination_dir.push(PathBuf::from(adjusted_target));
-        fs.insert(PathBuf::from(&destination_dir), FsLike::new())?;
+    // Missing parents are made too, which takes the whole tree
+    pub fn make_dir(&mut self, target: String) -> Result<(), FsError> {
+        let destination_dir = self.resolve(&target);
+        let plan = |fs: &Trie| {
+            let (parent, _) = place(fs, &destination_dir)?;
+            Some(Hold::new(vec![parent], Vec::new()))
+        };
+        self.reshape(plan, |fs| {
+            self.log(self.record(|| Command::MKDIR(target.clone())))?;
+            fs.insert(&destination_dir, FsLike::directory(&self.user))?;
 
-        Ok(())
+            Ok(())
+        })
     }
-    pub fn remove(&mut self, target: String) -> Result<(), &'static str> {
-        let mut fs = self.file_system.lock().unwrap();
-        let mut destination_dir = self.working_dir.clone();
-        let adjusted_target = self.adjust_target(&target)?;
-        destination_dir.push(PathBuf::from(adjusted_target));
-        fs.remove(destination_dir)
-    }
-    pub fn touch(&mut self, target: String) -> Result<(), &'static str> {
-        let mut fs = self.file_system.lock().unwrap();
-        let mut destination_dir = self.working_dir.clone();
-        let adjusted_target = self.adjust_target(&target)?;
-        destination_dir.push(PathBuf::from(adjusted_target));
-        fs.insert(destination_dir, FileLike { data: Vec::new() })
-    }
-    pub fn read_file(&self, target: String) -> Result<Vec<u8>, &'static str> {
-        let mut fs = self.file_system.lock().unwrap();
-        let mut destination_dir = self.working_dir.clone();
-        let adjusted_target = self.adjust_target(&target)?;
-        destination_dir.push(PathBuf::from(adjusted_target));
-        match fs.get(destination_dir) {
-            Some(node) => match node {
-                DirectoryLike { .. } => return Err("Can only read files"),
-                FileLike { data } => return Ok(data.clone()),
-            },
-            None => return Err("File not found"),
-        }
-    }
-    pub fn write_file(&self, target: String, content: String) -> Result<(), &'static str> {
-        let mut fs = self.file_system.lock().unwrap();
-        let mut destination_dir = self.working_dir.clone();
-        let adjusted_target = self.adjust_target(&target)?;
-        destination_dir.push(PathBuf::from(adjusted_target));
-        fs.insert(
-            destination_dir,
-            FileLike {
-                data: content.into_bytes(),
-            },
-        )
-    }
-    // Searches for all files or directories in current work
-    pub fn find_local(&self, target: String) -> Result<Vec<String>, &'static str> {
-        let mut fs = self.file_system.lock().unwrap();
-        // let parent_dir = match self.adjust_target("..") {
-        //     Err(..) => "/".to_string(),
-        //     Ok(val) => val,
-        // };
-        let dir = self.adjust_target(self.current_dir().to_str().unwrap())?;
-        println!("{:?}", dir);
-        match fs.get(PathBuf::from(dir)) {
-            Some(node) => match node {
-                DirectoryLike { children } => {
-                    println!("{:?}", node);
-                    let mut out = Vec::new();
-                    for key in children.keys() {
-                        let name = key
-                            .file_name()
-                            .unwrap_or(&OsString::from("Error"))
-                            .to_str()
-                            .unwrap()
-                            .to_string();
-                        if name.contains(&target) {
-                            out.push(name);
-                        }
+    // Every match is tried, the first failure is reported. A pattern or a
+    // directory with anything in it takes the whole tree
+    pub fn remove(&mut self, target: String) -> Result<(), FsError> {
+        let plan = |fs: &Trie| {
+            if glob::is_pattern(&target) {
+                return None;
+            }
+            let (parent, Some(id)) = place(fs, &self.resolve(&target))? else {
+                return None;
+            };
+            match fs.node(id).as_deref()? {
+                FileLike { .. } => Some(Hold::new(vec![parent], vec![id])),
+                DirectoryLike { children, .. } if children.is_empty() => {
+                    Some(Hold::new(vec![parent, id], Vec::new()))
+                }
+                _ => None,
+            }
+        };
+        self.reshape(plan, |fs| {
+            let mut failed = None;
+            let found = self.expand(fs, &target)?;
+            self.log(self.record(|| Command::RM(target.clone())))?;
+            for destination_dir in found {
+                if let Err(err) = fs.remove(destination_dir) {
+                    failed.get_or_insert(err);
+                }
+            }
+            failed.map_or(Ok(()), Err)
+        })
+    }
+    pub fn touch(&mut self, target: String) -> Result<(), FsError> {
+        let plan = |fs: &Trie| {
+            let (parent, existing) = place(fs, &self.resolve(&target))?;
+            match existing.map(|id| (id, fs.node(id))) {
+                None => Some(Hold::new(vec![parent], Vec::new())),
+                Some((_, Some(node))) if matches!(*node, SymlinkLike { .. }) => None,
+                Some((id, _)) => Some(Hold::new(vec![parent], vec![id])),
+            }
+        };
+        self.reshape(plan, |fs| {
+            let destination_dir = self.locate(fs, &target)?;
+            trie::writable(&destination_dir)?;
+            self.log(self.record(|| Command::TOUCH(target.clone())))?;
+            // Touching something that exists only bumps its times
+            if let Some(mut node) = fs.get_mut(&destination_dir) {
+                node.meta_mut().touch();
+                return Ok(());
+            }
+            fs.insert(destination_dir, FsLike::file(&self.user, Vec::new()))
+        })
+    }
+    // Matches of a pattern are read one after another, like cat
+    pub fn read_file(&self, target: String) -> Result<Vec<u8>, FsError> {
+        let live = self.file_system.read().recover();
+        let fs = live.view(&self.resolve(&target));
+        let mut out = Vec::new();
+        for path in self.expand(fs, &target)? {
+            let destination_dir = fs.canonicalize(&path, true)?;
+            let id = fs
+                .resolve_id(&destination_dir, true)
+                .ok_or_else(|| FsError::NotFound(destination_dir.clone()))?;
+            match fs.node(id).as_deref() {
+                Some(FileLike { data, .. }) => out.extend_from_slice(data),
+                Some(..) => return Err(FsError::IsADirectory(destination_dir)),
+                None => return Err(FsError::NotFound(destination_dir)),
+            }
+            fs.accessed(id);
+        }
+        Ok(out)
+    }
+    pub fn write_file(&self, target: String, content: impl Into<Vec<u8>>) -> Result<(), FsError> {
+        let content = content.into();
+        let record = self.record(|| Command::WRITE {
+            path: target.clone(),
+            data: content.clone(),
+        });
+        // Overwriting keeps the file's identity, only its contents change
+        self.write_to(&target, record, |fs, destination| {
+            fs.set_data(&destination, content)
+        })
+    }
+    // Adds content to the end of the file, creating it if it's missing
+    pub fn append_file(&self, target: String, content: &[u8]) -> Result<(), FsError> {
+        let record = self.record(|| Command::APPEND {
+            path: target.clone(),
+            data: content.to_vec(),
+        });
+        self.write_to(&target, record, |fs, destination| {
+            fs.append(&destination, content)
+        })
+    }
+    // Writes content at offset, what is around it stays as it was
+    pub fn write_at(&self, target: String, offset: u64, content: &[u8]) -> Result<(), FsError> {
+        let record = self.record(|| Command::PWRITE {
+            path: target.clone(),
+            offset,
+            data: content.to_vec(),
+        });
+        self.write_to(&target, record, |fs, destination| {
+            fs.write_at(&destination, offset, content)
+        })
+    }
+    pub fn truncate(&self, target: String, size: u64) -> Result<(), FsError> {
+        let record = self.record(|| Command::TRUNCATE {
+            path: target.clone(),
+            size,
+        });
+        self.write_to(&target, record, |fs, destination| {
+            fs.truncate(&destination, size)
+        })
+    }
+    // Logs record then runs write on the file target resolves to while
+    // sharing the tree, so writes to different files don't wait on each
+    // other. A missing file is made empty first, see reshape
+    fn write_to<T>(
+        &self,
+        target: &str,
+        record: Option<Record>,
+        write: impl FnOnce(&Trie, PathBuf) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        {
+            let fs = self.file_system.read().recover();
+            let destination = self.locate(&fs, target)?;
+            if let Some(id) = fs.resolve_id(&destination, true) {
+                let _turn = self.turn(id);
+                // The name may have gone to another file before the turn came
+                if fs.resolve_id(&destination, true) == Some(id) {
+                    self.log(record)?;
+                    return write(&fs, destination);
+                }
+            }
+        }
+        // Its turn is taken before anyone can find it, so no one writes to
+        // it before this does
+        let file = FsLike::file(&self.user, Vec::new());
+        let id = file.meta().inode;
+        let plan = |fs: &Trie| {
+            let (parent, None) = place(fs, &self.locate(fs, target).ok()?)? else {
+                return None;
+            };
+            Some(Hold::new(vec![parent], vec![id]))
+        };
+        self.reshape(plan, |fs| {
+            // Someone else may have made it in between
+            let destination = self.locate(fs, target)?;
+            trie::writable(&destination)?;
+            self.log(record)?;
+            if fs.resolve_id(&destination, true).is_none() {
+                fs.insert(&destination, file)?;
+            }
+            write(fs, destination)
+        })
+    }
+    pub fn symlink(&mut self, target: String, link: String) -> Result<(), FsError> {
+        let destination = self.resolve(&link);
+        let plan = |fs: &Trie| {
+            let (parent, _) = place(fs, &destination)?;
+            Some(Hold::new(vec![parent], Vec::new()))
+        };
+        self.reshape(plan, |fs| {
+            if fs.get_link(&destination).is_some() {
+                return Err(FsError::AlreadyExists(destination.clone()));
+            }
+            self.log(self.record(|| Command::SYMLINK {
+                target: target.clone(),
+                link: link.clone(),
+            }))?;
+            fs.insert(&destination, FsLike::symlink(&self.user, &target))
+        })
+    }
+    // Hard link, both names share one inode until either is removed
+    pub fn link(&mut self, target: String, link: String) -> Result<(), FsError> {
+        let (source, destination) = (self.resolve(&target), self.resolve(&link));
+        let plan = |fs: &Trie| {
+            if fs.canonicalize(&source, false).ok()? != source {
+                return None;
+            }
+            let id = fs.resolve_id(&source, false)?;
+            let (parent, _) = place(fs, &destination)?;
+            Some(Hold::new(vec![parent], vec![id]))
+        };
+        self.reshape(plan, |fs| {
+            self.log(self.record(|| Command::LINK {
+                target: target.clone(),
+                link: link.clone(),
+            }))?;
+            fs.link(&source, &destination)
+        })
+    }
+    pub fn read_link(&self, target: String) -> Result<String, FsError> {
+        let live = self.file_system.read().recover();
+        let destination = self.resolve(&target);
+        let node = live.view(&destination).get_link(&destination);
+        match node.as_deref() {
+            Some(SymlinkLike { target, .. }) => Ok(target.to_string_lossy().into_owned()),
+            Some(..) => Err(FsError::NotALink(destination)),
+            None => Err(FsError::NotFound(destination)),
+        }
+    }
+    // Paths under root matching every predicate of query, root included,
+    // the way find walks. With delete the matches are removed deepest first,
+    // directories only once they are empty
+    pub fn find(&self, root: &str, query: &FindQuery) -> Result<Vec<String>, FsError> {
+        let found = if query.delete {
+            let fs = self.file_system.write().recover();
+            let found = self.walk(&fs, root, query)?;
+            self.log(self.deleted(&fs, &found))?;
+            let mut failed = None;
+            for path in found.iter().rev() {
+                let not_empty = matches!(
+                    fs.get_link(path).as_deref(),
+                    Some(DirectoryLike { children, .. }) if !children.is_empty()
+                );
+                let result = match not_empty {
+                    true => Err(FsError::NotEmpty(path.clone())),
+                    false => fs.remove(path.clone()),
+                };
+                if let Err(err) = result {
+                    failed.get_or_insert(err);
+                }
+            }
+            if let Some(err) = failed {
+                return Err(err);
+            }
+            found
+        } else {
+            let live = self.file_system.read().recover();
+            self.walk(live.view(&self.resolve(root)), root, query)?
+        };
+        Ok(found
+            .into_iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect())
+    }
+    // What removing found deepest first comes to, logged as a removal of
+    // each path that goes. The query itself could match other paths by the
+    // time it's replayed
+    fn deleted(&self, fs: &Trie, found: &[PathBuf]) -> Vec<Record> {
+        if self.journal.is_none() {
+            return Vec::new();
+        }
+        let mut gone = HashSet::new();
+        let mut records = Vec::new();
+        for path in found.iter().rev() {
+            let emptied = match fs.get_link(path).as_deref() {
+                Some(DirectoryLike { children, .. }) => {
+                    children.keys().all(|name| gone.contains(&path.join(name)))
+                }
+                _ => true,
+            };
+            if emptied {
+                records.extend(self.record(|| Command::RM(glob::escape(path))));
+                gone.insert(path);
+            }
+        }
+        records
+    }
+    // Paths find reports for query, without acting on them
+    fn walk(&self, fs: &Trie, root: &str, query: &FindQuery) -> Result<Vec<PathBuf>, FsError> {
+        let start = self.locate(fs, root)?;
+        let node = fs
+            .get(&start)
+            .ok_or_else(|| FsError::NotFound(start.clone()))?;
+        let mut found = Vec::new();
+        find_into(fs, start, &node, 0, query, SystemTime::now(), &mut found);
+        Ok(found)
+    }
+    // Lines of the files at target matching the regex pattern. Each is
+    // prefixed with its file when more than one could match, and with its
+    // line number on request. With the index on, only likely files are read
+    pub fn grep(
+        &self,
+        pattern: &str,
+        target: &str,
+        options: &GrepOptions,
+    ) -> Result<Vec<String>, FsError> {
+        let regex = RegexBuilder::new(pattern)
+            .case_insensitive(options.ignore_case)
+            .build()
+            .map_err(|err| FsError::InvalidPattern(err.to_string()))?;
+        let live = self.file_system.read().recover();
+        let fs = live.view(&self.resolve(target));
+        let targets = self.expand(fs, target)?;
+        let several = options.recursive || target.is_empty() || targets.len() > 1;
+        let mut files = Vec::new();
+        for path in targets {
+            let name = self.display_name(&path, target);
+            let id = fs
+                .resolve_id(&path, true)
+                .ok_or_else(|| FsError::NotFound(path.clone()))?;
+            // Let go of it before walking into it
+            let kind = fs.node(id).map(|node| kind_of(&node));
+            match kind {
+                // Without a path the files in the working directory are
+                // searched, and those below it too with -r
+                Some(NodeKind::Directory) if options.recursive || target.is_empty() => {
+                    files_into(fs, id, &name, options.recursive, &mut files)
+                }
+                Some(NodeKind::Directory) => return Err(FsError::IsADirectory(path)),
+                _ => files.push((name, id)),
+            }
+        }
+        let candidates = fs
+            .index()
+            .zip(index::required_literals(pattern, options.ignore_case))
+            .and_then(|(index, literals)| index.candidates(&literals));
+        let mut out = Vec::new();
+        for (name, id) in files {
+            if candidates
+                .as_ref()
+                .is_some_and(|found| !found.contains(&id))
+            {
+                continue;
+            }
+            let Some(node) = fs.node(id) else {
+                continue;
+            };
+            let FileLike { data, .. } = &*node else {
+                continue;
+            };
+            if data.is_empty() {
+                continue;
+            }
+            let data = data.strip_suffix(b"\n").unwrap_or(data);
+            for (number, line) in data.split(|byte| *byte == b'\n').enumerate() {
+                let line = String::from_utf8_lossy(line);
+                if !regex.is_match(&line) {
+                    continue;
+                }
+                let mut hit = String::new();
+                if several {
+                    hit.push_str(&format!("{}:", name.display()));
+                }
+                if options.line_numbers {
+                    hit.push_str(&format!("{}:", number + 1));
+                }
+                hit.push_str(&line);
+                out.push(hit);
+            }
+        }
+        Ok(out)
+    }
+    // A copy is new and belongs to whoever made it, directories need recursive
+    pub fn copy(
+        &mut self,
+        target: String,
+        destination: String,
+        recursive: bool,
+    ) -> Result<(), FsError> {
+        // One file out of the tree, anything more takes the whole tree
+        let plan = |fs: &Trie| {
+            let source = self.resolve(&target);
+            if glob::is_pattern(&target) || fs.snapshot_at(&source).is_some() {
+                return None;
+            }
+            if fs.canonicalize(&source, true).ok()? != source {
+                return None;
+            }
+            let id = fs.resolve_id(&source, true)?;
+            if !matches!(fs.node(id).as_deref()?, FileLike { .. }) {
+                return None;
+            }
+            let destination_dir = self.locate(fs, &destination).ok()?;
+            let destination_dir = into_directory(fs, destination_dir, &source).ok()?;
+            let (parent, _) = place(fs, &destination_dir)?;
+            Hold::new(vec![parent], vec![id]).replacing(fs, &destination_dir)
+        };
+        self.reshape(plan, |fs| {
+            // Copying out of a snapshot is how single files are brought back
+            let snapshot = fs.snapshot_at(&self.resolve(&target)).cloned();
+            let from = snapshot.as_deref();
+            let source_fs = from.unwrap_or(fs);
+            let sources = self.expand(source_fs, &target)?;
+            let destination_dir = self.locate(fs, &destination)?;
+            several_into_directory(fs, &sources, &destination_dir)?;
+            self.log(self.record(|| Command::CP {
+                src: target.clone(),
+                dst: destination.clone(),
+                recursive,
+            }))?;
+            for source in sources {
+                let source_fs = from.unwrap_or(fs);
+                let target_dir = source_fs.canonicalize(&source, true)?;
+                match source_fs.get(&target_dir).as_deref() {
+                    None => return Err(FsError::NotFound(target_dir)),
+                    Some(DirectoryLike { .. }) if !recursive => {
+                        return Err(FsError::IsADirectory(target_dir))
                     }
-                    Ok(out)
+                    Some(..) => {}
                 }
-                _ => return Err("Cannot be in a file"),
+                let destination_dir = into_directory(fs, destination_dir.clone(), &target_dir)?;
+                fs.copy(from, &target_dir, &destination_dir, &self.user)?;
+            }
+            Ok(())
+        })
+    }
+    // Moving keeps the node's metadata, it is the same file under a new name.
+    // A link is moved itself, not what it points at. It takes the whole tree,
+    // so anyone holding it sees the file under exactly one of its names
+    pub fn mv(&mut self, target: String, destination: String) -> Result<(), FsError> {
+        let fs = self.file_system.write().recover();
+        let sources = self.expand(&fs, &target)?;
+        self.log(self.record(|| Command::MV {
+            src: target.clone(),
+            dst: destination.clone(),
+        }))?;
+        let destination = self.resolve(&destination);
+        several_into_directory(&fs, &sources, &destination)?;
+        for target_dir in sources {
+            trie::writable(&target_dir)?;
+            if fs.get_link(&target_dir).is_none() {
+                return Err(FsError::NotFound(target_dir));
+            }
+            let destination_dir = into_directory(&fs, destination.clone(), &target_dir)?;
+            fs.rename(&target_dir, &destination_dir)?;
+        }
+        Ok(())
+    }
+
+    // Opens a chunked upload into target, emptying any file already there
+    pub fn begin_upload(&mut self, target: String) -> Result<u32, FsError> {
+        let record = self.record(|| Command::WRITE {
+            path: target.clone(),
+            data: Vec::new(),
+        });
+        let destination = self.write_to(&target, record, |fs, destination| {
+            fs.set_data(&destination, Vec::new())?;
+            Ok(destination)
+        })?;
+        let transfer = self.next_transfer;
+        self.next_transfer = self.next_transfer.wrapping_add(1);
+        self.transfers.insert(transfer, destination);
+        Ok(transfer)
+    }
+    // Chunks go straight into the file, so they may arrive in any order
+    pub fn write_chunk(&mut self, transfer: u32, offset: u64, chunk: &[u8]) -> Result<(), FsError> {
+        let destination = self
+            .transfers
+            .get(&transfer)
+            .ok_or(FsError::InvalidHandle(transfer))?;
+        let find = |fs: &Trie| {
+            fs.resolve_id(destination, true)
+                .ok_or_else(|| FsError::NotFound(destination.clone()))
+        };
+        self.change_file(find, |fs| {
+            // The upload is gone on replay, the file it writes into isn't
+            self.log(self.record(|| Command::PWRITE {
+                path: destination.to_string_lossy().into_owned(),
+                offset,
+                data: chunk.to_vec(),
+            }))?;
+            fs.write_at(destination, offset, chunk)
+        })
+    }
+    pub fn commit_upload(&mut self, transfer: u32) -> Result<(), FsError> {
+        self.transfers
+            .remove(&transfer)
+            .map(|_| ())
+            .ok_or(FsError::InvalidHandle(transfer))
+    }
+    // Opens the file at target for the connection, the handle starts at offset 0
+    pub fn open(
+        &mut self,
+        target: String,
+        options: OpenOptions,
+        connection: ConnectionId,
+    ) -> Result<u32, FsError> {
+        let snapshot = self
+            .file_system
+            .read()
+            .recover()
+            .snapshot_at(&self.resolve(&target))
+            .cloned();
+        let (inode, destination) = match &snapshot {
+            Some(..) if options.write || options.truncate || options.create => {
+                return Err(FsError::ReadOnly(self.resolve(&target)))
+            }
+            Some(fs) => {
+                let destination = self.locate(fs, &target)?;
+                open_at(fs, destination, &options)?
+            }
+            None if options.create => {
+                // Appending nothing makes the file without touching one there
+                let record = self.record(|| match options.truncate {
+                    true => Command::TRUNCATE {
+                        path: target.clone(),
+                        size: 0,
+                    },
+                    false => Command::APPEND {
+                        path: target.clone(),
+                        data: Vec::new(),
+                    },
+                });
+                self.write_to(&target, record, |fs, destination| {
+                    open_at(fs, destination, &options)
+                })?
+            }
+            None if options.truncate => {
+                let find = |fs: &Trie| {
+                    let destination = self.locate(fs, &target)?;
+                    fs.resolve_id(&destination, true)
+                        .ok_or(FsError::NotFound(destination))
+                };
+                self.change_file(find, |fs| {
+                    let destination = self.locate(fs, &target)?;
+                    self.log(self.record(|| Command::TRUNCATE {
+                        path: target.clone(),
+                        size: 0,
+                    }))?;
+                    open_at(fs, destination, &options)
+                })?
+            }
+            None => {
+                let fs = self.file_system.read().recover();
+                let destination = self.locate(&fs, &target)?;
+                open_at(&fs, destination, &options)?
+            }
+        };
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        self.handles.insert(
+            handle,
+            OpenFile {
+                inode,
+                path: destination,
+                offset: 0,
+                options,
+                connection,
+                tree: snapshot,
             },
-            None => return Err("Current Dir is invalid for some reason, resetting to root"),
-        }
-    }
-    pub fn copy(&mut self, target: String, destination: String) -> Result<(), &'static str> {
-        let mut fs = self.file_system.lock().unwrap();
-        let mut target_dir = self.working_dir.clone();
-        let adjusted_target = self.adjust_target(&target)?;
-        let adjusted_destination = self.adjust_target(&destination)?;
-        let mut destination_dir = self.working_dir.clone();
-        destination_dir.push(PathBuf::from(adjusted_destination));
-        target_dir.push(PathBuf::from(adjusted_target));
-        let source_data = match fs.get(target_dir) {
-            None => Err("not found"),
-            Some(node) => Ok(node.clone()),
-        }?;
-        //TODO support directories
-        match source_data {
-            FileLike { .. } => fs.insert(destination_dir, source_data),
-            DirectoryLike { .. } => Err("copy not supported for directories yet"),
-        }
-    }
-    pub fn mv(&mut self, target: String, destination: String) -> Result<(), &'static str> {
-        self.copy(target.clone(), destination)?;
-        self.remove(target)
+        );
+        Ok(handle)
+    }
+    // Handle opened by connection, other connections can't see it
+    fn open_file(
+        &mut self,
+        handle: u32,
+        connection: ConnectionId,
+    ) -> Result<&mut OpenFile, FsError> {
+        self.handles
+            .get_mut(&handle)
+            .filter(|file| file.connection == connection)
+            .ok_or(FsError::InvalidHandle(handle))
+    }
+    // Reads up to len bytes at the handle's offset, at most MAX_READ_LEN, and
+    // moves past them, an empty result means end of file
+    pub fn read_handle(
+        &mut self,
+        handle: u32,
+        len: u32,
+        connection: ConnectionId,
+    ) -> Result<Vec<u8>, FsError> {
+        let len = len.min(MAX_READ_LEN);
+        let file_system = self.file_system.clone();
+        let fs = file_system.read().recover();
+        let file = self.open_file(handle, connection)?;
+        if !file.options.read {
+            return Err(FsError::InvalidHandle(handle));
+        }
+        let fs = file.tree.as_deref().unwrap_or(&fs);
+        let read = match fs.node(file.inode).as_deref() {
+            Some(FileLike { data, .. }) => {
+                let start = data.len().min(file.offset.try_into().unwrap_or(usize::MAX));
+                let end = data.len().min(start.saturating_add(len as usize));
+                data[start..end].to_vec()
+            }
+            _ => return Err(FsError::StaleHandle(handle)),
+        };
+        fs.accessed(file.inode);
+        file.offset += read.len() as u64;
+        Ok(read)
+    }
+    // Writes at the handle's offset, or the end of the file when opened for
+    // append, and moves past what was written
+    pub fn write_handle(
+        &mut self,
+        handle: u32,
+        content: &[u8],
+        connection: ConnectionId,
+    ) -> Result<(), FsError> {
+        let file = self.open_file(handle, connection)?;
+        if !file.options.write {
+            return Err(FsError::InvalidHandle(handle));
+        }
+        let offset = (!file.options.append).then_some(file.offset);
+        let (inode, path) = (file.inode, file.path.clone());
+        let find = |fs: &Trie| match fs.node(inode) {
+            Some(..) => Ok(inode),
+            None => Err(FsError::StaleHandle(handle)),
+        };
+        let offset = self.change_file(find, |fs| {
+            // The handle is gone on replay, the file it writes into isn't.
+            // One no longer reachable by any name needn't be logged at all
+            if let Some(name) = fs.path_of(inode, &path) {
+                let name = name.to_string_lossy().into_owned();
+                self.log(self.record(|| match offset {
+                    Some(offset) => Command::PWRITE {
+                        path: name,
+                        offset,
+                        data: content.to_vec(),
+                    },
+                    None => Command::APPEND {
+                        path: name,
+                        data: content.to_vec(),
+                    },
+                }))?;
+            }
+            fs.write_inode(inode, offset, content, &path)
+        })?;
+        self.open_file(handle, connection)?.offset = offset + content.len() as u64;
+        Ok(())
+    }
+    // Moves the handle's offset, past the end is fine and a later write
+    // fills the gap with zeros
+    pub fn seek(
+        &mut self,
+        handle: u32,
+        offset: i64,
+        whence: Whence,
+        connection: ConnectionId,
+    ) -> Result<u64, FsError> {
+        let file_system = self.file_system.clone();
+        let fs = file_system.read().recover();
+        let file = self.open_file(handle, connection)?;
+        let size = file
+            .tree
+            .as_deref()
+            .unwrap_or(&fs)
+            .node(file.inode)
+            .ok_or(FsError::StaleHandle(handle))?
+            .size();
+        let base = match whence {
+            Whence::Start => 0,
+            Whence::Current => file.offset,
+            Whence::End => size,
+        };
+        file.offset = base
+            .checked_add_signed(offset)
+            .ok_or(FsError::InvalidSeek(handle))?;
+        Ok(file.offset)
+    }
+    pub fn close(&mut self, handle: u32, connection: ConnectionId) -> Result<(), FsError> {
+        self.open_file(handle, connection)?;
+        self.handles.remove(&handle);
+        Ok(())
+    }
+    // Everything the connection left open or locked, called once it's gone
+    pub fn close_connection(&mut self, connection: ConnectionId) {
+        self.handles.retain(|_, file| file.connection != connection);
+        self.locks.release_all(connection);
+    }
+    // File a lock on target applies to, and the path to name it by
+    pub fn lockable(
+        &self,
+        target: &LockTarget,
+        connection: ConnectionId,
+    ) -> Result<(InodeId, PathBuf), FsError> {
+        match target {
+            LockTarget::Path(target) => {
+                let fs = self.file_system.read().recover();
+                let destination = self.locate(&fs, target)?;
+                trie::writable(&destination)?;
+                let inode = fs
+                    .resolve_id(&destination, true)
+                    .ok_or_else(|| FsError::NotFound(destination.clone()))?;
+                Ok((inode, destination))
+            }
+            LockTarget::Handle(handle) => {
+                let file = self
+                    .handles
+                    .get(handle)
+                    .filter(|file| file.connection == connection)
+                    .ok_or(FsError::InvalidHandle(*handle))?;
+                match file.tree {
+                    Some(..) => Err(FsError::ReadOnly(file.path.clone())),
+                    None => Ok((file.inode, file.path.clone())),
+                }
+            }
+        }
+    }
+    pub fn unlock(&self, target: &LockTarget, connection: ConnectionId) -> Result<(), FsError> {
+        let (inode, _) = self.lockable(target, connection)?;
+        self.locks.unlock(inode, connection);
+        Ok(())
+    }
+    // Reads up to len bytes from offset, at most MAX_READ_LEN so the reply
+    // fits in a frame, an empty result means end of file
+    pub fn read_range(&self, target: String, offset: u64, len: u32) -> Result<Vec<u8>, FsError> {
+        let len = len.min(MAX_READ_LEN);
+        let live = self.file_system.read().recover();
+        let fs = live.view(&self.resolve(&target));
+        let destination = self.locate(fs, &target)?;
+        let id = fs
+            .resolve_id(&destination, true)
+            .ok_or_else(|| FsError::NotFound(destination.clone()))?;
+        let read = match fs.node(id).as_deref() {
+            Some(FileLike { data, .. }) => {
+                let start = data.len().min(offset.try_into().unwrap_or(usize::MAX));
+                let end = data.len().min(start.saturating_add(len as usize));
+                data[start..end].to_vec()
+            }
+            Some(..) => return Err(FsError::IsADirectory(destination)),
+            None => return Err(FsError::NotFound(destination)),
+        };
+        fs.accessed(id);
+        Ok(read)
+    }
+    // Names of the snapshots kept, in order
+    pub fn list_snapshots(&self) -> Vec<String> {
+        let fs = self.file_system.read().recover();
+        fs.snapshots().map(|(name, _)| name.to_string()).collect()
+    }
+    pub fn create_snapshot(&mut self, name: &str) -> Result<(), FsError> {
+        let mut fs = self.file_system.write().recover();
+        self.log(self.snapshot_record(SnapshotAction::Create, name))?;
+        fs.create_snapshot(name)
+    }
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<(), FsError> {
+        let mut fs = self.file_system.write().recover();
+        self.log(self.snapshot_record(SnapshotAction::Delete, name))?;
+        fs.delete_snapshot(name)
+    }
+    // Rolls the whole tree back, handles left open on files since made or
+    // removed go stale
+    pub fn restore_snapshot(&mut self, name: &str) -> Result<(), FsError> {
+        let mut fs = self.file_system.write().recover();
+        self.log(self.snapshot_record(SnapshotAction::Restore, name))?;
+        fs.restore_snapshot(name)
+    }
+    fn snapshot_record(&self, action: SnapshotAction, name: &str) -> Option<Record> {
+        self.record(|| Command::SNAPSHOT {
+            action,
+            name: name.to_string(),
+        })
+    }
+}
+
+// Adds the sorted entries of dir to out, then those of each subdirectory when
+// listing recursively. Links are never followed so this always ends
+fn list_into(fs: &Trie, dir: &FsLike, prefix: &Path, options: &ListOptions, out: &mut Vec<Stat>) {
+    let Some(children) = dir.children() else {
+        return;
+    };
+    // Described one at a time, a file linked twice here can't be locked twice
+    let mut entries = children
+        .iter()
+        .filter(|(name, _)| options.all || !name.to_string_lossy().starts_with('.'))
+        .filter_map(|(name, id)| {
+            let name = prefix.join(name);
+            let node = fs.node(*id)?;
+            let described = stat(name.to_string_lossy().into_owned(), &node);
+            Some((name, *id, described))
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|(a_name, _, a), (b_name, _, b)| {
+        let order = if options.by_size {
+            b.size.cmp(&a.size)
+        } else if options.by_time {
+            b.modified.cmp(&a.modified)
+        } else {
+            std::cmp::Ordering::Equal
+        };
+        order.then_with(|| a_name.cmp(b_name))
+    });
+    let subdirectories = entries
+        .iter()
+        .filter(|(_, _, described)| described.kind == NodeKind::Directory)
+        .map(|(name, id, _)| (name.clone(), *id))
+        .collect::<Vec<_>>();
+    out.extend(entries.into_iter().map(|(_, _, described)| described));
+    if options.recursive {
+        for (name, id) in subdirectories {
+            if let Some(node) = fs.node(id) {
+                list_into(fs, &node, &name, options, out);
+            }
+        }
+    }
+}
+
+// Walks node and everything below it in name order, collecting the paths
+// that match query. Links are reported but never followed
+fn find_into(
+    fs: &Trie,
+    path: PathBuf,
+    node: &FsLike,
+    depth: u32,
+    query: &FindQuery,
+    now: SystemTime,
+    out: &mut Vec<PathBuf>,
+) {
+    let name = path
+        .file_name()
+        .map_or("/".into(), |name| name.to_string_lossy());
+    let age_days = now
+        .duration_since(node.meta().modified)
+        .unwrap_or_default()
+        .as_secs()
+        / (24 * 60 * 60);
+    let matched = query
+        .name
+        .as_ref()
+        .is_none_or(|pattern| glob::matches(pattern, &name))
+        && query.kind.is_none_or(|kind| kind == kind_of(node))
+        && query.size.is_none_or(|size| size.test(node.size()))
+        && query.mtime.is_none_or(|mtime| mtime.test(age_days));
+    if matched {
+        out.push(path.clone());
+    }
+    if query.max_depth.is_some_and(|max| depth >= max) {
+        return;
+    }
+    let Some(children) = node.children() else {
+        return;
+    };
+    let mut children = children.iter().collect::<Vec<_>>();
+    children.sort();
+    for (child_name, id) in children {
+        if let Some(child) = fs.node(*id) {
+            find_into(
+                fs,
+                path.join(child_name),
+                &child,
+                depth + 1,
+                query,
+                now,
+                out,
+            );
+        }
+    }
+}
+
+// Adds every file in the directory at id in name order, and with recursive
+// every file below it the way grep -r walks. Links are not followed
+fn files_into(
+    fs: &Trie,
+    id: InodeId,
+    prefix: &Path,
+    recursive: bool,
+    out: &mut Vec<(PathBuf, InodeId)>,
+) {
+    let Some(dir) = fs.node(id) else {
+        return;
+    };
+    let Some(children) = dir.children() else {
+        return;
+    };
+    let mut children = children.iter().collect::<Vec<_>>();
+    children.sort();
+    for (name, child) in children {
+        // Let go of the child before walking into it
+        let kind = fs.node(*child).map(|node| kind_of(&node));
+        match kind {
+            Some(NodeKind::File) => out.push((prefix.join(name), *child)),
+            Some(NodeKind::Directory) if recursive => {
+                files_into(fs, *child, &prefix.join(name), true, out)
+            }
+            _ => {}
+        }
+    }
+}
+
+// Like cp and mv, naming an existing directory as the destination puts the
+// source inside it under its own name
+fn into_directory(fs: &Trie, destination: PathBuf, source: &Path) -> Result<PathBuf, FsError> {
+    let is_dir = matches!(fs.get(&destination).as_deref(), Some(DirectoryLike { .. }));
+    match is_dir {
+        true => {
+            let name = source
+                .file_name()
+                .ok_or_else(|| FsError::InvalidPath(source.to_path_buf()))?;
+            Ok(fs.canonicalize(&destination, true)?.join(name))
+        }
+        false => Ok(destination),
+    }
+}
+
+// The directory path goes in and what's already there, when no link leads
+// through path and a change to it can be made holding just that directory.
+// See Trie::entries
+fn place(fs: &Trie, path: &Path) -> Option<(InodeId, Option<InodeId>)> {
+    trie::writable(path).ok()?;
+    if fs.canonicalize(path, false).ok()? != path {
+        return None;
+    }
+    path.file_name()?;
+    let parent = fs.resolve_id(path.parent()?, false)?;
+    // Checked before resolving path, which reads the parent again
+    if !matches!(fs.node(parent).as_deref()?, DirectoryLike { .. }) {
+        return None;
+    }
+    Some((parent, fs.resolve_id(path, false)))
+}
+
+// Several sources can only go into a directory, checked before any is touched
+fn several_into_directory(
+    fs: &Trie,
+    sources: &[PathBuf],
+    destination: &Path,
+) -> Result<(), FsError> {
+    match fs.get(destination).as_deref() {
+        _ if sources.len() < 2 => Ok(()),
+        Some(DirectoryLike { .. }) => Ok(()),
+        Some(..) => Err(FsError::NotADirectory(destination.to_path_buf())),
+        None => Err(FsError::NotFound(destination.to_path_buf())),
+    }
+}
+
+// The file at destination checked for opening with options, emptied when
+// they truncate
+fn open_at(
+    fs: &Trie,
+    destination: PathBuf,
+    options: &OpenOptions,
+) -> Result<(InodeId, PathBuf), FsError> {
+    let inode = fs
+        .resolve_id(&destination, true)
+        .ok_or_else(|| FsError::NotFound(destination.clone()))?;
+    match fs.node(inode).as_deref() {
+        Some(FileLike { .. }) => {}
+        Some(..) => return Err(FsError::IsADirectory(destination)),
+        None => return Err(FsError::NotFound(destination)),
+    }
+    if options.truncate {
+        fs.set_data(&destination, Vec::new())?;
+    }
+    Ok((inode, destination))
+}
+
+fn kind_of(node: &FsLike) -> NodeKind {
+    match node {
+        DirectoryLike { .. } => NodeKind::Directory,
+        FileLike { .. } => NodeKind::File,
+        SymlinkLike { .. } => NodeKind::Symlink,
+    }
+}
+
+fn nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+// Wire form of a node's metadata, name is however the caller referred to it
+fn stat(name: String, node: &FsLike) -> Stat {
+    let meta = node.meta();
+    Stat {
+        name,
+        kind: kind_of(node),
+        inode: meta.inode,
+        links: meta.links,
+        size: node.size(),
+        owner: meta.owner.clone(),
+        group: meta.group.clone(),
+        mode: meta.mode,
+        created: nanos(meta.created),
+        modified: nanos(meta.modified),
+        accessed: nanos(meta.accessed),
+        target: match node {
+            SymlinkLike { target, .. } => target.to_string_lossy().into_owned(),
+            _ => String::new(),
+        },
     }
 }
@@ -0,0 +1,248 @@
+/*
+The whole tree on disk.
+`magic "EPHS" | version u8 | log generation u64 | log end u64 | tree |
+snapshot count u32` then `name | tree` per named snapshot. A tree is
+`root u64 | inode count u64 | inodes`, each one `inode u64 | kind u8 |
+links u32 | owner | group | mode u32 | created u64 | modified u64 |
+accessed u64 | contents`, times in nanoseconds since the epoch. A
+directory holds its entry count u32 then a name and inode per entry, a
+link its target. A file holds `0 | data` the first time its data is
+written and `1 | index u32` of that write after, so data the snapshots
+share is stored and loaded once. Fields are encoded like frame payloads.
+ */
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use transport_layer::frame::ProtocolError;
+use transport_layer::wire::{put_bytes, put_str, put_u32, put_u64, put_u8, Reader};
+
+use crate::trie::{FsLike, Metadata, Trie};
+use crate::wal::Checkpoint;
+
+const MAGIC: &[u8; 4] = b"EPHS";
+const VERSION: u8 = 1;
+
+const DIRECTORY: u8 = 0;
+const FILE: u8 = 1;
+const SYMLINK: u8 = 2;
+
+// How file data is written, see the top
+const NEW_DATA: u8 = 0;
+const SEEN_DATA: u8 = 1;
+
+// Every inode of fs, reached from its root or not, with the checkpoint in
+// the log where it ends
+pub fn encode(fs: &Trie, checkpoint: Checkpoint) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    put_u8(&mut out, VERSION);
+    put_u64(&mut out, checkpoint.generation);
+    put_u64(&mut out, checkpoint.end);
+    let mut seen = HashMap::new();
+    encode_tree(fs, &mut out, &mut seen);
+    let snapshots = fs.snapshots().collect::<Vec<_>>();
+    put_u32(&mut out, snapshots.len() as u32);
+    for (name, snapshot) in snapshots {
+        put_str(&mut out, name);
+        encode_tree(snapshot, &mut out, &mut seen);
+    }
+    out
+}
+
+// seen numbers the file data written so far, in order
+fn encode_tree(fs: &Trie, out: &mut Vec<u8>, seen: &mut HashMap<*const Vec<u8>, u32>) {
+    put_u64(out, fs.root());
+    let inodes = fs.inodes();
+    put_u64(out, inodes.len() as u64);
+    for id in inodes {
+        if let Some(node) = fs.node(id) {
+            encode_node(&node, out, seen);
+        }
+    }
+}
+
+fn encode_node(node: &FsLike, out: &mut Vec<u8>, seen: &mut HashMap<*const Vec<u8>, u32>) {
+    let meta = node.meta();
+    put_u64(out, meta.inode);
+    put_u8(
+        out,
+        match node {
+            FsLike::DirectoryLike { .. } => DIRECTORY,
+            FsLike::FileLike { .. } => FILE,
+            FsLike::SymlinkLike { .. } => SYMLINK,
+        },
+    );
+    put_u32(out, meta.links);
+    put_str(out, &meta.owner);
+    put_str(out, &meta.group);
+    put_u32(out, meta.mode);
+    for time in [meta.created, meta.modified, meta.accessed] {
+        put_u64(out, nanos(time));
+    }
+    match node {
+        FsLike::DirectoryLike { children, .. } => {
+            put_u32(out, children.len() as u32);
+            for (name, id) in children.iter() {
+                put_bytes(out, name.as_os_str().as_bytes());
+                put_u64(out, *id);
+            }
+        }
+        FsLike::FileLike { data, .. } => match seen.get(&Arc::as_ptr(data)) {
+            Some(index) => {
+                put_u8(out, SEEN_DATA);
+                put_u32(out, *index);
+            }
+            None => {
+                seen.insert(Arc::as_ptr(data), seen.len() as u32);
+                put_u8(out, NEW_DATA);
+                put_bytes(out, data);
+            }
+        },
+        FsLike::SymlinkLike { target, .. } => put_bytes(out, target.as_os_str().as_bytes()),
+    }
+}
+
+// The tree and checkpoint encode wrote, with a fresh index when indexed
+pub fn decode(bytes: &[u8], indexed: bool) -> io::Result<(Trie, Checkpoint)> {
+    let Some(bytes) = bytes.strip_prefix(MAGIC) else {
+        return Err(invalid("not a snapshot"));
+    };
+    let mut reader = Reader::new(bytes);
+    let checkpoint = match reader.u8().map_err(malformed)? {
+        VERSION => Checkpoint {
+            generation: reader.u64().map_err(malformed)?,
+            end: reader.u64().map_err(malformed)?,
+        },
+        version => return Err(invalid(&format!("snapshot version {version} unsupported"))),
+    };
+    // Data written before, in order
+    let mut seen = Vec::new();
+    let mut fs = decode_tree(&mut reader, &mut seen, indexed)?;
+    for _ in 0..reader.u32().map_err(malformed)? {
+        let name = reader.string().map_err(malformed)?;
+        let snapshot = decode_tree(&mut reader, &mut seen, false)?;
+        fs.load_snapshot(name, snapshot);
+    }
+    if !reader.is_empty() {
+        return Err(invalid("trailing bytes after the last inode"));
+    }
+    Ok((fs, checkpoint))
+}
+
+fn decode_tree(
+    reader: &mut Reader,
+    seen: &mut Vec<Arc<Vec<u8>>>,
+    indexed: bool,
+) -> io::Result<Trie> {
+    let root = reader.u64().map_err(malformed)?;
+    let count = reader.u64().map_err(malformed)?;
+    let mut nodes = HashMap::new();
+    for _ in 0..count {
+        let node = decode_node(reader, seen).map_err(malformed)?;
+        nodes.insert(node.meta().inode, node);
+    }
+    Trie::from_nodes(root, nodes, indexed).map_err(|problems| invalid(&problems.join(", ")))
+}
+
+fn decode_node(reader: &mut Reader, seen: &mut Vec<Arc<Vec<u8>>>) -> Result<FsLike, ProtocolError> {
+    let inode = reader.u64()?;
+    let kind = reader.u8()?;
+    let meta = Metadata {
+        inode,
+        links: reader.u32()?,
+        owner: reader.string()?,
+        group: reader.string()?,
+        mode: reader.u32()?,
+        created: time(reader.u64()?),
+        modified: time(reader.u64()?),
+        accessed: time(reader.u64()?),
+    };
+    Ok(match kind {
+        DIRECTORY => {
+            let mut children = HashMap::new();
+            for _ in 0..reader.u32()? {
+                let name = PathBuf::from(OsStr::from_bytes(&reader.bytes()?));
+                children.insert(name, reader.u64()?);
+            }
+            FsLike::DirectoryLike {
+                children: Arc::new(children),
+                meta,
+            }
+        }
+        FILE => FsLike::FileLike {
+            data: decode_data(reader, seen)?,
+            meta,
+        },
+        SYMLINK => FsLike::SymlinkLike {
+            target: PathBuf::from(OsStr::from_bytes(&reader.bytes()?)),
+            meta,
+        },
+        _ => return Err(ProtocolError::Malformed("unknown inode kind")),
+    })
+}
+
+fn decode_data(
+    reader: &mut Reader,
+    seen: &mut Vec<Arc<Vec<u8>>>,
+) -> Result<Arc<Vec<u8>>, ProtocolError> {
+    match reader.u8()? {
+        NEW_DATA => {
+            let data = Arc::new(reader.bytes()?);
+            seen.push(data.clone());
+            Ok(data)
+        }
+        SEEN_DATA => seen
+            .get(reader.u32()? as usize)
+            .cloned()
+            .ok_or(ProtocolError::Malformed("file data not written before")),
+        _ => Err(ProtocolError::Malformed("unknown file data tag")),
+    }
+}
+
+pub fn load(path: &Path, indexed: bool) -> io::Result<(Trie, Checkpoint)> {
+    decode(&std::fs::read(path)?, indexed)
+}
+
+// Writes bytes next to path then renames them over it, so a crash leaves
+// either the old file or the new one but never half of either
+pub fn write(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".tmp");
+    let partial = PathBuf::from(partial);
+    let mut file = File::create(&partial)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&partial, path)?;
+    // The rename only survives a crash once its directory is synced
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+fn nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn time(nanos: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(nanos)
+}
+
+fn invalid(why: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, why.to_string())
+}
+
+fn malformed(err: ProtocolError) -> io::Error {
+    match err {
+        ProtocolError::Malformed(why) => invalid(why),
+        err => invalid(&err.to_string()),
+    }
+}
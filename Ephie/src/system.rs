@@ -1,4 +1,16 @@
-use crate::trie::FsLike;
-use std::sync::{Arc, Mutex};
+use crate::trie::Trie;
+use std::sync::{Arc, LockResult, PoisonError, RwLock};
 
-pub type FileSystem = Arc<Mutex<FsLike>>;
+// Readers share the tree, only changing its shape takes it whole
+pub type FileSystem = Arc<RwLock<Trie>>;
+
+// Locks are still taken after a panic poisoned them. The connection that
+// panicked is closed and the tree repaired, see Trie::repair
+pub trait Recover<T> {
+    fn recover(self) -> T;
+}
+impl<T> Recover<T> for LockResult<T> {
+    fn recover(self) -> T {
+        self.unwrap_or_else(PoisonError::into_inner)
+    }
+}
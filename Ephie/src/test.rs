@@ -1,44 +1,75 @@
 use crate::{
-    session::{self, Session},
-    trie::{FsLike, FsLike::FileLike},
+    blocking,
+    error::FsError,
+    execute, glob,
+    index::{self, TrigramIndex},
+    locks::Locks,
+    path::resolve,
+    process, replay, respond, save,
+    session::Session,
+    snapshot,
+    trie::{FsLike, Trie, ENTRY_TURNS, MAX_FILE_SIZE, MAX_LINK_DEPTH},
+    wal::{self, Checkpoint, Log, Record, Wal},
+    Sessions,
 };
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{
-    collections::{HashMap, HashSet},
-    sync::{Arc, Mutex},
+    collections::HashSet,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use transport_layer::command::{
+    Command, Compare, FindQuery, GrepOptions, ListOptions, LockKind, LockTarget, OpenOptions,
+    SnapshotAction, Whence, MAX_READ_LEN,
 };
 
-fn test_system() -> FsLike {
-    let mut system = FsLike::new();
-    system
-        .insert(PathBuf::from("/"), FsLike::new())
-        .expect("Failed to insert");
+use dashmap::DashMap;
+use transport_layer::frame::{read_frame, write_frame, Frame, MAX_PAYLOAD_LEN};
+use transport_layer::response::{Body, NodeKind, Response, Stat, Status};
+use transport_layer::wire::put_u32;
+
+fn test_system() -> Trie {
+    let system = Trie::new();
     system
-        .insert(PathBuf::from("/Documents/"), FsLike::new())
+        .insert(PathBuf::from("/Documents/"), FsLike::directory("root"))
         .expect("Failed to insert");
     system
-        .insert(PathBuf::from("/Documents/projects"), FsLike::new())
+        .insert(
+            PathBuf::from("/Documents/projects"),
+            FsLike::directory("root"),
+        )
         .expect("Failed to insert");
     system
-        .insert(PathBuf::from("/Documents/paperwork"), FsLike::new())
+        .insert(
+            PathBuf::from("/Documents/paperwork"),
+            FsLike::directory("root"),
+        )
         .expect("Failed to insert");
     system
-        .insert(PathBuf::from("/Downloads/"), FsLike::new())
+        .insert(PathBuf::from("/Downloads/"), FsLike::directory("root"))
         .expect("Failed to insert");
     system
         .insert(
             PathBuf::from("/Downloads/test.hello"),
-            FileLike {
-                data: String::from("hello world").into_bytes(),
-            },
+            FsLike::file("root", "hello world"),
         )
         .expect("Failed to insert");
     system
 }
 
+// Names in the working directory, the way the old `ls` returned them
+fn names(session: &Session) -> HashSet<String> {
+    session
+        .list("", &ListOptions::default())
+        .unwrap()
+        .into_iter()
+        .map(|stat| stat.name)
+        .collect()
+}
+
 fn test_session() -> Session {
-    let db = Arc::new(Mutex::new(test_system()));
-    Session::new("TestUser".to_string(), db.clone())
+    let db = Arc::new(RwLock::new(test_system()));
+    Session::new("TestUser".to_string(), db.clone(), Locks::default())
 }
 
 #[test]
@@ -50,7 +81,7 @@ fn test_ls_at_root() {
     let expected: HashSet<String> = vec!["Documents".to_string(), "Downloads".to_string()]
         .into_iter()
         .collect();
-    let out = session.list();
+    let out = names(&session);
     println!("out is {:#?}", out);
     assert_eq!(expected, out);
 }
@@ -123,9 +154,7 @@ fn test_cd_parent_multi() {
     let out = session.current_dir();
     assert_eq!(out, PathBuf::from("/Documents/projects"))
 }
-// We don't support this yet
 #[test]
-#[should_panic]
 fn test_cd_parent_nested() {
     let mut session = test_session();
     session
@@ -141,7 +170,7 @@ fn test_mkdir_absolute() {
     session
         .make_dir("/Pictures".to_string())
         .expect("Root not found");
-    let out = session.list();
+    let out = names(&session);
     assert!(out.contains("Pictures"));
 }
 #[test]
@@ -151,7 +180,7 @@ fn test_mkdir_relative() {
     session
         .make_dir("Pictures".to_string())
         .expect("Root not found");
-    let out = session.list();
+    let out = names(&session);
     assert!(out.contains("Pictures"));
 }
 #[test]
@@ -160,7 +189,7 @@ fn test_mkdir_parent() {
     session.change_dir("Documents".to_string()).unwrap();
     session.make_dir("../Pictures".to_string()).unwrap();
     session.change_dir("..".to_string()).unwrap();
-    let out = session.list();
+    let out = names(&session);
     assert!(out.contains("Pictures"))
 }
 #[test]
@@ -172,7 +201,7 @@ fn test_mkdir_absolute_nested() {
     session
         .change_dir("Pictures".to_string())
         .expect("not found");
-    let out = session.list();
+    let out = names(&session);
     assert!(out.contains("Mexico"));
 }
 #[test]
@@ -184,23 +213,25 @@ fn test_mkdir_relative_nested() {
     session
         .change_dir("Pictures".to_string())
         .expect("not found");
-    let out = session.list();
+    let out = names(&session);
     assert!(out.contains("Mexico"));
 }
 #[test]
 fn test_rm_directory_present() {
     let mut session = test_session();
     session.remove("/Downloads".to_string()).unwrap();
-    let out = session.list();
+    let out = names(&session);
     assert!(!out.contains("Downloads"))
 }
 #[test]
 fn test_rm_file_nested() {
     let mut session = test_session();
-    session.write_file("Downloads/new.file".to_string(), "test content".to_string());
+    session
+        .write_file("Downloads/new.file".to_string(), "test content".to_string())
+        .unwrap();
     session.remove("/Downloads/new.file".to_string()).unwrap();
     session.change_dir("Downloads".to_string()).unwrap();
-    let out = session.list();
+    let out = names(&session);
     assert!(!out.contains("new.file"))
 }
 #[test]
@@ -211,19 +242,21 @@ fn test_rm_file_nested_relative() {
         .unwrap();
     session.remove("Downloads/new.file".to_string()).unwrap();
     session.change_dir("Downloads".to_string()).unwrap();
-    let out = session.list();
+    let out = names(&session);
     assert!(!out.contains("new.file"))
 }
 #[test]
 fn test_rm_directory_nested() {
     let mut session = test_session();
-    session.write_file(
-        "Downloads/test/new.file".to_string(),
-        "test content".to_string(),
-    );
+    session
+        .write_file(
+            "Downloads/test/new.file".to_string(),
+            "test content".to_string(),
+        )
+        .unwrap();
     session.remove("/Downloads/test".to_string()).unwrap();
     session.change_dir("Downloads".to_string()).unwrap();
-    let out = session.list();
+    let out = names(&session);
     assert!(!out.contains("test"))
 }
 #[test]
@@ -237,7 +270,7 @@ fn test_rm_file_presnt() {
     let mut session = test_session();
     session.remove("Downloads/test.hello".to_string()).unwrap();
     session.change_dir("Downloads".to_string()).unwrap();
-    let out = session.list();
+    let out = names(&session);
     assert!(!out.contains("test.hello"))
 }
 #[test]
@@ -254,8 +287,8 @@ fn test_touch_relative() {
     session
         .touch("Documents/Files/file.txt".to_string())
         .unwrap();
-    session.change_dir("Documents/Files".to_string());
-    let out = session.list();
+    session.change_dir("Documents/Files".to_string()).unwrap();
+    let out = names(&session);
     assert!(out.contains("file.txt"))
 }
 #[test]
@@ -266,7 +299,7 @@ fn test_touch_existing_dir() {
     session
         .change_dir("Documents/paperwork".to_string())
         .unwrap();
-    assert!(session.list().is_empty())
+    assert!(names(&session).is_empty())
 }
 #[test]
 fn test_touch_existing_file_doesnt_overwrite() {
@@ -291,7 +324,7 @@ fn test_read_file_exists() {
 #[should_panic]
 fn test_read_file_missing() {
     let session = test_session();
-    let out = session
+    let _out = session
         .read_file("Downloads/missing.hello".to_string())
         .unwrap();
 }
@@ -299,7 +332,7 @@ fn test_read_file_missing() {
 #[should_panic]
 fn test_read_file_not_file() {
     let session = test_session();
-    let out = session.read_file("Downloads".to_string()).unwrap();
+    let _out = session.read_file("Downloads".to_string()).unwrap();
 }
 #[test]
 fn test_write_file() {
@@ -327,25 +360,182 @@ fn test_write_file_should_overwrite() {
     let out = session.read_file("Downloads/test.hello".into()).unwrap();
     assert_eq!(out, expected.as_bytes())
 }
+fn by_name(pattern: &str) -> FindQuery {
+    FindQuery {
+        name: Some(pattern.to_string()),
+        ..FindQuery::default()
+    }
+}
 #[test]
-fn test_find_local_present() {
+fn test_find_by_name() {
     let session = test_session();
-    let out = session.find_local("Do".to_string()).unwrap();
-    assert!(out.contains(&"Downloads".to_string()));
-    assert!(out.contains(&"Documents".to_string()));
+    let out = session.find("/", &by_name("Do*")).unwrap();
+    assert_eq!(out, vec!["/Documents", "/Downloads"]);
 }
 #[test]
-fn test_find_local_none() {
+fn test_find_none() {
     let session = test_session();
-    let out = session.find_local("Missing".to_string()).unwrap();
+    let out = session.find("/", &by_name("Missing")).unwrap();
     assert!(out.is_empty())
 }
 #[test]
-fn test_find_local_file() {
+fn test_find_from_working_dir() {
     let mut session = test_session();
     session.change_dir("Downloads".to_string()).unwrap();
-    let out = session.find_local("test".to_string()).unwrap();
-    assert_eq!(out, vec!["test.hello"])
+    let out = session.find("", &by_name("test.*")).unwrap();
+    assert_eq!(out, vec!["/Downloads/test.hello"])
+}
+#[test]
+fn test_find_everything_includes_root() {
+    let session = test_session();
+    let out = session.find("Documents", &FindQuery::default()).unwrap();
+    assert_eq!(
+        out,
+        vec!["/Documents", "/Documents/paperwork", "/Documents/projects"]
+    );
+}
+#[test]
+fn test_find_by_type() {
+    let mut session = test_session();
+    session
+        .symlink("test.hello".to_string(), "Downloads/link".to_string())
+        .unwrap();
+    let files = FindQuery {
+        kind: Some(NodeKind::File),
+        ..FindQuery::default()
+    };
+    assert_eq!(
+        session.find("/", &files).unwrap(),
+        vec!["/Downloads/test.hello"]
+    );
+    let links = FindQuery {
+        kind: Some(NodeKind::Symlink),
+        ..FindQuery::default()
+    };
+    assert_eq!(session.find("/", &links).unwrap(), vec!["/Downloads/link"]);
+}
+#[test]
+fn test_find_by_size() {
+    let session = test_session();
+    session
+        .write_file("Documents/big".to_string(), "x".repeat(2048))
+        .unwrap();
+    let query = FindQuery {
+        kind: Some(NodeKind::File),
+        size: Some(Compare::Above(1024)),
+        ..FindQuery::default()
+    };
+    assert_eq!(session.find("/", &query).unwrap(), vec!["/Documents/big"]);
+}
+#[test]
+fn test_find_by_mtime() {
+    let session = test_session();
+    let fresh = FindQuery {
+        mtime: Some(Compare::Below(1)),
+        ..by_name("test.hello")
+    };
+    assert_eq!(
+        session.find("/", &fresh).unwrap(),
+        vec!["/Downloads/test.hello"]
+    );
+    let stale = FindQuery {
+        mtime: Some(Compare::Above(1)),
+        ..FindQuery::default()
+    };
+    assert!(session.find("/", &stale).unwrap().is_empty());
+}
+#[test]
+fn test_find_max_depth() {
+    let session = test_session();
+    let query = FindQuery {
+        max_depth: Some(1),
+        ..FindQuery::default()
+    };
+    assert_eq!(
+        session.find("/", &query).unwrap(),
+        vec!["/", "/Documents", "/Downloads"]
+    );
+}
+#[test]
+fn test_find_missing_root() {
+    let session = test_session();
+    let out = session.find("Missing", &FindQuery::default());
+    assert!(matches!(out, Err(FsError::NotFound(..))));
+}
+#[test]
+fn test_find_delete() {
+    let session = test_session();
+    let query = FindQuery {
+        delete: true,
+        ..by_name("p*")
+    };
+    let out = session.find("/", &query).unwrap();
+    assert_eq!(out, vec!["/Documents/paperwork", "/Documents/projects"]);
+    assert!(session.find("/", &by_name("p*")).unwrap().is_empty());
+}
+#[test]
+fn test_find_delete_skips_full_directories() {
+    let session = test_session();
+    let query = FindQuery {
+        delete: true,
+        ..by_name("Downloads")
+    };
+    let out = session.find("/", &query);
+    assert!(matches!(out, Err(FsError::NotEmpty(..))));
+    assert_eq!(session.find("/", &by_name("Downloads")).unwrap().len(), 1);
+}
+#[test]
+fn test_find_delete_contents_before_directory() {
+    let session = test_session();
+    let query = FindQuery {
+        delete: true,
+        ..FindQuery::default()
+    };
+    session.find("Downloads", &query).unwrap();
+    assert!(!names(&session).contains("Downloads"));
+}
+#[test]
+fn test_glob_matches() {
+    assert!(glob::matches("*.hello", "test.hello"));
+    assert!(glob::matches("t?st*", "test.hello"));
+    assert!(glob::matches("[a-c]at", "bat"));
+    assert!(!glob::matches("[!a-c]at", "bat"));
+    assert!(glob::matches("\\*", "*"));
+    assert!(!glob::matches("\\*", "x"));
+    assert!(glob::matches("[]]", "]"));
+    assert!(!glob::matches("*.hello", "test.hell"));
+}
+#[test]
+fn test_glob_many_stars_is_quick() {
+    let start = Instant::now();
+    let pattern = "*a".repeat(12) + "b";
+    assert!(!glob::matches(&pattern, &"a".repeat(40)));
+    assert!(glob::matches(&pattern, &("a".repeat(40) + "b")));
+    assert!(glob::matches("*a*?[ab]*", "xxaxb"));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+#[test]
+fn test_glob_escape() {
+    let mut session = test_session();
+    for name in [
+        "a*b",
+        "aXb",
+        "[x]",
+        "x",
+        "back\\slash?",
+        "back\\slashy",
+        "plain\\",
+    ] {
+        session.touch(name.to_string()).unwrap();
+    }
+    for name in ["a*b", "[x]", "back\\slash?", "plain\\"] {
+        let escaped = glob::escape(&Path::new("/").join(name));
+        session.remove(escaped).unwrap();
+        assert!(!names(&session).contains(name));
+    }
+    for name in ["aXb", "x", "back\\slashy"] {
+        assert!(names(&session).contains(name));
+    }
 }
 #[test]
 fn test_cp_file() {
@@ -354,6 +544,7 @@ fn test_cp_file() {
         .copy(
             "Downloads/test.hello".to_string(),
             "Documents/test.hello".to_string(),
+            false,
         )
         .unwrap();
     session.change_dir("/Documents".to_string()).unwrap();
@@ -364,7 +555,11 @@ fn test_cp_file() {
 fn test_cp_to_here() {
     let mut session = test_session();
     session
-        .copy("Downloads/test.hello".to_string(), "test.hello".to_string())
+        .copy(
+            "Downloads/test.hello".to_string(),
+            "test.hello".to_string(),
+            false,
+        )
         .unwrap();
     let out: Vec<u8> = session.read_file("test.hello".to_string()).unwrap();
     assert_eq!(out, "hello world".as_bytes())
@@ -382,7 +577,7 @@ fn test_mv_file() {
     let out = session.read_file("test.hello".to_string()).unwrap();
     assert_eq!(out, "hello world".as_bytes());
     session.change_dir("/Downloads".to_string()).unwrap();
-    assert!(session.list().is_empty())
+    assert!(names(&session).is_empty())
 }
 #[test]
 fn test_mv_to_here() {
@@ -393,5 +588,2772 @@ fn test_mv_to_here() {
     let out: Vec<u8> = session.read_file("test.hello".to_string()).unwrap();
     assert_eq!(out, "hello world".as_bytes());
     session.change_dir("/Downloads".to_string()).unwrap();
-    assert!(session.list().is_empty())
+    assert!(names(&session).is_empty())
+}
+#[test]
+fn test_cd_missing_error() {
+    let mut session = test_session();
+    let out = session.change_dir("Missing".to_string());
+    assert_eq!(out, Err(FsError::NotFound(PathBuf::from("/Missing"))))
+}
+#[test]
+fn test_cd_file_error() {
+    let mut session = test_session();
+    let out = session.change_dir("Downloads/test.hello".to_string());
+    assert_eq!(
+        out,
+        Err(FsError::NotADirectory(PathBuf::from(
+            "/Downloads/test.hello"
+        )))
+    )
+}
+#[test]
+fn test_read_dir_error() {
+    let session = test_session();
+    let out = session.read_file("Downloads".to_string());
+    assert_eq!(out, Err(FsError::IsADirectory(PathBuf::from("/Downloads"))))
+}
+#[test]
+fn test_touch_under_file_error() {
+    let mut session = test_session();
+    let out = session.touch("/Downloads/test.hello/nested".to_string());
+    assert!(matches!(out, Err(FsError::NotADirectory(..))))
+}
+#[test]
+fn test_rm_root_error() {
+    let mut session = test_session();
+    let out = session.remove("/".to_string());
+    assert_eq!(out, Err(FsError::InvalidPath(PathBuf::from("/"))))
+}
+#[test]
+fn test_error_display_names_path() {
+    let err = FsError::NotFound(PathBuf::from("/Missing"));
+    assert_eq!(err.to_string(), "/Missing: No such file or directory")
+}
+#[test]
+fn test_write_binary_round_trip() {
+    let session = test_session();
+    let content = vec![0u8, 159, 146, 150, b'~', b'%', b'%', b'~', 255];
+    session
+        .write_file("Documents/blob.bin".to_string(), content.clone())
+        .unwrap();
+    let out = session.read_file("Documents/blob.bin".to_string()).unwrap();
+    assert_eq!(out, content)
+}
+#[test]
+fn test_chunked_upload_out_of_order() {
+    let mut session = test_session();
+    let transfer = session
+        .begin_upload("Downloads/big.bin".to_string())
+        .unwrap();
+    session.write_chunk(transfer, 4, b"5678").unwrap();
+    session.write_chunk(transfer, 0, b"1234").unwrap();
+    session.commit_upload(transfer).unwrap();
+    let out = session.read_file("Downloads/big.bin".to_string()).unwrap();
+    assert_eq!(out, b"12345678")
+}
+#[test]
+fn test_chunked_upload_replaces_existing() {
+    let mut session = test_session();
+    let transfer = session
+        .begin_upload("Downloads/test.hello".to_string())
+        .unwrap();
+    session.write_chunk(transfer, 0, b"bye").unwrap();
+    session.commit_upload(transfer).unwrap();
+    let out = session
+        .read_file("Downloads/test.hello".to_string())
+        .unwrap();
+    assert_eq!(out, b"bye")
+}
+#[test]
+fn test_chunk_after_commit_rejected() {
+    let mut session = test_session();
+    let transfer = session.begin_upload("big.bin".to_string()).unwrap();
+    session.commit_upload(transfer).unwrap();
+    let out = session.write_chunk(transfer, 0, b"late");
+    assert_eq!(out, Err(FsError::InvalidHandle(transfer)))
+}
+#[test]
+fn test_chunk_past_max_size_rejected() {
+    let mut session = test_session();
+    let transfer = session.begin_upload("big.bin".to_string()).unwrap();
+    let out = session.write_chunk(transfer, crate::trie::MAX_FILE_SIZE, b"x");
+    assert!(matches!(out, Err(FsError::FileTooLarge(..))))
+}
+#[test]
+fn test_read_range() {
+    let session = test_session();
+    let path = "Downloads/test.hello".to_string();
+    assert_eq!(session.read_range(path.clone(), 6, 3).unwrap(), b"wor");
+    assert_eq!(session.read_range(path.clone(), 6, 100).unwrap(), b"world");
+    assert!(session.read_range(path, 100, 3).unwrap().is_empty())
+}
+#[test]
+fn test_resolve_lexical_cases() {
+    let cases = [
+        ("/", "", "/"),
+        ("/", ".", "/"),
+        ("/", "..", "/"),
+        ("/", "../../..", "/"),
+        ("/", "Documents", "/Documents"),
+        ("/", "Documents/", "/Documents"),
+        ("/", "./Documents/./projects/.", "/Documents/projects"),
+        ("/Documents", "..", "/"),
+        ("/Documents/paperwork", "../..", "/"),
+        ("/Documents/paperwork", "../../..", "/"),
+        ("/Documents/paperwork", "../projects", "/Documents/projects"),
+        ("/Documents", "foo/../bar", "/Documents/bar"),
+        ("/Documents", "foo/bar/../../baz", "/Documents/baz"),
+        ("/Documents", "/Downloads", "/Downloads"),
+        ("/Documents", "/Downloads/../Documents", "/Documents"),
+        ("/Documents", "/..", "/"),
+        ("/Documents", "/../../Downloads", "/Downloads"),
+        (
+            "/Documents",
+            "//Downloads//test.hello",
+            "/Downloads/test.hello",
+        ),
+        ("/Documents", "notes..txt", "/Documents/notes..txt"),
+        ("/Documents", "..hidden", "/Documents/..hidden"),
+        ("/Documents", "...", "/Documents/..."),
+        ("/Documents", ".config", "/Documents/.config"),
+        ("/Documents", ".config/..", "/Documents"),
+    ];
+    for (working_dir, target, expected) in cases {
+        let out = resolve(Path::new(working_dir), target);
+        assert_eq!(
+            out.as_os_str(),
+            expected,
+            "resolving {target:?} from {working_dir:?}"
+        );
+    }
+}
+#[test]
+fn test_cd_parent_clamps_at_root() {
+    let mut session = test_session();
+    session.change_dir("Documents".to_string()).unwrap();
+    session.change_dir("../../../..".to_string()).unwrap();
+    assert_eq!(session.current_dir(), PathBuf::from("/"))
+}
+#[test]
+fn test_cd_absolute_with_parent() {
+    let mut session = test_session();
+    session
+        .change_dir("/Documents/../Downloads".to_string())
+        .unwrap();
+    assert_eq!(session.current_dir(), PathBuf::from("/Downloads"))
+}
+#[test]
+fn test_cd_parent_in_middle() {
+    let mut session = test_session();
+    session
+        .change_dir("Documents/missing/../projects".to_string())
+        .unwrap();
+    assert_eq!(session.current_dir(), PathBuf::from("/Documents/projects"))
+}
+#[test]
+fn test_cd_dot() {
+    let mut session = test_session();
+    session.change_dir("Documents".to_string()).unwrap();
+    session.change_dir(".".to_string()).unwrap();
+    assert_eq!(session.current_dir(), PathBuf::from("/Documents"))
+}
+#[test]
+fn test_name_containing_dots_is_not_a_parent() {
+    let mut session = test_session();
+    session.change_dir("Documents".to_string()).unwrap();
+    session.touch("notes..txt".to_string()).unwrap();
+    assert!(names(&session).contains("notes..txt"));
+    let out = session.read_file("/Documents/notes..txt".to_string());
+    assert!(out.unwrap().is_empty())
+}
+#[test]
+fn test_rm_parent_of_root_refused() {
+    let mut session = test_session();
+    let out = session.remove("..".to_string());
+    assert_eq!(out, Err(FsError::InvalidPath(PathBuf::from("/"))))
+}
+#[test]
+fn test_read_parent_relative() {
+    let mut session = test_session();
+    session
+        .change_dir("Documents/paperwork".to_string())
+        .unwrap();
+    let out = session
+        .read_file("../../Downloads/test.hello".to_string())
+        .unwrap();
+    assert_eq!(out, "hello world".as_bytes())
+}
+#[test]
+fn test_cp_with_parents() {
+    let mut session = test_session();
+    session
+        .change_dir("Documents/projects".to_string())
+        .unwrap();
+    session
+        .copy(
+            "../../Downloads/test.hello".to_string(),
+            "../paperwork/./copy.hello".to_string(),
+            false,
+        )
+        .unwrap();
+    let out = session
+        .read_file("/Documents/paperwork/copy.hello".to_string())
+        .unwrap();
+    assert_eq!(out, "hello world".as_bytes())
+}
+#[test]
+fn test_stat_file() {
+    let session = test_session();
+    let out = session.stat("Downloads/test.hello".to_string()).unwrap();
+    assert_eq!(out.kind, NodeKind::File);
+    assert_eq!(out.size, 11);
+    assert_eq!(out.owner, "root");
+    assert_eq!(out.mode_string(), "-rw-r--r--")
+}
+#[test]
+fn test_stat_missing() {
+    let session = test_session();
+    let out = session.stat("nothing".to_string());
+    assert_eq!(out, Err(FsError::NotFound(PathBuf::from("/nothing"))))
+}
+#[test]
+fn test_new_nodes_owned_by_user() {
+    let mut session = test_session();
+    session.make_dir("a/b".to_string()).unwrap();
+    session.touch("a/b/c".to_string()).unwrap();
+    for target in ["a", "a/b", "a/b/c"] {
+        let out = session.stat(target.to_string()).unwrap();
+        assert_eq!(out.owner, "TestUser")
+    }
+    let dir = session.stat("a/b".to_string()).unwrap();
+    assert_eq!(dir.kind, NodeKind::Directory);
+    assert_eq!(dir.size, 1);
+    assert_eq!(dir.mode_string(), "drwxr-xr-x")
+}
+#[test]
+fn test_write_keeps_identity() {
+    let session = test_session();
+    let before = session.stat("Downloads/test.hello".to_string()).unwrap();
+    session
+        .write_file("Downloads/test.hello".to_string(), "bye")
+        .unwrap();
+    let after = session.stat("Downloads/test.hello".to_string()).unwrap();
+    assert_eq!(after.inode, before.inode);
+    assert_eq!(after.created, before.created);
+    assert_eq!(after.size, 3);
+    assert!(after.modified >= before.modified)
+}
+#[test]
+fn test_write_onto_directory() {
+    let session = test_session();
+    let out = session.write_file("Documents".to_string(), "data");
+    assert_eq!(out, Err(FsError::IsADirectory(PathBuf::from("/Documents"))))
+}
+#[test]
+fn test_touch_existing_keeps_data() {
+    let mut session = test_session();
+    session.touch("Downloads/test.hello".to_string()).unwrap();
+    let out = session
+        .read_file("Downloads/test.hello".to_string())
+        .unwrap();
+    assert_eq!(out, "hello world".as_bytes())
+}
+#[test]
+fn test_mv_keeps_inode_cp_does_not() {
+    let mut session = test_session();
+    let original = session.stat("Downloads/test.hello".to_string()).unwrap();
+    session
+        .copy(
+            "Downloads/test.hello".to_string(),
+            "copy.hello".to_string(),
+            false,
+        )
+        .unwrap();
+    session
+        .mv(
+            "Downloads/test.hello".to_string(),
+            "moved.hello".to_string(),
+        )
+        .unwrap();
+    let copied = session.stat("copy.hello".to_string()).unwrap();
+    let moved = session.stat("moved.hello".to_string()).unwrap();
+    assert_ne!(copied.inode, original.inode);
+    assert_eq!(copied.owner, "TestUser");
+    assert_eq!(moved.inode, original.inode);
+    assert_eq!(moved.owner, "root")
+}
+#[test]
+fn test_mv_onto_itself() {
+    let mut session = test_session();
+    session
+        .mv(
+            "Downloads/test.hello".to_string(),
+            "Downloads/./test.hello".to_string(),
+        )
+        .unwrap();
+    let out = session
+        .read_file("Downloads/test.hello".to_string())
+        .unwrap();
+    assert_eq!(out, "hello world".as_bytes())
+}
+#[test]
+fn test_list_sorted() {
+    let session = test_session();
+    let out = session.list("", &ListOptions::default()).unwrap();
+    let names = out
+        .iter()
+        .map(|stat| stat.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["Documents", "Downloads"]);
+    assert!(out.iter().all(|stat| stat.kind == NodeKind::Directory))
+}
+#[test]
+fn test_symlink_read_through() {
+    let mut session = test_session();
+    session
+        .symlink("Downloads/test.hello".to_string(), "/hello".to_string())
+        .unwrap();
+    let out = session.read_file("hello".to_string()).unwrap();
+    assert_eq!(out, "hello world".as_bytes());
+    let listed = session.list("", &ListOptions::default()).unwrap();
+    assert!(listed.iter().any(|stat| stat.marked_name() == "hello@"))
+}
+#[test]
+fn test_symlink_relative_target() {
+    let mut session = test_session();
+    session
+        .symlink("../Downloads".to_string(), "Documents/dl".to_string())
+        .unwrap();
+    session.change_dir("Documents/dl".to_string()).unwrap();
+    assert_eq!(session.current_dir(), Path::new("/Downloads"));
+    let out = session.read_file("test.hello".to_string()).unwrap();
+    assert_eq!(out, "hello world".as_bytes())
+}
+#[test]
+fn test_symlink_write_and_touch_follow() {
+    let mut session = test_session();
+    session
+        .symlink("/Documents/new.txt".to_string(), "new".to_string())
+        .unwrap();
+    // A dangling link makes its target on write
+    session.write_file("new".to_string(), "made").unwrap();
+    let out = session.read_file("Documents/new.txt".to_string()).unwrap();
+    assert_eq!(out, "made".as_bytes());
+    // Parents behind a link are followed when creating
+    session
+        .symlink("Documents".to_string(), "docs".to_string())
+        .unwrap();
+    session.touch("docs/other.txt".to_string()).unwrap();
+    assert!(session.stat("Documents/other.txt".to_string()).is_ok())
+}
+#[test]
+fn test_symlink_loop() {
+    let mut session = test_session();
+    session.symlink("b".to_string(), "a".to_string()).unwrap();
+    session.symlink("a".to_string(), "b".to_string()).unwrap();
+    let out = session.read_file("a".to_string());
+    assert!(matches!(out, Err(FsError::LinkLoop(..))));
+    let out = session.change_dir("a".to_string());
+    assert!(matches!(out, Err(FsError::LinkLoop(..))))
+}
+#[test]
+fn test_symlink_chain_too_deep() {
+    let mut session = test_session();
+    session
+        .symlink("Downloads/test.hello".to_string(), "link0".to_string())
+        .unwrap();
+    for depth in 1..=MAX_LINK_DEPTH {
+        session
+            .symlink(format!("link{}", depth - 1), format!("link{}", depth))
+            .unwrap();
+    }
+    let out = session.read_file(format!("link{}", MAX_LINK_DEPTH - 1));
+    assert_eq!(out.unwrap(), "hello world".as_bytes());
+    let out = session.read_file(format!("link{}", MAX_LINK_DEPTH));
+    assert!(matches!(out, Err(FsError::LinkLoop(..))))
+}
+#[test]
+fn test_rm_symlink_keeps_target() {
+    let mut session = test_session();
+    session
+        .symlink("Downloads".to_string(), "dl".to_string())
+        .unwrap();
+    session.remove("dl".to_string()).unwrap();
+    assert!(!names(&session).contains("dl"));
+    assert!(names(&session).contains("Downloads"));
+    let out = session
+        .read_file("Downloads/test.hello".to_string())
+        .unwrap();
+    assert_eq!(out, "hello world".as_bytes())
+}
+#[test]
+fn test_readlink_and_stat() {
+    let mut session = test_session();
+    session
+        .symlink("Downloads/test.hello".to_string(), "hello".to_string())
+        .unwrap();
+    let out = session.read_link("hello".to_string()).unwrap();
+    assert_eq!(out, "Downloads/test.hello");
+    let stat = session.stat("hello".to_string()).unwrap();
+    assert_eq!(stat.kind, NodeKind::Symlink);
+    assert_eq!(stat.target, "Downloads/test.hello");
+    let out = session.read_link("Downloads".to_string());
+    assert_eq!(out, Err(FsError::NotALink(PathBuf::from("/Downloads"))))
+}
+#[test]
+fn test_symlink_exists() {
+    let mut session = test_session();
+    let out = session.symlink("anything".to_string(), "Downloads".to_string());
+    assert_eq!(
+        out,
+        Err(FsError::AlreadyExists(PathBuf::from("/Downloads")))
+    )
+}
+#[test]
+fn test_mv_symlink_moves_link() {
+    let mut session = test_session();
+    session
+        .symlink("/Downloads/test.hello".to_string(), "hello".to_string())
+        .unwrap();
+    session
+        .mv("hello".to_string(), "Documents/hello".to_string())
+        .unwrap();
+    let out = session.read_link("Documents/hello".to_string()).unwrap();
+    assert_eq!(out, "/Downloads/test.hello");
+    assert!(session.stat("Downloads/test.hello".to_string()).is_ok())
+}
+#[test]
+fn test_hard_link_shares_data() {
+    let mut session = test_session();
+    session
+        .link("Downloads/test.hello".to_string(), "hello".to_string())
+        .unwrap();
+    session.write_file("hello".to_string(), "changed").unwrap();
+    let out = session
+        .read_file("Downloads/test.hello".to_string())
+        .unwrap();
+    assert_eq!(out, "changed".as_bytes());
+    let first = session.stat("hello".to_string()).unwrap();
+    let second = session.stat("Downloads/test.hello".to_string()).unwrap();
+    assert_eq!(first.inode, second.inode);
+    assert_eq!(first.links, 2)
+}
+#[test]
+fn test_rm_hard_link_frees_last() {
+    let mut session = test_session();
+    let inode = session
+        .stat("Downloads/test.hello".to_string())
+        .unwrap()
+        .inode;
+    session
+        .link("Downloads/test.hello".to_string(), "hello".to_string())
+        .unwrap();
+    session.remove("Downloads/test.hello".to_string()).unwrap();
+    let out = session.read_file("hello".to_string()).unwrap();
+    assert_eq!(out, "hello world".as_bytes());
+    assert_eq!(session.stat("hello".to_string()).unwrap().links, 1);
+    session.remove("hello".to_string()).unwrap();
+    assert!(session.file_system.read().unwrap().node(inode).is_none())
+}
+#[test]
+fn test_rm_directory_frees_contents() {
+    let mut session = test_session();
+    let inode = session
+        .stat("Downloads/test.hello".to_string())
+        .unwrap()
+        .inode;
+    session.remove("Downloads".to_string()).unwrap();
+    assert!(session.file_system.read().unwrap().node(inode).is_none())
+}
+#[test]
+fn test_hard_link_directory_refused() {
+    let mut session = test_session();
+    let out = session.link("Documents".to_string(), "docs".to_string());
+    assert_eq!(out, Err(FsError::NotPermitted(PathBuf::from("/Documents"))))
+}
+#[test]
+fn test_hard_link_exists() {
+    let mut session = test_session();
+    let out = session.link("Downloads/test.hello".to_string(), "Documents".to_string());
+    assert_eq!(
+        out,
+        Err(FsError::AlreadyExists(PathBuf::from("/Documents")))
+    )
+}
+#[test]
+fn test_hard_link_missing_parent() {
+    let mut session = test_session();
+    let out = session.link("Downloads/test.hello".to_string(), "nope/hello".to_string());
+    assert_eq!(out, Err(FsError::NotFound(PathBuf::from("/nope"))))
+}
+#[test]
+fn test_mv_between_hard_links() {
+    let mut session = test_session();
+    session
+        .link("Downloads/test.hello".to_string(), "hello".to_string())
+        .unwrap();
+    // Both names are one file, so nothing changes
+    session
+        .mv("hello".to_string(), "Downloads/test.hello".to_string())
+        .unwrap();
+    assert!(names(&session).contains("hello"));
+    assert_eq!(session.stat("hello".to_string()).unwrap().links, 2)
+}
+#[test]
+fn test_cp_directory_needs_recursive() {
+    let mut session = test_session();
+    let out = session.copy("Documents".to_string(), "Backup".to_string(), false);
+    assert_eq!(out, Err(FsError::IsADirectory(PathBuf::from("/Documents"))))
+}
+#[test]
+fn test_cp_recursive() {
+    let mut session = test_session();
+    session
+        .copy("Downloads".to_string(), "Backup".to_string(), true)
+        .unwrap();
+    let out = session.read_file("Backup/test.hello".to_string()).unwrap();
+    assert_eq!(out, "hello world".as_bytes());
+    // The copy is independent of the original
+    session
+        .write_file("Backup/test.hello".to_string(), "changed")
+        .unwrap();
+    let out = session
+        .read_file("Downloads/test.hello".to_string())
+        .unwrap();
+    assert_eq!(out, "hello world".as_bytes());
+    let copy = session.stat("Backup".to_string()).unwrap();
+    assert_eq!(copy.owner, "TestUser")
+}
+#[test]
+fn test_cp_recursive_into_directory() {
+    let mut session = test_session();
+    session
+        .copy("Documents".to_string(), "Downloads".to_string(), true)
+        .unwrap();
+    session
+        .change_dir("Downloads/Documents/projects".to_string())
+        .unwrap();
+    assert!(names(&session).is_empty())
+}
+#[test]
+fn test_cp_into_itself() {
+    let mut session = test_session();
+    let out = session.copy(
+        "Documents".to_string(),
+        "Documents/projects/copy".to_string(),
+        true,
+    );
+    assert_eq!(
+        out,
+        Err(FsError::IntoDescendant(PathBuf::from(
+            "/Documents/projects/copy"
+        )))
+    )
+}
+#[test]
+fn test_cp_onto_same_file() {
+    let mut session = test_session();
+    let out = session.copy(
+        "Downloads/test.hello".to_string(),
+        "Downloads".to_string(),
+        false,
+    );
+    assert_eq!(
+        out,
+        Err(FsError::SameFile(PathBuf::from("/Downloads/test.hello")))
+    )
+}
+#[test]
+fn test_cp_onto_file_keeps_inode() {
+    let mut session = test_session();
+    session.touch("target".to_string()).unwrap();
+    let before = session.stat("target".to_string()).unwrap();
+    session
+        .copy(
+            "Downloads/test.hello".to_string(),
+            "target".to_string(),
+            false,
+        )
+        .unwrap();
+    let after = session.stat("target".to_string()).unwrap();
+    assert_eq!(after.inode, before.inode);
+    assert_eq!(after.size, 11)
+}
+#[test]
+fn test_mv_directory() {
+    let mut session = test_session();
+    let before = session.stat("Documents".to_string()).unwrap();
+    session
+        .mv("Documents".to_string(), "Archive".to_string())
+        .unwrap();
+    let after = session.stat("Archive".to_string()).unwrap();
+    assert_eq!(after.inode, before.inode);
+    assert!(session.stat("Archive/projects".to_string()).is_ok());
+    assert!(!names(&session).contains("Documents"))
+}
+#[test]
+fn test_mv_directory_into_directory() {
+    let mut session = test_session();
+    session
+        .mv("Documents".to_string(), "Downloads".to_string())
+        .unwrap();
+    assert!(session
+        .change_dir("/Downloads/Documents/paperwork".to_string())
+        .is_ok());
+    session.change_dir("/".to_string()).unwrap();
+    assert!(!names(&session).contains("Documents"))
+}
+#[test]
+fn test_mv_into_descendant() {
+    let mut session = test_session();
+    let out = session.mv("Documents".to_string(), "Documents/projects/x".to_string());
+    assert_eq!(
+        out,
+        Err(FsError::IntoDescendant(PathBuf::from(
+            "/Documents/projects/x"
+        )))
+    );
+    // Nothing was made along the way
+    session
+        .change_dir("Documents/projects".to_string())
+        .unwrap();
+    assert!(names(&session).is_empty())
+}
+#[test]
+fn test_rename_replaces_file() {
+    let fs = test_system();
+    fs.insert("/other", FsLike::file("root", "other")).unwrap();
+    let replaced = fs.get("/other").unwrap().meta().inode;
+    fs.rename(Path::new("/Downloads/test.hello"), Path::new("/other"))
+        .unwrap();
+    assert!(fs.get("/Downloads/test.hello").is_none());
+    match fs.get("/other").as_deref() {
+        Some(FsLike::FileLike { data, .. }) => assert_eq!(**data, b"hello world"),
+        _ => panic!("renamed file missing"),
+    }
+    assert!(fs.node(replaced).is_none())
+}
+#[test]
+fn test_rename_directory_over_empty_directory() {
+    let fs = test_system();
+    fs.rename(Path::new("/Downloads"), Path::new("/Documents/projects"))
+        .unwrap();
+    assert!(fs.get("/Documents/projects/test.hello").is_some());
+    assert!(fs.get("/Downloads").is_none())
+}
+#[test]
+fn test_rename_directory_over_full_directory() {
+    let fs = test_system();
+    let out = fs.rename(Path::new("/Documents/projects"), Path::new("/Downloads"));
+    assert_eq!(out, Err(FsError::NotEmpty(PathBuf::from("/Downloads"))));
+    assert!(fs.get("/Documents/projects").is_some());
+    assert!(fs.get("/Downloads/test.hello").is_some())
+}
+#[test]
+fn test_rename_kind_mismatch() {
+    let fs = test_system();
+    let out = fs.rename(Path::new("/Downloads/test.hello"), Path::new("/Documents"));
+    assert_eq!(out, Err(FsError::IsADirectory(PathBuf::from("/Documents"))));
+    let out = fs.rename(Path::new("/Documents"), Path::new("/Downloads/test.hello"));
+    assert_eq!(
+        out,
+        Err(FsError::NotADirectory(PathBuf::from(
+            "/Downloads/test.hello"
+        )))
+    )
+}
+#[test]
+fn test_rename_missing_parent() {
+    let fs = test_system();
+    let out = fs.rename(
+        Path::new("/Downloads/test.hello"),
+        Path::new("/nope/test.hello"),
+    );
+    assert_eq!(out, Err(FsError::NotFound(PathBuf::from("/nope"))));
+    assert!(fs.get("/Downloads/test.hello").is_some())
+}
+#[test]
+fn test_mv_is_atomic() {
+    let db = Arc::new(RwLock::new(test_system()));
+    let mut mover = Session::new("TestUser".to_string(), db.clone(), Locks::default());
+    let watcher = Session::new("Liz".to_string(), db, Locks::default());
+    let handle = std::thread::spawn(move || {
+        for _ in 0..500 {
+            mover
+                .mv("Downloads/test.hello".to_string(), "moved".to_string())
+                .unwrap();
+            mover
+                .mv("moved".to_string(), "Downloads/test.hello".to_string())
+                .unwrap();
+        }
+    });
+    // The file is always under exactly one of its names
+    while !handle.is_finished() {
+        let fs = watcher.file_system.read().unwrap();
+        let seen = [fs.get("/Downloads/test.hello"), fs.get("/moved")];
+        assert_eq!(seen.iter().filter(|node| node.is_some()).count(), 1);
+    }
+    handle.join().unwrap()
+}
+#[test]
+fn test_ls_other_directory() {
+    let mut session = test_session();
+    session
+        .change_dir("Documents/projects".to_string())
+        .unwrap();
+    for target in ["../../Downloads", "/Downloads"] {
+        let out = session.list(target, &ListOptions::default()).unwrap();
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].name, "test.hello")
+    }
+    let out = session.list("..", &ListOptions::default()).unwrap();
+    let names = out.iter().map(Stat::marked_name).collect::<Vec<_>>();
+    assert_eq!(names, vec!["paperwork/", "projects/"])
+}
+#[test]
+fn test_ls_file() {
+    let session = test_session();
+    let out = session
+        .list("Downloads/test.hello", &ListOptions::default())
+        .unwrap();
+    assert_eq!(out.len(), 1);
+    assert_eq!(out[0].name, "Downloads/test.hello");
+    assert_eq!(out[0].size, 11)
+}
+#[test]
+fn test_ls_missing() {
+    let session = test_session();
+    let out = session.list("nothing", &ListOptions::default());
+    assert_eq!(out, Err(FsError::NotFound(PathBuf::from("/nothing"))))
+}
+#[test]
+fn test_ls_all_shows_dot_files() {
+    let mut session = test_session();
+    session.touch(".config".to_string()).unwrap();
+    assert!(!names(&session).contains(".config"));
+    let options = ListOptions {
+        all: true,
+        ..ListOptions::default()
+    };
+    let out = session.list("", &options).unwrap();
+    let names = out
+        .iter()
+        .map(|stat| stat.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec![".", "..", ".config", "Documents", "Downloads"])
+}
+#[test]
+fn test_ls_recursive() {
+    let session = test_session();
+    let options = ListOptions {
+        recursive: true,
+        ..ListOptions::default()
+    };
+    let out = session.list("/", &options).unwrap();
+    let names = out
+        .iter()
+        .map(|stat| stat.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec![
+            "Documents",
+            "Downloads",
+            "Documents/paperwork",
+            "Documents/projects",
+            "Downloads/test.hello"
+        ]
+    )
+}
+#[test]
+fn test_ls_sort_by_size_and_time() {
+    let session = test_session();
+    session.write_file("small".to_string(), "a").unwrap();
+    session.write_file("large".to_string(), "abcdef").unwrap();
+    let by_size = ListOptions {
+        by_size: true,
+        ..ListOptions::default()
+    };
+    let out = session.list("", &by_size).unwrap();
+    let names = out
+        .iter()
+        .map(|stat| stat.name.as_str())
+        .collect::<Vec<_>>();
+    // Directories count their entries, ties fall back to the name
+    assert_eq!(names, vec!["large", "Documents", "Downloads", "small"]);
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    session.write_file("small".to_string(), "b").unwrap();
+    let by_time = ListOptions {
+        by_time: true,
+        ..ListOptions::default()
+    };
+    let out = session.list("", &by_time).unwrap();
+    assert_eq!(out[0].name, "small")
+}
+// Session over the test tree with a few logs to match against
+fn glob_session() -> Session {
+    let session = test_session();
+    for name in [
+        "Documents/a.log",
+        "Documents/b.log",
+        "Documents/notes.md",
+        "Documents/.hidden.log",
+        "Documents/projects/c.log",
+    ] {
+        session.write_file(name.to_string(), name).unwrap();
+    }
+    session
+}
+#[test]
+fn test_glob_is_pattern() {
+    assert!(glob::is_pattern("*.log"));
+    assert!(glob::is_pattern("docs/[ab].md"));
+    assert!(!glob::is_pattern("plain.log"));
+    assert!(!glob::is_pattern("\\*.log"));
+}
+#[test]
+fn test_glob_expand_skips_hidden() {
+    let session = glob_session();
+    let fs = session.file_system.read().unwrap();
+    let out = glob::expand(&fs, Path::new("/Documents/*.log"));
+    assert_eq!(
+        out,
+        vec![
+            PathBuf::from("/Documents/a.log"),
+            PathBuf::from("/Documents/b.log")
+        ]
+    );
+    let hidden = glob::expand(&fs, Path::new("/Documents/.*"));
+    assert_eq!(hidden, vec![PathBuf::from("/Documents/.hidden.log")]);
+}
+#[test]
+fn test_glob_expand_double_star() {
+    let session = glob_session();
+    let fs = session.file_system.read().unwrap();
+    let out = glob::expand(&fs, Path::new("/**/*.log"));
+    assert_eq!(
+        out,
+        vec![
+            PathBuf::from("/Documents/a.log"),
+            PathBuf::from("/Documents/b.log"),
+            PathBuf::from("/Documents/projects/c.log")
+        ]
+    );
+    let everything = glob::expand(&fs, Path::new("/Documents/projects/**"));
+    assert_eq!(everything, vec![PathBuf::from("/Documents/projects/c.log")]);
+}
+#[test]
+fn test_glob_expand_directories() {
+    let session = glob_session();
+    let fs = session.file_system.read().unwrap();
+    let out = glob::expand(&fs, Path::new("/D*/p?o*/*"));
+    assert_eq!(out, vec![PathBuf::from("/Documents/projects/c.log")]);
+}
+#[test]
+fn test_rm_glob() {
+    let mut session = glob_session();
+    session.remove("Documents/*.log".to_string()).unwrap();
+    let left = session.find("Documents", &by_name("*.log")).unwrap();
+    assert_eq!(
+        left,
+        vec!["/Documents/.hidden.log", "/Documents/projects/c.log"]
+    );
+}
+#[test]
+fn test_rm_glob_no_match() {
+    let mut session = glob_session();
+    let out = session.remove("*.missing".to_string());
+    assert_eq!(out, Err(FsError::NoMatch(PathBuf::from("/*.missing"))));
+    assert_eq!(out.unwrap_err().to_string(), "/*.missing: No matches found");
+}
+#[test]
+fn test_cp_glob_into_directory() {
+    let mut session = glob_session();
+    session.make_dir("backup".to_string()).unwrap();
+    session
+        .copy("Documents/*.log".to_string(), "backup".to_string(), false)
+        .unwrap();
+    session.change_dir("backup".to_string()).unwrap();
+    assert_eq!(
+        names(&session),
+        HashSet::from(["a.log".to_string(), "b.log".to_string()])
+    );
+}
+#[test]
+fn test_cp_glob_needs_directory() {
+    let mut session = glob_session();
+    let out = session.copy(
+        "Documents/*.log".to_string(),
+        "Documents/notes.md".to_string(),
+        false,
+    );
+    assert!(matches!(out, Err(FsError::NotADirectory(..))));
+}
+#[test]
+fn test_mv_glob() {
+    let mut session = glob_session();
+    session
+        .mv("Documents/?.log".to_string(), "Downloads".to_string())
+        .unwrap();
+    let moved = session.find("/", &by_name("?.log")).unwrap();
+    assert_eq!(
+        moved,
+        vec![
+            "/Documents/projects/c.log",
+            "/Downloads/a.log",
+            "/Downloads/b.log"
+        ]
+    );
+}
+#[test]
+fn test_read_glob_concatenates() {
+    let session = glob_session();
+    let out = session.read_file("Documents/[ab].log".to_string()).unwrap();
+    assert_eq!(out, b"Documents/a.logDocuments/b.log");
+}
+#[test]
+fn test_ls_glob() {
+    let mut session = glob_session();
+    session.change_dir("Documents".to_string()).unwrap();
+    let out = session
+        .list("[np]*", &ListOptions::default())
+        .unwrap()
+        .into_iter()
+        .map(|stat| stat.name)
+        .collect::<Vec<_>>();
+    // The empty paperwork directory has no entries to show
+    assert_eq!(out, vec!["notes.md", "projects/c.log"]);
+}
+// Session over a tree with a content index and a few files to search
+fn grep_session(system: Trie) -> Session {
+    let session = Session::new(
+        "TestUser".to_string(),
+        Arc::new(RwLock::new(system)),
+        Locks::default(),
+    );
+    for (name, contents) in [
+        ("src/main.rs", "fn main() {\n    // TODO wire up\n}\n"),
+        ("src/lib.rs", "pub fn helper() {}\n// todo: tests\n"),
+        ("src/deep/util.rs", "fn util() {}\n"),
+        ("notes.txt", "nothing to see\n"),
+    ] {
+        session.write_file(name.to_string(), contents).unwrap();
+    }
+    session
+}
+fn recursive() -> GrepOptions {
+    GrepOptions {
+        recursive: true,
+        ..GrepOptions::default()
+    }
+}
+#[test]
+fn test_grep_file() {
+    let session = grep_session(Trie::new());
+    let out = session
+        .grep("fn \\w+", "src/main.rs", &GrepOptions::default())
+        .unwrap();
+    assert_eq!(out, vec!["fn main() {"]);
+}
+#[test]
+fn test_grep_line_numbers() {
+    let session = grep_session(Trie::new());
+    let options = GrepOptions {
+        line_numbers: true,
+        ..GrepOptions::default()
+    };
+    let out = session.grep("TODO", "src/main.rs", &options).unwrap();
+    assert_eq!(out, vec!["2:    // TODO wire up"]);
+}
+#[test]
+fn test_grep_recursive_ignore_case() {
+    let session = grep_session(Trie::new());
+    let options = GrepOptions {
+        ignore_case: true,
+        ..recursive()
+    };
+    let out = session.grep("todo", "src", &options).unwrap();
+    assert_eq!(
+        out,
+        vec![
+            "src/lib.rs:// todo: tests",
+            "src/main.rs:    // TODO wire up"
+        ]
+    );
+}
+#[test]
+fn test_grep_recursive_from_working_dir() {
+    let session = grep_session(Trie::new());
+    let out = session.grep("^fn", "", &recursive()).unwrap();
+    assert_eq!(
+        out,
+        vec!["src/deep/util.rs:fn util() {}", "src/main.rs:fn main() {"]
+    );
+}
+#[test]
+fn test_grep_working_dir_files() {
+    let mut session = grep_session(Trie::new());
+    session.change_dir("src".to_string()).unwrap();
+    let out = session.grep("fn", "", &GrepOptions::default()).unwrap();
+    assert_eq!(
+        out,
+        vec!["lib.rs:pub fn helper() {}", "main.rs:fn main() {"]
+    );
+}
+#[test]
+fn test_grep_directory_needs_recursive() {
+    let session = grep_session(Trie::new());
+    let out = session.grep("fn", "src", &GrepOptions::default());
+    assert!(matches!(out, Err(FsError::IsADirectory(..))));
+}
+#[test]
+fn test_grep_glob() {
+    let session = grep_session(Trie::new());
+    let out = session
+        .grep("fn", "src/*.rs", &GrepOptions::default())
+        .unwrap();
+    assert_eq!(
+        out,
+        vec!["src/lib.rs:pub fn helper() {}", "src/main.rs:fn main() {"]
+    );
+}
+#[test]
+fn test_grep_invalid_pattern() {
+    let session = grep_session(Trie::new());
+    let out = session.grep("fn(", "src", &recursive());
+    assert!(matches!(out, Err(FsError::InvalidPattern(..))));
+}
+#[test]
+fn test_grep_indexed_matches_unindexed() {
+    let plain = grep_session(Trie::new());
+    let indexed = grep_session(Trie::with_index());
+    for pattern in ["helper", "(?i)TODO", "wire|util", "fn [a-z]+\\(", "nothing"] {
+        assert_eq!(
+            plain.grep(pattern, "/", &recursive()).unwrap(),
+            indexed.grep(pattern, "/", &recursive()).unwrap()
+        );
+    }
+}
+#[test]
+fn test_grep_indexed_matches_unindexed_escapes() {
+    let plain = grep_session(Trie::new());
+    let indexed = grep_session(Trie::with_index());
+    for session in [&plain, &indexed] {
+        session
+            .write_file("escapes.txt".to_string(), "xABC xfoo\n")
+            .unwrap();
+    }
+    for pattern in [
+        "\\x41BC",
+        "x\\x{41}BC",
+        "\\u0041BC",
+        "\\U00000041BC",
+        "\\pLfoo",
+        "\\PNxfoo",
+        "\\p{L}foo",
+    ] {
+        let found = plain.grep(pattern, "/", &recursive()).unwrap();
+        assert_eq!(found, vec!["/escapes.txt:xABC xfoo"]);
+        assert_eq!(found, indexed.grep(pattern, "/", &recursive()).unwrap());
+    }
+}
+#[test]
+fn test_grep_index_follows_writes() {
+    let mut session = grep_session(Trie::with_index());
+    session
+        .write_file("notes.txt".to_string(), "a needle here")
+        .unwrap();
+    assert_eq!(
+        session.grep("needle", "/", &recursive()).unwrap(),
+        vec!["/notes.txt:a needle here"]
+    );
+    let transfer = session.begin_upload("upload.txt".to_string()).unwrap();
+    session.write_chunk(transfer, 4, b"haystack").unwrap();
+    session.write_chunk(transfer, 0, b"hay ").unwrap();
+    session.commit_upload(transfer).unwrap();
+    assert_eq!(
+        session
+            .grep("hay haystack", "upload.txt", &GrepOptions::default())
+            .unwrap(),
+        vec!["hay haystack"]
+    );
+    session
+        .copy("notes.txt".to_string(), "copy.txt".to_string(), false)
+        .unwrap();
+    session.remove("notes.txt".to_string()).unwrap();
+    assert_eq!(
+        session.grep("needle", "/", &recursive()).unwrap(),
+        vec!["/copy.txt:a needle here"]
+    );
+}
+#[test]
+fn test_index_candidates() {
+    let mut index = TrigramIndex::default();
+    index.set(1, b"Hello World");
+    index.set(2, b"hello there");
+    let both = index.candidates(&["hello".to_string()]).unwrap();
+    assert_eq!(both, HashSet::from([1, 2]));
+    let one = index.candidates(&["world".to_string()]).unwrap();
+    assert_eq!(one, HashSet::from([1]));
+    assert_eq!(index.candidates(&[]), None);
+    index.forget(1);
+    assert!(index.candidates(&["world".to_string()]).unwrap().is_empty());
+}
+#[test]
+fn test_required_literals() {
+    let literals = |pattern| index::required_literals(pattern, false);
+    assert_eq!(literals("hello"), Some(vec!["hello".to_string()]));
+    assert_eq!(
+        literals("fn [a-z]+ main"),
+        Some(vec!["fn ".to_string(), " main".to_string()])
+    );
+    assert_eq!(literals("colou?r"), Some(vec!["colo".to_string()]));
+    assert_eq!(literals("(abc)?def"), Some(vec!["def".to_string()]));
+    assert_eq!(
+        literals("[[:alpha:]]xyz[^]q]end"),
+        Some(vec!["xyz".to_string(), "end".to_string()])
+    );
+    assert_eq!(literals("a|b"), None);
+    assert_eq!(literals("(?i)abc"), None);
+    assert_eq!(literals("\\x41BC"), None);
+    assert_eq!(literals("\\pLfoo"), None);
+    assert_eq!(
+        index::required_literals("desk", true),
+        Some(Vec::<String>::new())
+    );
+}
+#[test]
+fn test_append_file() {
+    let session = test_session();
+    let path = "Downloads/test.hello".to_string();
+    session.append_file(path.clone(), b", again").unwrap();
+    assert_eq!(session.read_file(path).unwrap(), b"hello world, again");
+    session
+        .append_file("Downloads/new.log".to_string(), b"first\n")
+        .unwrap();
+    session
+        .append_file("Downloads/new.log".to_string(), b"second\n")
+        .unwrap();
+    assert_eq!(
+        session.read_file("Downloads/new.log".to_string()).unwrap(),
+        b"first\nsecond\n"
+    );
+}
+#[test]
+fn test_append_keeps_inode() {
+    let session = test_session();
+    let path = "Downloads/test.hello".to_string();
+    let before = session.stat(path.clone()).unwrap().inode;
+    session.append_file(path.clone(), b"!").unwrap();
+    assert_eq!(session.stat(path).unwrap().inode, before);
+}
+#[test]
+fn test_write_at_offset() {
+    let session = test_session();
+    let path = "Downloads/test.hello".to_string();
+    session.write_at(path.clone(), 6, b"there").unwrap();
+    assert_eq!(session.read_file(path.clone()).unwrap(), b"hello there");
+    session.write_at(path.clone(), 13, b"!").unwrap();
+    assert_eq!(session.read_file(path).unwrap(), b"hello there\0\0!");
+}
+#[test]
+fn test_truncate() {
+    let session = test_session();
+    let path = "Downloads/test.hello".to_string();
+    session.truncate(path.clone(), 5).unwrap();
+    assert_eq!(session.read_file(path.clone()).unwrap(), b"hello");
+    session.truncate(path.clone(), 7).unwrap();
+    assert_eq!(session.read_file(path.clone()).unwrap(), b"hello\0\0");
+    session.truncate("Downloads/empty".to_string(), 0).unwrap();
+    assert_eq!(session.stat("Downloads/empty".to_string()).unwrap().size, 0);
+    let out = session.truncate(path, MAX_FILE_SIZE + 1);
+    assert!(matches!(out, Err(FsError::FileTooLarge(..))));
+}
+// A huge count reads only what fits in one reply
+#[tokio::test]
+async fn test_head_huge_count_capped() {
+    let session = test_session();
+    let big = vec![7u8; MAX_READ_LEN as usize + 10];
+    session.write_file("big.bin".to_string(), big).unwrap();
+    let read = session
+        .read_range("big.bin".to_string(), 0, u32::MAX)
+        .unwrap();
+    assert_eq!(read.len(), MAX_READ_LEN as usize);
+    let sessions: Sessions = Arc::new(DashMap::from_iter([(1, Arc::new(Mutex::new(session)))]));
+    let command = Command::from(("head", "-c", "4294967295 big.bin"));
+    let response = respond(&sessions, 1, 1, command).await.unwrap();
+    assert_eq!(response.status, Status::Ok);
+    assert!(response.to_frame(0).payload.len() <= MAX_PAYLOAD_LEN as usize);
+}
+#[test]
+fn test_truncate_directory() {
+    let session = test_session();
+    let out = session.truncate("Downloads".to_string(), 0);
+    assert!(matches!(out, Err(FsError::IsADirectory(..))));
+}
+#[test]
+fn test_insert_empty_file_replaces() {
+    let system = test_system();
+    system
+        .insert("/Downloads/test.hello", FsLike::file("root", Vec::new()))
+        .unwrap();
+    assert_eq!(system.get("/Downloads/test.hello").unwrap().size(), 0);
+}
+#[test]
+fn test_grep_index_follows_truncate() {
+    let session = grep_session(Trie::with_index());
+    session.truncate("notes.txt".to_string(), 5).unwrap();
+    session
+        .append_file("notes.txt".to_string(), b"ng new")
+        .unwrap();
+    assert_eq!(
+        session.grep("nothing new", "/", &recursive()).unwrap(),
+        vec!["/notes.txt:nothing new"]
+    );
+}
+fn mode(mode: &str) -> OpenOptions {
+    OpenOptions::from_mode(mode).unwrap()
+}
+#[test]
+fn test_handle_read_in_pieces() {
+    let mut session = test_session();
+    let fd = session
+        .open("Downloads/test.hello".to_string(), mode("r"), 1)
+        .unwrap();
+    assert_eq!(session.read_handle(fd, 5, 1).unwrap(), b"hello");
+    assert_eq!(session.read_handle(fd, 100, 1).unwrap(), b" world");
+    assert!(session.read_handle(fd, 100, 1).unwrap().is_empty());
+}
+#[test]
+fn test_handle_read_capped() {
+    let mut session = test_session();
+    let big = vec![7u8; MAX_READ_LEN as usize + 10];
+    session.write_file("big.bin".to_string(), big).unwrap();
+    let fd = session.open("big.bin".to_string(), mode("r"), 1).unwrap();
+    let read = session.read_handle(fd, u32::MAX, 1).unwrap();
+    assert_eq!(read.len(), MAX_READ_LEN as usize);
+    assert_eq!(session.read_handle(fd, u32::MAX, 1).unwrap(), [7u8; 10]);
+}
+#[test]
+fn test_handle_write_and_seek() {
+    let mut session = test_session();
+    let fd = session.open("notes".to_string(), mode("w+"), 1).unwrap();
+    session.write_handle(fd, b"hello world", 1).unwrap();
+    assert_eq!(session.seek(fd, -5, Whence::End, 1).unwrap(), 6);
+    session.write_handle(fd, b"there", 1).unwrap();
+    assert_eq!(session.seek(fd, 0, Whence::Start, 1).unwrap(), 0);
+    assert_eq!(session.read_handle(fd, 100, 1).unwrap(), b"hello there");
+    assert_eq!(session.seek(fd, 2, Whence::Current, 1).unwrap(), 13);
+    session.write_handle(fd, b"!", 1).unwrap();
+    assert_eq!(
+        session.read_file("notes".to_string()).unwrap(),
+        b"hello there\0\0!"
+    );
+}
+#[test]
+fn test_handle_seek_before_start() {
+    let mut session = test_session();
+    let fd = session
+        .open("Downloads/test.hello".to_string(), mode("r"), 1)
+        .unwrap();
+    let out = session.seek(fd, -1, Whence::Start, 1);
+    assert_eq!(out, Err(FsError::InvalidSeek(fd)));
+}
+#[test]
+fn test_handle_append_mode() {
+    let mut session = test_session();
+    let fd = session
+        .open("Downloads/test.hello".to_string(), mode("a"), 1)
+        .unwrap();
+    session.seek(fd, 0, Whence::Start, 1).unwrap();
+    session.write_handle(fd, b"!", 1).unwrap();
+    assert_eq!(
+        session
+            .read_file("Downloads/test.hello".to_string())
+            .unwrap(),
+        b"hello world!"
+    );
+}
+#[test]
+fn test_handle_respects_mode() {
+    let mut session = test_session();
+    let reader = session
+        .open("Downloads/test.hello".to_string(), mode("r"), 1)
+        .unwrap();
+    let writer = session
+        .open("Downloads/test.hello".to_string(), mode("a"), 1)
+        .unwrap();
+    let out = session.write_handle(reader, b"x", 1);
+    assert_eq!(out, Err(FsError::InvalidHandle(reader)));
+    let out = session.read_handle(writer, 1, 1);
+    assert_eq!(out, Err(FsError::InvalidHandle(writer)));
+}
+#[test]
+fn test_open_missing_and_directory() {
+    let mut session = test_session();
+    let out = session.open("missing".to_string(), mode("r"), 1);
+    assert!(matches!(out, Err(FsError::NotFound(..))));
+    let out = session.open("Downloads".to_string(), mode("r"), 1);
+    assert!(matches!(out, Err(FsError::IsADirectory(..))));
+}
+#[test]
+fn test_open_truncates() {
+    let mut session = test_session();
+    session
+        .open("Downloads/test.hello".to_string(), mode("w"), 1)
+        .unwrap();
+    assert!(session
+        .read_file("Downloads/test.hello".to_string())
+        .unwrap()
+        .is_empty());
+}
+#[test]
+fn test_handle_survives_rename() {
+    let mut session = test_session();
+    let fd = session
+        .open("Downloads/test.hello".to_string(), mode("r+"), 1)
+        .unwrap();
+    session
+        .mv(
+            "Downloads/test.hello".to_string(),
+            "Documents/renamed".to_string(),
+        )
+        .unwrap();
+    session.write_handle(fd, b"HELLO", 1).unwrap();
+    assert_eq!(
+        session.read_file("Documents/renamed".to_string()).unwrap(),
+        b"HELLO world"
+    );
+}
+#[test]
+fn test_handle_stale_after_remove() {
+    let mut session = test_session();
+    let fd = session
+        .open("Downloads/test.hello".to_string(), mode("r"), 1)
+        .unwrap();
+    session.remove("Downloads/test.hello".to_string()).unwrap();
+    assert_eq!(session.read_handle(fd, 1, 1), Err(FsError::StaleHandle(fd)));
+}
+#[test]
+fn test_handle_belongs_to_connection() {
+    let mut session = test_session();
+    let fd = session
+        .open("Downloads/test.hello".to_string(), mode("r"), 1)
+        .unwrap();
+    assert_eq!(
+        session.read_handle(fd, 1, 2),
+        Err(FsError::InvalidHandle(fd))
+    );
+    assert_eq!(session.close(fd, 2), Err(FsError::InvalidHandle(fd)));
+    session.close(fd, 1).unwrap();
+    assert_eq!(
+        session.read_handle(fd, 1, 1),
+        Err(FsError::InvalidHandle(fd))
+    );
+}
+#[test]
+fn test_close_connection() {
+    let mut session = test_session();
+    let mine = session
+        .open("Downloads/test.hello".to_string(), mode("r"), 1)
+        .unwrap();
+    let theirs = session
+        .open("Downloads/test.hello".to_string(), mode("r"), 2)
+        .unwrap();
+    session.close_connection(1);
+    assert_eq!(
+        session.read_handle(mine, 1, 1),
+        Err(FsError::InvalidHandle(mine))
+    );
+    assert_eq!(session.read_handle(theirs, 1, 2).unwrap(), b"h");
+}
+const NO_WAIT: Duration = Duration::ZERO;
+#[test]
+fn test_shared_locks_coexist() {
+    let locks = Locks::default();
+    let path = Path::new("/f");
+    locks.lock(1, LockKind::Shared, 1, NO_WAIT, path).unwrap();
+    locks.lock(1, LockKind::Shared, 2, NO_WAIT, path).unwrap();
+    let out = locks.lock(1, LockKind::Exclusive, 3, NO_WAIT, path);
+    assert_eq!(out, Err(FsError::WouldBlock(path.to_path_buf())));
+}
+#[test]
+fn test_exclusive_lock_excludes() {
+    let locks = Locks::default();
+    let path = Path::new("/f");
+    locks
+        .lock(1, LockKind::Exclusive, 1, NO_WAIT, path)
+        .unwrap();
+    // Taking it again is a no-op, other files are unaffected
+    locks
+        .lock(1, LockKind::Exclusive, 1, NO_WAIT, path)
+        .unwrap();
+    locks
+        .lock(2, LockKind::Exclusive, 2, NO_WAIT, path)
+        .unwrap();
+    assert!(locks.lock(1, LockKind::Shared, 2, NO_WAIT, path).is_err());
+    locks.unlock(1, 1);
+    locks.lock(1, LockKind::Shared, 2, NO_WAIT, path).unwrap();
+}
+#[test]
+fn test_lock_conversion() {
+    let locks = Locks::default();
+    let path = Path::new("/f");
+    locks.lock(1, LockKind::Shared, 1, NO_WAIT, path).unwrap();
+    locks
+        .lock(1, LockKind::Exclusive, 1, NO_WAIT, path)
+        .unwrap();
+    assert!(locks.lock(1, LockKind::Shared, 2, NO_WAIT, path).is_err());
+    locks.lock(1, LockKind::Shared, 1, NO_WAIT, path).unwrap();
+    locks.lock(1, LockKind::Shared, 2, NO_WAIT, path).unwrap();
+    assert!(locks
+        .lock(1, LockKind::Exclusive, 1, NO_WAIT, path)
+        .is_err());
+}
+#[test]
+fn test_lock_times_out() {
+    let locks = Locks::default();
+    let path = Path::new("/f");
+    locks
+        .lock(1, LockKind::Exclusive, 1, NO_WAIT, path)
+        .unwrap();
+    let start = std::time::Instant::now();
+    let out = locks.lock(1, LockKind::Exclusive, 2, Duration::from_millis(50), path);
+    assert_eq!(out, Err(FsError::WouldBlock(path.to_path_buf())));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+}
+#[test]
+fn test_lock_waits_for_release() {
+    let locks = Locks::default();
+    let path = Path::new("/f");
+    locks
+        .lock(1, LockKind::Exclusive, 1, NO_WAIT, path)
+        .unwrap();
+    let waiter = {
+        let locks = locks.clone();
+        std::thread::spawn(move || {
+            locks.lock(
+                1,
+                LockKind::Exclusive,
+                2,
+                Duration::from_secs(10),
+                Path::new("/f"),
+            )
+        })
+    };
+    std::thread::sleep(Duration::from_millis(20));
+    locks.release_all(1);
+    waiter.join().unwrap().unwrap();
+    assert!(locks.lock(1, LockKind::Shared, 1, NO_WAIT, path).is_err());
+}
+#[test]
+fn test_lock_deadlock_avoided() {
+    let locks = Locks::default();
+    let path = Path::new("/f");
+    locks
+        .lock(1, LockKind::Exclusive, 1, NO_WAIT, path)
+        .unwrap();
+    locks
+        .lock(2, LockKind::Exclusive, 2, NO_WAIT, path)
+        .unwrap();
+    // Connection 1 waits for file 2 while holding file 1
+    let waiter = {
+        let locks = locks.clone();
+        std::thread::spawn(move || {
+            locks.lock(
+                2,
+                LockKind::Exclusive,
+                1,
+                Duration::from_secs(10),
+                Path::new("/f"),
+            )
+        })
+    };
+    std::thread::sleep(Duration::from_millis(20));
+    let out = locks.lock(1, LockKind::Exclusive, 2, Duration::from_secs(10), path);
+    assert_eq!(out, Err(FsError::Deadlock(path.to_path_buf())));
+    locks.unlock(2, 2);
+    waiter.join().unwrap().unwrap();
+}
+#[test]
+fn test_session_locks_follow_file() {
+    let mut session = test_session();
+    let fd = session
+        .open("Downloads/test.hello".to_string(), mode("r"), 1)
+        .unwrap();
+    let by_handle = session.lockable(&LockTarget::Handle(fd), 1).unwrap();
+    session
+        .mv("Downloads/test.hello".to_string(), "moved".to_string())
+        .unwrap();
+    let by_path = session
+        .lockable(&LockTarget::Path("moved".to_string()), 1)
+        .unwrap();
+    assert_eq!(by_handle.0, by_path.0);
+    assert_eq!(
+        session.lockable(&LockTarget::Handle(fd), 2),
+        Err(FsError::InvalidHandle(fd))
+    );
+    let out = session.lockable(&LockTarget::Path("missing".to_string()), 1);
+    assert!(matches!(out, Err(FsError::NotFound(..))));
+}
+#[test]
+fn test_close_connection_releases_locks() {
+    let mut session = test_session();
+    let target = LockTarget::Path("Downloads/test.hello".to_string());
+    let (inode, path) = session.lockable(&target, 1).unwrap();
+    session
+        .locks
+        .lock(inode, LockKind::Exclusive, 1, NO_WAIT, &path)
+        .unwrap();
+    assert!(session
+        .locks
+        .lock(inode, LockKind::Shared, 2, NO_WAIT, &path)
+        .is_err());
+    session.close_connection(1);
+    session
+        .locks
+        .lock(inode, LockKind::Shared, 2, NO_WAIT, &path)
+        .unwrap();
+    session.unlock(&target, 2).unwrap();
+    session
+        .locks
+        .lock(inode, LockKind::Exclusive, 3, NO_WAIT, &path)
+        .unwrap();
+}
+#[test]
+fn test_concurrent_appends_all_land() {
+    let session = Arc::new(test_session());
+    let appenders = (0..8)
+        .map(|_| {
+            let session = session.clone();
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    session.append_file("log".to_string(), b"x").unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for appender in appenders {
+        appender.join().unwrap();
+    }
+    assert_eq!(
+        session.read_file("log".to_string()).unwrap(),
+        vec![b'x'; 800]
+    );
+}
+#[test]
+fn test_reads_and_file_writes_share_tree() {
+    let session = Arc::new(test_session());
+    // Another reader holds the tree the whole time
+    let held = session.file_system.clone();
+    let _reading = held.read().unwrap();
+    let (done, finished) = std::sync::mpsc::channel();
+    let worker = session.clone();
+    std::thread::spawn(move || {
+        worker
+            .write_file("Downloads/test.hello".to_string(), "shared")
+            .unwrap();
+        worker
+            .append_file("Downloads/test.hello".to_string(), b"!")
+            .unwrap();
+        done.send(worker.read_file("Downloads/test.hello".to_string()))
+            .unwrap();
+    });
+    let out = finished.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(out.unwrap(), b"shared!");
+}
+// Making a file shares the tree, it only waits on whoever holds the entries
+// of the directory it goes in
+#[test]
+fn test_new_file_waits_for_its_directory() {
+    let session = Arc::new(test_session());
+    let held = session.file_system.clone();
+    let reading = held.read().unwrap();
+    let turn = |path: &str| reading.resolve_id(path, false).unwrap() % ENTRY_TURNS as u64;
+    let other = ["/Documents", "/Documents/projects", "/Documents/paperwork"]
+        .into_iter()
+        .find(|path| turn(path) != turn("/Downloads"))
+        .unwrap();
+    let entries = reading.entries(&[reading.resolve_id("/Downloads", false).unwrap()]);
+    let write = |path: String| {
+        let (done, finished) = std::sync::mpsc::channel();
+        let worker = session.clone();
+        std::thread::spawn(move || done.send(worker.write_file(path, "made")).unwrap());
+        finished
+    };
+    let elsewhere = write(format!("{other}/new"));
+    let out = elsewhere.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(out, Ok(()));
+    let inside = write("/Downloads/new".to_string());
+    assert!(inside.recv_timeout(Duration::from_millis(100)).is_err());
+    drop(entries);
+    let out = inside.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(out, Ok(()));
+    drop(reading);
+    assert_eq!(
+        session.read_file("Downloads/new".to_string()).unwrap(),
+        b"made"
+    );
+}
+
+// What a run of concurrent sessions got done
+struct Throughput {
+    reads: usize,
+    writes: usize,
+    // Longest any one small write took
+    worst_write: Duration,
+    // Directories made and removed again
+    reshapes: usize,
+    worst_reshape: Duration,
+}
+
+// Readers cat a large shared file while each writer rewrites its own small
+// one and each shaper makes and removes a directory in one of its own, all
+// for a second. With serial every operation first takes that one lock, the
+// way the whole tree used to sit behind a single Mutex
+fn run_sessions(
+    readers: usize,
+    writers: usize,
+    shapers: usize,
+    serial: Option<Arc<Mutex<()>>>,
+) -> Throughput {
+    let db = Arc::new(RwLock::new(Trie::new()));
+    let mut setup = Session::new("root".to_string(), db.clone(), Locks::default());
+    setup
+        .write_file("/shared/big".to_string(), vec![b'a'; 4 * 1024 * 1024])
+        .unwrap();
+    for writer in 0..writers {
+        setup
+            .write_file(format!("/home/{}/notes", writer), "")
+            .unwrap();
+    }
+    for shaper in 0..shapers {
+        setup.make_dir(format!("/work/{}", shaper)).unwrap();
+    }
+    let until = Instant::now() + Duration::from_secs(1);
+    let reading = (0..readers)
+        .map(|_| {
+            let session = Session::new("reader".to_string(), db.clone(), Locks::default());
+            let serial = serial.clone();
+            std::thread::spawn(move || {
+                let mut reads = 0;
+                while Instant::now() < until {
+                    let _serial = serial.as_ref().map(|serial| serial.lock().unwrap());
+                    session.read_file("/shared/big".to_string()).unwrap();
+                    reads += 1;
+                }
+                reads
+            })
+        })
+        .collect::<Vec<_>>();
+    let writing = (0..writers)
+        .map(|writer| {
+            let session = Session::new("writer".to_string(), db.clone(), Locks::default());
+            let serial = serial.clone();
+            std::thread::spawn(move || {
+                let (mut writes, mut worst) = (0, Duration::ZERO);
+                while Instant::now() < until {
+                    let started = Instant::now();
+                    let _serial = serial.as_ref().map(|serial| serial.lock().unwrap());
+                    session
+                        .write_file(format!("/home/{}/notes", writer), "note")
+                        .unwrap();
+                    worst = worst.max(started.elapsed());
+                    writes += 1;
+                }
+                (writes, worst)
+            })
+        })
+        .collect::<Vec<_>>();
+    let shaping = (0..shapers)
+        .map(|shaper| {
+            let mut session = Session::new("shaper".to_string(), db.clone(), Locks::default());
+            let serial = serial.clone();
+            std::thread::spawn(move || {
+                let (mut reshapes, mut worst) = (0, Duration::ZERO);
+                while Instant::now() < until {
+                    let started = Instant::now();
+                    let _serial = serial.as_ref().map(|serial| serial.lock().unwrap());
+                    let dir = format!("/work/{}/dir", shaper);
+                    session.make_dir(dir.clone()).unwrap();
+                    session.remove(dir).unwrap();
+                    worst = worst.max(started.elapsed());
+                    reshapes += 1;
+                }
+                (reshapes, worst)
+            })
+        })
+        .collect::<Vec<_>>();
+    let reads = reading
+        .into_iter()
+        .map(|reader| reader.join().unwrap())
+        .sum();
+    let mut out = Throughput {
+        reads,
+        writes: 0,
+        worst_write: Duration::ZERO,
+        reshapes: 0,
+        worst_reshape: Duration::ZERO,
+    };
+    for writer in writing {
+        let (writes, worst) = writer.join().unwrap();
+        out.writes += writes;
+        out.worst_write = out.worst_write.max(worst);
+    }
+    for shaper in shaping {
+        let (reshapes, worst) = shaper.join().unwrap();
+        out.reshapes += reshapes;
+        out.worst_reshape = out.worst_reshape.max(worst);
+    }
+    out
+}
+
+// cargo test --release bench_concurrent_sessions -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_concurrent_sessions() {
+    // Making and removing only hold the entries of the shaper's own
+    // directory, so shapers don't wait on anyone
+    for (readers, writers, shapers) in [(4, 4, 0), (4, 4, 2), (16, 16, 0), (16, 16, 4)] {
+        let global = run_sessions(readers, writers, shapers, Some(Arc::new(Mutex::new(()))));
+        let fine = run_sessions(readers, writers, shapers, None);
+        println!(
+            "{} readers, {} writers, {} shapers",
+            readers, writers, shapers
+        );
+        for (name, run) in [("one global lock", global), ("per node locks", fine)] {
+            println!(
+                "  {:<15} {:>6} reads {:>8} writes {:>8} reshapes, slowest write {:?}, slowest reshape {:?}",
+                name, run.reads, run.writes, run.reshapes, run.worst_write, run.worst_reshape
+            );
+        }
+    }
+}
+// Requests wait for the tree off the runtime, so its one thread keeps going
+#[tokio::test]
+#[allow(clippy::await_holding_lock)]
+async fn test_requests_leave_runtime_free() {
+    let session = test_session();
+    let fs = session.file_system.clone();
+    let sessions: Sessions = Arc::new(DashMap::from_iter([(1, Arc::new(Mutex::new(session)))]));
+    let held = fs.write().unwrap();
+    let command = Command::READ("Downloads/test.hello".to_string());
+    let request = tokio::spawn(async move { respond(&sessions, 1, 1, command).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!request.is_finished());
+    drop(held);
+    let response = request.await.unwrap().unwrap();
+    assert_eq!(response.status, Status::Ok);
+    assert_eq!(response.body, Some(Body::Bytes(b"hello world".to_vec())));
+}
+// Pipelined requests on one connection run in the order they were sent
+#[tokio::test]
+async fn test_connection_runs_requests_in_order() {
+    let session = test_session();
+    let fs = session.file_system.clone();
+    let sessions: Sessions = Arc::new(DashMap::from_iter([(1, Arc::new(Mutex::new(session)))]));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        process(socket, sessions).await;
+    });
+    let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+    write_frame(&mut client, &Frame::hello(1)).await.unwrap();
+    read_frame(&mut client).await.unwrap();
+    let mut commands = Vec::new();
+    for i in 0..20 {
+        let dir = ["/Documents", "/Downloads"][i % 2];
+        commands.push(Command::CD(dir.to_string()));
+        commands.push(Command::TOUCH(format!("file{i}")));
+    }
+    for (id, command) in commands.iter().enumerate() {
+        let frame = command.to_frame().with_request_id(id as u32);
+        write_frame(&mut client, &frame).await.unwrap();
+    }
+    for id in 0..commands.len() as u32 {
+        let reply = read_frame(&mut client).await.unwrap();
+        assert_eq!(reply.request_id, id);
+    }
+    let fs = fs.read().unwrap();
+    for i in 0..20 {
+        let dir = ["/Documents", "/Downloads"][i % 2];
+        let path = PathBuf::from(format!("{dir}/file{i}"));
+        assert!(fs.get(&path).is_some(), "{}", path.display());
+    }
+}
+// A read waiting on the tree holds up neither the reads after it nor the
+// session, a change after it still waits for it
+#[tokio::test]
+#[allow(clippy::await_holding_lock)]
+async fn test_reads_overtake_waiting_read() {
+    let session = test_session();
+    let fs = session.file_system.clone();
+    let sessions: Sessions = Arc::new(DashMap::from_iter([(1, Arc::new(Mutex::new(session)))]));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        process(socket, sessions).await;
+    });
+    let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+    write_frame(&mut client, &Frame::hello(1)).await.unwrap();
+    read_frame(&mut client).await.unwrap();
+    let held = fs.write().unwrap();
+    let commands = [
+        Command::READ("Downloads/test.hello".to_string()),
+        Command::PWD,
+        Command::TOUCH("after".to_string()),
+    ];
+    for (id, command) in commands.iter().enumerate() {
+        let frame = command.to_frame().with_request_id(id as u32);
+        write_frame(&mut client, &frame).await.unwrap();
+    }
+    let reply = read_frame(&mut client).await.unwrap();
+    assert_eq!(reply.request_id, 1);
+    let waiting = tokio::time::timeout(Duration::from_millis(50), read_frame(&mut client)).await;
+    assert!(waiting.is_err());
+    drop(held);
+    let ids = [
+        read_frame(&mut client).await.unwrap().request_id,
+        read_frame(&mut client).await.unwrap().request_id,
+    ];
+    assert_eq!(ids, [0, 2]);
+}
+// A reply too big for a frame is refused without dropping the connection
+#[tokio::test]
+async fn test_oversize_reply_keeps_connection() {
+    let session = test_session();
+    let big = vec![7u8; MAX_PAYLOAD_LEN as usize + 1];
+    session.write_file("big.bin".to_string(), big).unwrap();
+    let sessions: Sessions = Arc::new(DashMap::from_iter([(1, Arc::new(Mutex::new(session)))]));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        process(socket, sessions).await;
+    });
+    let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+    write_frame(&mut client, &Frame::hello(1)).await.unwrap();
+    read_frame(&mut client).await.unwrap();
+    let read = Command::READ("big.bin".to_string()).to_frame();
+    write_frame(&mut client, &read.with_request_id(1))
+        .await
+        .unwrap();
+    let reply = read_frame(&mut client).await.unwrap();
+    assert_eq!(reply.request_id, 1);
+    let response = Response::from_frame(&reply).unwrap();
+    assert_eq!(response.status, Status::TooLarge);
+    write_frame(&mut client, &Command::PWD.to_frame().with_request_id(2))
+        .await
+        .unwrap();
+    let reply = read_frame(&mut client).await.unwrap();
+    assert_eq!(Response::from_frame(&reply).unwrap().status, Status::Ok);
+}
+#[test]
+fn test_unknown_opcode_is_unsupported() {
+    let command = Command::from_frame(&Frame::new(0xE0, Vec::new())).unwrap();
+    let response = execute(&mut test_session(), command, 1);
+    assert_eq!(response.status, Status::Unsupported);
+}
+// A panic is answered, everything it held is usable again after
+#[tokio::test]
+async fn test_panicked_command_is_answered() {
+    let session = Arc::new(Mutex::new(test_session()));
+    let fs = session.lock().unwrap().file_system.clone();
+    let out = blocking(session.clone(), |session| {
+        let session = session.lock().unwrap();
+        let _tree = session.file_system.write().unwrap();
+        panic!("failing on purpose")
+    })
+    .await;
+    assert_eq!(out.unwrap_err().status, Status::Internal);
+    assert!(!fs.is_poisoned());
+    assert!(!session.is_poisoned());
+    let sessions: Sessions = Arc::new(DashMap::from_iter([(1, session)]));
+    let command = Command::READ("Downloads/test.hello".to_string());
+    let response = respond(&sessions, 1, 1, command).await.unwrap();
+    assert_eq!(response.body, Some(Body::Bytes(b"hello world".to_vec())));
+}
+#[test]
+fn test_poisoned_tree_still_usable() {
+    let session = test_session();
+    let fs = session.file_system.clone();
+    let _ = std::thread::spawn(move || {
+        let _tree = fs.write().unwrap();
+        panic!("failing on purpose")
+    })
+    .join();
+    assert!(session.file_system.is_poisoned());
+    session
+        .write_file("Downloads/test.hello".to_string(), "still here")
+        .unwrap();
+    let out = session.read_file("Downloads/test.hello".to_string());
+    assert_eq!(out.unwrap(), b"still here");
+}
+#[test]
+fn test_repair_leaves_good_tree() {
+    let mut session = test_session();
+    session
+        .link("Downloads/test.hello".to_string(), "hard".to_string())
+        .unwrap();
+    session
+        .copy("Documents".to_string(), "copied".to_string(), true)
+        .unwrap();
+    session
+        .mv(
+            "Downloads".to_string(),
+            "Documents/projects/moved".to_string(),
+        )
+        .unwrap();
+    session.remove("hard".to_string()).unwrap();
+    let problems = session.file_system.write().unwrap().repair();
+    assert!(problems.is_empty(), "{:?}", problems);
+}
+#[test]
+fn test_repair_fixes_half_done_changes() {
+    let mut fs = test_system();
+    fs.insert("/notes", FsLike::file("root", "")).unwrap();
+    let root = fs.resolve_id("/", true).unwrap();
+    let downloads = fs.resolve_id("/Downloads", true).unwrap();
+    let documents = fs.resolve_id("/Documents", true).unwrap();
+    let file = fs.resolve_id("/Downloads/test.hello", true).unwrap();
+    let notes = fs.resolve_id("/notes", true).unwrap();
+    // What a panic between the steps of a few changes could leave
+    if let Some(FsLike::DirectoryLike { children, .. }) = fs.node_mut(downloads).as_deref_mut() {
+        let children = Arc::make_mut(children);
+        children.remove(Path::new("test.hello"));
+        children.insert("gone".into(), u64::MAX);
+    }
+    if let Some(FsLike::DirectoryLike { children, .. }) = fs.node_mut(root).as_deref_mut() {
+        Arc::make_mut(children).insert("again".into(), documents);
+    }
+    fs.node_mut(notes).unwrap().meta_mut().links = 3;
+    assert_eq!(fs.repair().len(), 4);
+    assert!(fs.get_link("/Downloads/gone").is_none());
+    assert!(fs.get_link("/again").is_none());
+    assert!(fs.get("/Documents/projects").is_some());
+    assert!(fs.node(file).is_none());
+    assert_eq!(fs.get("/notes").unwrap().meta().links, 1);
+    assert!(fs.repair().is_empty())
+}
+// A tree with a bit of everything a snapshot has to keep
+fn snapshot_session() -> Session {
+    let mut session = test_session();
+    session
+        .write_file("bin/blob".to_string(), vec![0, 159, 146, 150, 255])
+        .unwrap();
+    session
+        .link("Downloads/test.hello".to_string(), "hard".to_string())
+        .unwrap();
+    session
+        .symlink("Downloads/test.hello".to_string(), "soft".to_string())
+        .unwrap();
+    session
+}
+fn everything(session: &Session) -> Vec<Stat> {
+    let options = ListOptions {
+        all: true,
+        recursive: true,
+        ..ListOptions::default()
+    };
+    session.list("/", &options).unwrap()
+}
+fn from_snapshot(system: Trie) -> Session {
+    Session::new(
+        "TestUser".to_string(),
+        Arc::new(RwLock::new(system)),
+        Locks::default(),
+    )
+}
+#[test]
+fn test_snapshot_round_trip() {
+    let session = snapshot_session();
+    let checkpoint = Checkpoint {
+        generation: 7,
+        end: 40,
+    };
+    let bytes = snapshot::encode(&session.file_system.read().unwrap(), checkpoint);
+    let (system, decoded) = snapshot::decode(&bytes, false).unwrap();
+    assert_eq!(decoded, checkpoint);
+    let loaded = from_snapshot(system);
+    // Same inodes, link counts, owners and times
+    assert_eq!(everything(&loaded), everything(&session));
+    let out = loaded.read_file("bin/blob".to_string()).unwrap();
+    assert_eq!(out, vec![0, 159, 146, 150, 255]);
+    assert_eq!(
+        loaded.read_link("soft".to_string()).unwrap(),
+        "Downloads/test.hello"
+    );
+    loaded
+        .write_file("hard".to_string(), "through the link")
+        .unwrap();
+    let out = loaded.read_file("Downloads/test.hello".to_string());
+    assert_eq!(out.unwrap(), b"through the link");
+}
+#[test]
+fn test_snapshot_rejects_damage() {
+    let session = snapshot_session();
+    let bytes = snapshot::encode(&session.file_system.read().unwrap(), Checkpoint::whole(0));
+    assert!(snapshot::decode(&bytes[..bytes.len() - 1], false).is_err());
+    assert!(snapshot::decode(&[bytes.as_slice(), &[0]].concat(), false).is_err());
+    assert!(snapshot::decode(b"EPHI", false).is_err());
+    let mut newer = bytes.clone();
+    newer[4] += 1;
+    assert!(snapshot::decode(&newer, false).is_err());
+}
+// Only changes make the tree worth saving again, reading it doesn't
+#[test]
+fn test_reads_are_not_changes() {
+    let session = test_session();
+    let before = session.file_system.read().unwrap().changes();
+    session
+        .read_file("Downloads/test.hello".to_string())
+        .unwrap();
+    session
+        .read_range("Downloads/test.hello".to_string(), 0, 5)
+        .unwrap();
+    assert_eq!(session.file_system.read().unwrap().changes(), before);
+    session
+        .write_file("Downloads/test.hello".to_string(), "")
+        .unwrap();
+    assert!(session.file_system.read().unwrap().changes() > before);
+}
+#[test]
+fn test_snapshot_save_and_load() {
+    let path = std::env::temp_dir().join(format!("ephie-{}.snap", std::process::id()));
+    let session = snapshot_session();
+    let bytes = snapshot::encode(&session.file_system.read().unwrap(), Checkpoint::whole(0));
+    snapshot::write(&path, &bytes).unwrap();
+    // Only the finished snapshot is left behind
+    assert!(!path.with_extension("snap.tmp").exists());
+    let loaded = from_snapshot(snapshot::load(&path, false).unwrap().0);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(everything(&loaded), everything(&session));
+    // Nothing made after loading takes a loaded inode number
+    loaded.write_file("new".to_string(), "").unwrap();
+    let new = loaded.stat("new".to_string()).unwrap().inode;
+    assert!(everything(&session).iter().all(|stat| stat.inode != new));
+}
+#[test]
+fn test_snapshot_rebuilds_index() {
+    let session = grep_session(Trie::new());
+    let bytes = snapshot::encode(&session.file_system.read().unwrap(), Checkpoint::whole(0));
+    let loaded = from_snapshot(snapshot::decode(&bytes, true).unwrap().0);
+    assert!(loaded.file_system.read().unwrap().index().is_some());
+    let out = loaded.grep("helper", "src", &recursive()).unwrap();
+    assert_eq!(out, vec!["src/lib.rs:pub fn helper() {}"]);
+}
+fn temp_log(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ephie-{}-{name}.wal", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+fn record(command: Command) -> Record {
+    Record {
+        user: "TestUser".to_string(),
+        dir: PathBuf::from("/Documents"),
+        command,
+    }
+}
+fn logged(n: u8) -> Vec<Record> {
+    (0..n)
+        .map(|i| {
+            record(Command::WRITE {
+                path: format!("file{i}"),
+                data: vec![i; 16],
+            })
+        })
+        .collect()
+}
+#[test]
+fn test_wal_round_trip() {
+    let path = temp_log("round-trip");
+    let (mut wal, records) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    assert!(records.is_empty());
+    for record in logged(3) {
+        wal.append(&[record]).unwrap();
+    }
+    let (_, records) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(records, logged(3));
+}
+#[test]
+fn test_wal_cuts_torn_tail() {
+    let path = temp_log("torn");
+    let (mut wal, _) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    for record in logged(3) {
+        wal.append(&[record]).unwrap();
+    }
+    // A crash part way through the last record
+    let whole = std::fs::metadata(&path).unwrap().len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(whole - 5)
+        .unwrap();
+    let (mut wal, records) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    assert_eq!(records, logged(2));
+    // What comes next follows the last whole record
+    wal.append(&logged(3)[2..]).unwrap();
+    let (_, records) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    assert_eq!(records, logged(3));
+    // One that doesn't match its checksum is dropped too
+    let mut bytes = std::fs::read(&path).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    std::fs::write(&path, bytes).unwrap();
+    let (_, records) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(records, logged(2));
+}
+// A record that checks out but can't be read isn't a torn write, nothing
+// after it is dropped
+#[test]
+fn test_wal_keeps_unreadable_record() {
+    let path = temp_log("unreadable");
+    let (mut wal, _) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    wal.append(&logged(1)).unwrap();
+    let payload = vec![0xff; 3];
+    let mut bytes = std::fs::read(&path).unwrap();
+    put_u32(&mut bytes, payload.len() as u32);
+    put_u32(&mut bytes, wal::crc32(&payload));
+    bytes.extend_from_slice(&payload);
+    std::fs::write(&path, &bytes).unwrap();
+    let out = Wal::open(&path, Checkpoint::whole(0)).map(|_| ());
+    assert_eq!(out.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+    std::fs::remove_file(&path).unwrap();
+}
+#[test]
+fn test_wal_generations() {
+    let path = temp_log("generations");
+    let (mut wal, _) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    wal.append(&logged(1)).unwrap();
+    // A snapshot newer than the log already has what's in it
+    let (wal, records) = Wal::open(&path, Checkpoint::whole(1)).unwrap();
+    assert!(records.is_empty());
+    assert_eq!(wal.generation(), 1);
+    // A log newer than the snapshot means changes are missing from it
+    assert!(Wal::open(&path, Checkpoint::whole(0)).is_err());
+    std::fs::write(&path, b"EPHS").unwrap();
+    assert!(Wal::open(&path, Checkpoint::whole(1)).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+#[test]
+fn test_crc32() {
+    assert_eq!(wal::crc32(b""), 0);
+    assert_eq!(wal::crc32(b"123456789"), 0xCBF4_3926);
+}
+// Names, kinds, sizes and contents, what a replay has to bring back.
+// Inode numbers and times are new
+fn contents(session: &Session) -> Vec<(String, NodeKind, u64, Vec<u8>)> {
+    let mut out = everything(session)
+        .into_iter()
+        .map(|stat| {
+            let path = format!("/{}", stat.name);
+            let data = match stat.kind {
+                NodeKind::File => session.read_file(path).unwrap(),
+                _ => Vec::new(),
+            };
+            (stat.name, stat.kind, stat.size, data)
+        })
+        .collect::<Vec<_>>();
+    out.sort_by(|a, b| a.0.cmp(&b.0));
+    out
+}
+#[test]
+fn test_journaled_changes_replay() {
+    let path = temp_log("replay");
+    let (wal, _) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    let mut session = snapshot_session();
+    let base = snapshot::encode(&session.file_system.read().unwrap(), Checkpoint::whole(0));
+    session.journal = Some(Arc::new(Log::new(wal)));
+    let mut handle = 0;
+    let mut transfer = 0;
+    let commands = [
+        Command::MKDIR("logs".to_string()),
+        Command::CD("logs".to_string()),
+        Command::WRITE {
+            path: "today".to_string(),
+            data: b"first".to_vec(),
+        },
+        Command::APPEND {
+            path: "today".to_string(),
+            data: b" second".to_vec(),
+        },
+        Command::OPEN {
+            path: "open".to_string(),
+            options: mode("w+"),
+        },
+        Command::MV {
+            src: "open".to_string(),
+            dst: "/moved".to_string(),
+        },
+        Command::FDWRITE {
+            handle: 1,
+            data: b"through a handle".to_vec(),
+        },
+        Command::UPLOAD("upload".to_string()),
+        Command::CHUNK {
+            transfer: 1,
+            offset: 5,
+            data: b"world".to_vec(),
+        },
+        Command::CHUNK {
+            transfer: 1,
+            offset: 0,
+            data: b"hello".to_vec(),
+        },
+        Command::COMMIT(1),
+        Command::CP {
+            src: "/Downloads".to_string(),
+            dst: "copy".to_string(),
+            recursive: true,
+        },
+        Command::RM("/Downloads/test.hello".to_string()),
+        Command::TOUCH("/Documents/new".to_string()),
+        // Fails the same way on replay
+        Command::MKDIR("/hard/nope".to_string()),
+        Command::READ("today".to_string()),
+    ];
+    for command in commands {
+        let response = execute(&mut session, command, 1);
+        match response.body {
+            Some(Body::Handle(1)) if handle == 0 => handle = 1,
+            Some(Body::Handle(1)) => transfer = 1,
+            _ => {}
+        }
+    }
+    assert_eq!((handle, transfer), (1, 1));
+    let (_, records) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    // Reads aren't logged, nor are commits and moving around
+    assert_eq!(records.len(), 13);
+    let (system, _) = snapshot::decode(&base, false).unwrap();
+    let replayed = from_snapshot(system);
+    replay(&replayed.file_system, records);
+    assert_eq!(contents(&replayed), contents(&session));
+    let out = replayed.read_file("/moved".to_string()).unwrap();
+    assert_eq!(out, b"through a handle");
+    let out = replayed.read_file("/logs/upload".to_string()).unwrap();
+    assert_eq!(out, b"helloworld");
+}
+// Shape changes sharing the tree race each other and file writes over the
+// same few names, the log still has them in the order they were made
+#[test]
+fn test_concurrent_changes_replay() {
+    let path = temp_log("concurrent");
+    let (wal, _) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    let session = snapshot_session();
+    let base = snapshot::encode(&session.file_system.read().unwrap(), Checkpoint::whole(0));
+    let journal = Arc::new(Log::new(wal));
+    let workers = (0..4u64)
+        .map(|worker| {
+            let mut session = Session::new(
+                format!("worker{worker}"),
+                session.file_system.clone(),
+                Locks::default(),
+            );
+            session.journal = Some(journal.clone());
+            std::thread::spawn(move || {
+                let mut seed = worker + 1;
+                for _ in 0..300 {
+                    seed = seed
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    let name = |shift: u64| format!("/Documents/{}", (seed >> shift) % 3);
+                    let command = match (seed >> 40) % 8 {
+                        0 => Command::MKDIR(name(20)),
+                        1 => Command::RM(name(20)),
+                        2 => Command::TOUCH(name(20)),
+                        3 => Command::WRITE {
+                            path: name(20),
+                            data: seed.to_le_bytes().to_vec(),
+                        },
+                        4 => Command::APPEND {
+                            path: name(20),
+                            data: vec![worker as u8],
+                        },
+                        5 => Command::CP {
+                            src: name(20),
+                            dst: name(30),
+                            recursive: false,
+                        },
+                        6 => Command::LINK {
+                            target: name(20),
+                            link: name(30),
+                        },
+                        _ => Command::SYMLINK {
+                            target: name(20),
+                            link: name(30),
+                        },
+                    };
+                    execute(&mut session, command, 1);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    let (_, records) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let (system, _) = snapshot::decode(&base, false).unwrap();
+    let replayed = from_snapshot(system);
+    replay(&replayed.file_system, records);
+    assert_eq!(contents(&replayed), contents(&session));
+}
+#[test]
+fn test_save_compacts_log() {
+    let path = std::env::temp_dir().join(format!("ephie-{}-compact.snap", std::process::id()));
+    let log = wal::path_for(&path);
+    let (wal, _) = Wal::open(&log, Checkpoint::whole(0)).unwrap();
+    let journal = Arc::new(Log::new(wal));
+    let mut session = snapshot_session();
+    session.journal = Some(journal.clone());
+    execute(&mut session, Command::MKDIR("logged".to_string()), 1);
+    assert!(save(&session.file_system, &journal, &path, None).is_some());
+    assert_eq!(journal.wal().generation(), 1);
+    // Logged after the snapshot, so only this is replayed
+    execute(&mut session, Command::MKDIR("after".to_string()), 1);
+    let (system, checkpoint) = snapshot::load(&path, false).unwrap();
+    let (_, records) = Wal::open(&log, checkpoint).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&log).unwrap();
+    assert_eq!(checkpoint.generation, 1);
+    let after = Record {
+        dir: PathBuf::from("/"),
+        ..record(Command::MKDIR("after".to_string()))
+    };
+    assert_eq!(records, vec![after]);
+    let loaded = from_snapshot(system);
+    replay(&loaded.file_system, records);
+    assert_eq!(contents(&loaded), contents(&session));
+}
+// Changes logged while a save writes its copy come after its checkpoint,
+// they're kept when the log starts over and replayed if it never does
+#[test]
+fn test_save_keeps_changes_logged_meanwhile() {
+    let path = temp_log("meanwhile");
+    let (mut wal, _) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    let before = record(Command::MKDIR("before".to_string()));
+    let during = vec![record(Command::MKDIR("during".to_string()))];
+    wal.append(&[before]).unwrap();
+    let checkpoint = Checkpoint {
+        generation: 1,
+        end: wal.end(),
+    };
+    wal.append(&during).unwrap();
+    // Crashed before the log started over
+    let (_, records) = Wal::open(&path, checkpoint).unwrap();
+    assert_eq!(records, during);
+    let (mut wal, records) = Wal::open(&path, checkpoint).unwrap();
+    assert_eq!(records, during);
+    assert_eq!(wal.generation(), 1);
+    // Or it did
+    let checkpoint = Checkpoint {
+        generation: 2,
+        end: wal.end(),
+    };
+    let after = vec![record(Command::MKDIR("after".to_string()))];
+    wal.append(&after).unwrap();
+    wal.compact(checkpoint).unwrap();
+    let (_, records) = Wal::open(&path, checkpoint).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(records, after);
+}
+#[test]
+fn test_find_delete_logs_what_it_removed() {
+    let path = temp_log("find-delete");
+    let (wal, _) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    let mut session = snapshot_session();
+    let base = snapshot::encode(&session.file_system.read().unwrap(), Checkpoint::whole(0));
+    session.journal = Some(Arc::new(Log::new(wal)));
+    execute(&mut session, Command::MKDIR("/old".to_string()), 1);
+    execute(&mut session, Command::MKDIR("/old/keep".to_string()), 1);
+    for name in ["/old/a*", "/old/aX", "/old/keep/[x]"] {
+        session.write_file(name.to_string(), "stale").unwrap();
+    }
+    let query = FindQuery {
+        kind: Some(NodeKind::File),
+        mtime: Some(Compare::Below(1)),
+        delete: true,
+        ..FindQuery::default()
+    };
+    let find = Command::FIND {
+        root: "/old".to_string(),
+        query,
+    };
+    assert_eq!(execute(&mut session, find, 1).status, Status::Ok);
+    // Matched by the query too, were it run again
+    session.write_file("/old/late".to_string(), "new").unwrap();
+    let (_, records) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let removed = records[5..8]
+        .iter()
+        .map(|record| record.command.clone())
+        .collect::<Vec<_>>();
+    let expected =
+        ["/old/keep/[[]x]", "/old/aX", "/old/a[*]"].map(|path| Command::RM(path.to_string()));
+    assert_eq!(removed, expected);
+    let (system, _) = snapshot::decode(&base, false).unwrap();
+    let replayed = from_snapshot(system);
+    replay(&replayed.file_system, records);
+    assert_eq!(contents(&replayed), contents(&session));
+    assert_eq!(replayed.read_file("/old/late".to_string()).unwrap(), b"new");
+}
+#[test]
+fn test_unlogged_change_not_made() {
+    let dir = std::env::temp_dir().join(format!("ephie-{}-unlogged", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (mut wal, _) = Wal::open(&dir.join("log.wal"), Checkpoint::whole(0)).unwrap();
+    // The log can't be started over without its directory, and takes
+    // nothing more once it wasn't
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(wal.compact(Checkpoint::whole(1)).is_err());
+    let mut session = test_session();
+    session.journal = Some(Arc::new(Log::new(wal)));
+    let before = contents(&session);
+    let commands = [
+        Command::MKDIR("new".to_string()),
+        Command::WRITE {
+            path: "Downloads/test.hello".to_string(),
+            data: b"changed".to_vec(),
+        },
+        Command::RM("Downloads/test.hello".to_string()),
+        Command::FIND {
+            root: "Downloads".to_string(),
+            query: FindQuery {
+                delete: true,
+                ..FindQuery::default()
+            },
+        },
+    ];
+    for command in commands {
+        assert_eq!(execute(&mut session, command, 1).status, Status::Internal);
+    }
+    assert_eq!(contents(&session), before);
+}
+fn snapshot_command(action: SnapshotAction, name: &str) -> Command {
+    Command::SNAPSHOT {
+        action,
+        name: name.to_string(),
+    }
+}
+// The contents of the file at path in the named snapshot, or the tree
+fn data_of(session: &Session, snapshot: Option<&str>, path: &str) -> Arc<Vec<u8>> {
+    let fs = session.file_system.read().unwrap();
+    let tree = match snapshot {
+        Some(name) => fs.snapshots().find(|(found, _)| *found == name).unwrap().1,
+        None => &*fs,
+    };
+    let data = match tree.get(Path::new(path)).as_deref() {
+        Some(FsLike::FileLike { data, .. }) => data.clone(),
+        _ => panic!("{path} is not a file"),
+    };
+    data
+}
+#[test]
+fn test_snapshot_create_list_delete() {
+    let mut session = test_session();
+    session.create_snapshot("one").unwrap();
+    session.create_snapshot("two").unwrap();
+    assert_eq!(session.list_snapshots(), vec!["one", "two"]);
+    let out = session.create_snapshot("one");
+    assert_eq!(
+        out,
+        Err(FsError::AlreadyExists(PathBuf::from("/.snapshots/one")))
+    );
+    for name in ["", "..", "a/b"] {
+        let out = session.create_snapshot(name);
+        assert!(matches!(out, Err(FsError::InvalidPath(..))), "{name}");
+    }
+    session.change_dir("/.snapshots".to_string()).unwrap();
+    assert_eq!(names(&session), HashSet::from(["one".into(), "two".into()]));
+    session.delete_snapshot("one").unwrap();
+    assert_eq!(session.list_snapshots(), vec!["two"]);
+    assert_eq!(names(&session), HashSet::from(["two".into()]));
+    let out = session.delete_snapshot("one");
+    assert_eq!(
+        out,
+        Err(FsError::NotFound(PathBuf::from("/.snapshots/one")))
+    );
+}
+#[test]
+fn test_snapshot_is_read_only_view() {
+    let mut session = grep_session(Trie::new());
+    session.create_snapshot("before").unwrap();
+    session
+        .write_file("src/lib.rs".to_string(), "changed")
+        .unwrap();
+    session.remove("notes.txt".to_string()).unwrap();
+    let out = session.read_file("/.snapshots/before/src/deep/util.rs".to_string());
+    assert_eq!(out.unwrap(), b"fn util() {}\n");
+    let out = session.read_range("/.snapshots/before/notes.txt".to_string(), 0, 7);
+    assert_eq!(out.unwrap(), b"nothing");
+    // Other snapshots aren't inside it
+    session
+        .change_dir("/.snapshots/before".to_string())
+        .unwrap();
+    assert_eq!(
+        names(&session),
+        HashSet::from(["notes.txt".into(), "src".into()])
+    );
+    let query = FindQuery {
+        name: Some("*.rs".to_string()),
+        ..FindQuery::default()
+    };
+    let out = session.find("src", &query).unwrap();
+    assert_eq!(
+        out,
+        vec![
+            "/.snapshots/before/src/deep/util.rs",
+            "/.snapshots/before/src/lib.rs",
+            "/.snapshots/before/src/main.rs"
+        ]
+    );
+    let out = session.grep("helper", "src", &recursive()).unwrap();
+    assert_eq!(out, vec!["src/lib.rs:pub fn helper() {}"]);
+    let fd = session.open("notes.txt".to_string(), mode("r"), 1).unwrap();
+    assert_eq!(
+        session.read_handle(fd, 100, 1).unwrap(),
+        b"nothing to see\n"
+    );
+    // Copying out is how a file comes back
+    session
+        .copy("notes.txt".to_string(), "/notes.txt".to_string(), false)
+        .unwrap();
+    let out = session.read_file("/notes.txt".to_string());
+    assert_eq!(out.unwrap(), b"nothing to see\n");
+    let read_only = |out: Result<(), FsError>| matches!(out, Err(FsError::ReadOnly(..)));
+    assert!(read_only(session.write_file("notes.txt".to_string(), "")));
+    assert!(read_only(session.append_file("new".to_string(), b"")));
+    assert!(read_only(session.make_dir("dir".to_string())));
+    assert!(read_only(session.touch("notes.txt".to_string())));
+    assert!(read_only(session.remove("src".to_string())));
+    assert!(read_only(session.remove("/.snapshots/before".to_string())));
+    assert!(read_only(
+        session.mv("notes.txt".to_string(), "/out".to_string())
+    ));
+    assert!(read_only(
+        session.mv("/notes.txt".to_string(), "in".to_string())
+    ));
+    assert!(read_only(session.copy(
+        "/notes.txt".to_string(),
+        "in".to_string(),
+        false
+    )));
+    assert!(read_only(
+        session
+            .open("notes.txt".to_string(), mode("r+"), 1)
+            .map(|_| ())
+    ));
+    assert!(read_only(
+        session.lockable(&LockTarget::Handle(fd), 1).map(|_| ())
+    ));
+    let out = session.read_file("/.snapshots/before/notes.txt".to_string());
+    assert_eq!(out.unwrap(), b"nothing to see\n");
+}
+#[test]
+fn test_snapshot_shares_contents() {
+    let mut session = snapshot_session();
+    session.create_snapshot("shared").unwrap();
+    let path = "/.snapshots/shared/Downloads/test.hello";
+    let kept = data_of(&session, Some("shared"), path);
+    assert!(Arc::ptr_eq(
+        &kept,
+        &data_of(&session, None, "/Downloads/test.hello")
+    ));
+    session.append_file("hard".to_string(), b", again").unwrap();
+    let changed = data_of(&session, None, "/Downloads/test.hello");
+    assert!(!Arc::ptr_eq(&kept, &changed));
+    assert_eq!(*data_of(&session, Some("shared"), path), b"hello world");
+    let blob = data_of(&session, None, "/bin/blob");
+    let path = "/.snapshots/shared/bin/blob";
+    assert!(Arc::ptr_eq(&blob, &data_of(&session, Some("shared"), path)));
+}
+// Taking a snapshot copies no nodes, each is copied out once it's written,
+// whichever way that happens
+#[test]
+fn test_snapshot_shares_nodes() {
+    let mut session = snapshot_session();
+    let fd = session
+        .open("Downloads/test.hello".to_string(), mode("r+"), 1)
+        .unwrap();
+    let transfer = session.begin_upload("upload".to_string()).unwrap();
+    session.write_file("cut".to_string(), "to be cut").unwrap();
+    session.create_snapshot("before").unwrap();
+    let in_snapshot = |session: &Session| {
+        contents(session)
+            .into_iter()
+            .filter(|(name, ..)| name.starts_with(".snapshots"))
+            .collect::<Vec<_>>()
+    };
+    let kept = in_snapshot(&session);
+    let touched = session.stat("/.snapshots/before/bin/blob".to_string());
+    let paths = [
+        "/Downloads/test.hello",
+        "/upload",
+        "/cut",
+        "/bin/blob",
+        "/Documents",
+    ];
+    let shared = |session: &Session| {
+        let fs = session.file_system.read().unwrap();
+        paths.map(|path| fs.shared(fs.resolve_id(path, true).unwrap()))
+    };
+    assert_eq!(shared(&session), [true; 5]);
+    session.write_handle(fd, b"J", 1).unwrap();
+    session.write_chunk(transfer, 0, b"chunk").unwrap();
+    let truncate = OpenOptions {
+        write: true,
+        truncate: true,
+        ..OpenOptions::default()
+    };
+    session.open("cut".to_string(), truncate, 1).unwrap();
+    session.touch("bin/blob".to_string()).unwrap();
+    assert_eq!(shared(&session), [false, false, false, false, true]);
+    assert_eq!(in_snapshot(&session), kept);
+    let out = session.stat("/.snapshots/before/bin/blob".to_string());
+    assert_eq!(out.unwrap().modified, touched.unwrap().modified);
+    let out = session.read_file("Downloads/test.hello".to_string());
+    assert_eq!(out.unwrap(), b"Jello world");
+    // Written nodes are the tree's own, so only the first write copies
+    session.write_handle(fd, b"j", 1).unwrap();
+    assert_eq!(in_snapshot(&session), kept);
+    session.restore_snapshot("before").unwrap();
+    assert_eq!(shared(&session), [true; 5]);
+    let out = session.read_file("Downloads/test.hello".to_string());
+    assert_eq!(out.unwrap(), b"hello world");
+}
+// A save encodes a copy taken under a short hold of the tree, changes made
+// while it's written don't reach it
+#[test]
+fn test_save_copy_unchanged() {
+    let mut session = snapshot_session();
+    let copy = session.file_system.write().unwrap().share();
+    let saved = snapshot::encode(&copy, Checkpoint::whole(0));
+    session
+        .write_file("Downloads/test.hello".to_string(), "changed")
+        .unwrap();
+    session.write_file("new".to_string(), "made").unwrap();
+    session.remove("bin/blob".to_string()).unwrap();
+    assert_eq!(snapshot::encode(&copy, Checkpoint::whole(0)), saved);
+    let out = session.read_file("Downloads/test.hello".to_string());
+    assert_eq!(out.unwrap(), b"changed");
+}
+#[test]
+fn test_snapshot_restore() {
+    let mut session = snapshot_session();
+    session.create_snapshot("good").unwrap();
+    let outside = |session: &Session| {
+        contents(session)
+            .into_iter()
+            .filter(|(name, ..)| !name.starts_with(".snapshots"))
+            .collect::<Vec<_>>()
+    };
+    let before = outside(&session);
+    session
+        .write_file("Downloads/test.hello".to_string(), "oops")
+        .unwrap();
+    session.remove("bin".to_string()).unwrap();
+    session.make_dir("extra".to_string()).unwrap();
+    session.create_snapshot("bad").unwrap();
+    session.restore_snapshot("good").unwrap();
+    // Every snapshot stays, the later one too
+    assert_eq!(session.list_snapshots(), vec!["bad", "good"]);
+    assert_eq!(outside(&session), before);
+    // Still a hard link, and the snapshot isn't touched by writes after
+    session.write_file("hard".to_string(), "after").unwrap();
+    let out = session.read_file("Downloads/test.hello".to_string());
+    assert_eq!(out.unwrap(), b"after");
+    let out = session.read_file("/.snapshots/good/hard".to_string());
+    assert_eq!(out.unwrap(), b"hello world");
+    let out = session.read_file("/.snapshots/bad/Downloads/test.hello".to_string());
+    assert_eq!(out.unwrap(), b"oops");
+    let out = session.restore_snapshot("missing");
+    assert!(matches!(out, Err(FsError::NotFound(..))));
+}
+#[test]
+fn test_snapshots_saved_with_tree() {
+    let mut session = snapshot_session();
+    session.create_snapshot("kept").unwrap();
+    session
+        .write_file("bin/blob".to_string(), "changed")
+        .unwrap();
+    let bytes = snapshot::encode(&session.file_system.read().unwrap(), Checkpoint::whole(0));
+    let loaded = from_snapshot(snapshot::decode(&bytes, false).unwrap().0);
+    assert_eq!(loaded.list_snapshots(), vec!["kept"]);
+    assert_eq!(contents(&loaded), contents(&session));
+    let out = loaded.read_file("/.snapshots/kept/bin/blob".to_string());
+    assert_eq!(out.unwrap(), vec![0, 159, 146, 150, 255]);
+    // Data the snapshot shares with the tree is loaded once
+    let path = "/.snapshots/kept/Downloads/test.hello";
+    let kept = data_of(&loaded, Some("kept"), path);
+    assert!(Arc::ptr_eq(
+        &kept,
+        &data_of(&loaded, None, "/Downloads/test.hello")
+    ));
+    let smaller = {
+        let mut session = snapshot_session();
+        session.create_snapshot("kept").unwrap();
+        session
+            .write_file("big".to_string(), vec![7; 4096])
+            .unwrap();
+        let without =
+            snapshot::encode(&session.file_system.read().unwrap(), Checkpoint::whole(0)).len();
+        session.create_snapshot("again").unwrap();
+        let with =
+            snapshot::encode(&session.file_system.read().unwrap(), Checkpoint::whole(0)).len();
+        with - without < 4096
+    };
+    assert!(smaller);
+}
+#[test]
+fn test_snapshot_commands_replay() {
+    let path = temp_log("snapshots");
+    let (wal, _) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    let mut session = snapshot_session();
+    let base = snapshot::encode(&session.file_system.read().unwrap(), Checkpoint::whole(0));
+    session.journal = Some(Arc::new(Log::new(wal)));
+    let commands = [
+        snapshot_command(SnapshotAction::Create, "first"),
+        Command::WRITE {
+            path: "bin/blob".to_string(),
+            data: b"second".to_vec(),
+        },
+        snapshot_command(SnapshotAction::Create, "second"),
+        snapshot_command(SnapshotAction::List, ""),
+        snapshot_command(SnapshotAction::Restore, "first"),
+        snapshot_command(SnapshotAction::Delete, "first"),
+    ];
+    for command in commands {
+        let response = execute(&mut session, command, 1);
+        assert_eq!(response.status, Status::Ok);
+        if let Some(Body::Listing(names)) = response.body {
+            assert_eq!(names, vec!["first", "second"]);
+        }
+    }
+    let (_, records) = Wal::open(&path, Checkpoint::whole(0)).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(records.len(), 5);
+    let (system, _) = snapshot::decode(&base, false).unwrap();
+    let replayed = from_snapshot(system);
+    replay(&replayed.file_system, records);
+    assert_eq!(replayed.list_snapshots(), vec!["second"]);
+    assert_eq!(contents(&replayed), contents(&session));
+    let out = replayed.read_file("/.snapshots/second/bin/blob".to_string());
+    assert_eq!(out.unwrap(), b"second");
+    let response = execute(
+        &mut session,
+        Command::WRITE {
+            path: "/.snapshots/second/bin/blob".to_string(),
+            data: Vec::new(),
+        },
+        1,
+    );
+    assert_eq!(response.status, Status::ReadOnly);
 }
@@ -1,147 +1,1110 @@
 /*
-Special case trie that splits on "/" backed with hashmap
+Special case trie that splits on "/" backed with hashmap.
+Nodes live in an inode table, directories map names to inode ids so one
+file can be reached by several names.
  */
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     ffi::OsStr,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    sync::{Arc, Mutex, MutexGuard, RwLock},
+    time::SystemTime,
 };
 
+use parking_lot::{ArcRwLockReadGuard, ArcRwLockWriteGuard, RawRwLock};
+
+use crate::error::FsError;
+use crate::index::TrigramIndex;
+use crate::path;
+use crate::system::Recover;
+
+// Largest file a chunked or offset write may grow, everything lives in memory
+pub const MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+// Most links followed while resolving one path, same as linux
+pub const MAX_LINK_DEPTH: usize = 40;
+// Named snapshots are read under here, each at its name
+pub const SNAPSHOTS: &str = "/.snapshots";
+const SNAPSHOTS_NAME: &str = ".snapshots";
+
+pub type InodeId = u64;
+// A node taken out of the tree, holding its lock. It doesn't borrow the
+// tree, so nodes can come and go from the table while it's held
+pub type NodeRef = ArcRwLockReadGuard<RawRwLock, FsLike>;
+pub type NodeMut = ArcRwLockWriteGuard<RawRwLock, FsLike>;
+type NodeLock = parking_lot::RwLock<FsLike>;
+
+// Turns directories take to have their entries changed, see Trie::entries
+pub const ENTRY_TURNS: usize = 1024;
+
+// Inode numbers are never reused while the server runs
+static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub inode: InodeId,
+    // Names pointing at this inode, it is freed when the last one goes
+    pub links: u32,
+    pub owner: String,
+    pub group: String,
+    pub mode: u32,
+    pub created: SystemTime,
+    pub modified: SystemTime,
+    pub accessed: SystemTime,
+}
+impl Metadata {
+    // Fresh identity owned by owner, every user is their own group for now
+    pub fn new(owner: &str, mode: u32) -> Self {
+        let now = SystemTime::now();
+        Self {
+            inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            links: 0,
+            owner: owner.to_string(),
+            group: owner.to_string(),
+            mode,
+            created: now,
+            modified: now,
+            accessed: now,
+        }
+    }
+    pub fn touch(&mut self) {
+        let now = SystemTime::now();
+        self.modified = now;
+        self.accessed = now;
+    }
+}
+
+// Contents are shared by copies of a node, such as a snapshot's, and only
+// copied once one of them changes
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum FsLike {
-    DirectoryLike { children: HashMap<PathBuf, FsLike> },
-    FileLike { data: Vec<u8> },
-    //TODO Symlinks
+    DirectoryLike {
+        children: Arc<HashMap<PathBuf, InodeId>>,
+        meta: Metadata,
+    },
+    FileLike {
+        data: Arc<Vec<u8>>,
+        meta: Metadata,
+    },
+    // Target is stored as given, relative targets resolve from the link's parent
+    SymlinkLike {
+        target: PathBuf,
+        meta: Metadata,
+    },
 }
 impl FsLike {
+    pub fn directory(owner: &str) -> Self {
+        Self::DirectoryLike {
+            children: Arc::default(),
+            meta: Metadata::new(owner, 0o755),
+        }
+    }
+    pub fn file(owner: &str, data: impl Into<Vec<u8>>) -> Self {
+        Self::FileLike {
+            data: Arc::new(data.into()),
+            meta: Metadata::new(owner, 0o644),
+        }
+    }
+    pub fn symlink(owner: &str, target: impl Into<PathBuf>) -> Self {
+        Self::SymlinkLike {
+            target: target.into(),
+            meta: Metadata::new(owner, 0o777),
+        }
+    }
+    pub fn meta(&self) -> &Metadata {
+        match self {
+            Self::DirectoryLike { meta, .. }
+            | Self::FileLike { meta, .. }
+            | Self::SymlinkLike { meta, .. } => meta,
+        }
+    }
+    pub fn meta_mut(&mut self) -> &mut Metadata {
+        match self {
+            Self::DirectoryLike { meta, .. }
+            | Self::FileLike { meta, .. }
+            | Self::SymlinkLike { meta, .. } => meta,
+        }
+    }
+    // Bytes held by a file, the number of entries in a directory or the
+    // length of a link's target
+    pub fn size(&self) -> u64 {
+        match self {
+            Self::DirectoryLike { children, .. } => children.len() as u64,
+            Self::FileLike { data, .. } => data.len() as u64,
+            Self::SymlinkLike { target, .. } => target.as_os_str().len() as u64,
+        }
+    }
+    // Writes bytes into a file's data at offset, zero filling any gap
+    pub fn write_at(&mut self, offset: u64, bytes: &[u8], path: &Path) -> Result<(), FsError> {
+        let FsLike::FileLike { data, meta } = self else {
+            return Err(FsError::IsADirectory(path.to_path_buf()));
+        };
+        let end = offset.saturating_add(bytes.len() as u64);
+        if end > MAX_FILE_SIZE {
+            return Err(FsError::FileTooLarge(path.to_path_buf()));
+        }
+        let (offset, end) = (offset as usize, end as usize);
+        let data = Arc::make_mut(data);
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(bytes);
+        meta.modified = SystemTime::now();
+        Ok(())
+    }
+    pub fn children(&self) -> Option<&HashMap<PathBuf, InodeId>> {
+        match &self {
+            Self::DirectoryLike { children, .. } => Some(children),
+            _ => None,
+        }
+    }
+}
+
+// A node in the inode table. Tables share them with the snapshots taken
+// from them, and copy one out before changing it
+#[derive(Debug, Clone)]
+struct Inode {
+    // Of the tree when this copy was made, see Trie::shared
+    generation: u64,
+    node: Arc<NodeLock>,
+}
+impl Inode {
+    fn new(node: FsLike, generation: u64) -> Self {
+        Self {
+            generation,
+            node: Arc::new(NodeLock::new(node)),
+        }
+    }
+}
+
+// The whole file system, every path is absolute and starts at root.
+// Each node has its own lock, so contents can change under a shared borrow
+// of the tree. So can its shape, by whoever holds the entries of every
+// directory they check or change, anything more needs it exclusively
+#[derive(Debug)]
+pub struct Trie {
+    // Persistent, a snapshot takes all of it at once and each side copies
+    // only what it changes after. Only held to look a node up or swap one
+    // in or out, never while waiting on a node
+    nodes: RwLock<im::HashMap<InodeId, Inode>>,
+    // Bumped whenever the tree is shared, inodes from before may be in a
+    // snapshot or a copy being saved
+    generation: u64,
+    // The generation since a named snapshot was last taken or restored,
+    // inodes from before may be in one
+    snapshotted: u64,
+    root: InodeId,
+    // Content index kept up to date on every write, when enabled
+    index: Option<Mutex<TrigramIndex>>,
+    // Bumped whenever a node may change, tells when a snapshot is stale
+    changes: AtomicU64,
+    // Named read only copies of the tree, by name
+    snapshots: BTreeMap<String, Arc<Trie>>,
+    // Set on a named snapshot's own tree, which not even reads change
+    frozen: bool,
+    // Directories share turns by inode, see entries
+    entry_turns: Vec<Mutex<()>>,
+}
+impl Trie {
     pub fn new() -> Self {
-        return Self::DirectoryLike {
-            children: HashMap::new(),
+        let mut root = FsLike::directory("root");
+        root.meta_mut().links = 1;
+        let id = root.meta().inode;
+        Self {
+            nodes: RwLock::new(im::HashMap::unit(id, Inode::new(root, 0))),
+            generation: 0,
+            snapshotted: 0,
+            root: id,
+            index: None,
+            changes: AtomicU64::new(0),
+            snapshots: BTreeMap::new(),
+            frozen: false,
+            entry_turns: entry_turns(),
+        }
+    }
+    // Rebuilds a tree from every inode in it, the way a snapshot holds them.
+    // It must be whole, anything repair would fix is reported instead
+    pub fn from_nodes(
+        root: InodeId,
+        nodes: HashMap<InodeId, FsLike>,
+        indexed: bool,
+    ) -> Result<Self, Vec<String>> {
+        if !matches!(nodes.get(&root), Some(FsLike::DirectoryLike { .. })) {
+            return Err(vec!["root is not a directory".to_string()]);
+        }
+        // Inodes made from now on mustn't take a loaded one's number
+        if let Some(last) = nodes.keys().max() {
+            NEXT_INODE.fetch_max(last + 1, Ordering::Relaxed);
+        }
+        let mut trie = Self {
+            nodes: RwLock::new(
+                nodes
+                    .into_iter()
+                    .map(|(id, node)| (id, Inode::new(node, 0)))
+                    .collect(),
+            ),
+            generation: 0,
+            snapshotted: 0,
+            root,
+            index: indexed.then(Mutex::default),
+            changes: AtomicU64::new(0),
+            snapshots: BTreeMap::new(),
+            frozen: false,
+            entry_turns: entry_turns(),
         };
+        // Also builds the index
+        let problems = trie.repair();
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+        trie.changes = AtomicU64::new(0);
+        Ok(trie)
     }
-    //Insert new directory
-    pub fn insert(&mut self, path: impl AsRef<Path>, node: Self) -> Result<(), &'static str> {
-        let mut iter = path.as_ref().iter();
-        let Some(node_name) = iter.next_back().map(Path::new) else {
-            *self = node;
-            return Ok(());
+    pub fn root(&self) -> InodeId {
+        self.root
+    }
+    // Every inode, in no particular order
+    pub fn inodes(&self) -> Vec<InodeId> {
+        self.nodes.read().recover().keys().copied().collect()
+    }
+    pub fn changes(&self) -> u64 {
+        self.changes.load(Ordering::Relaxed)
+    }
+    pub fn with_index() -> Self {
+        Self {
+            index: Some(Mutex::new(TrigramIndex::default())),
+            ..Self::new()
+        }
+    }
+    pub fn index(&self) -> Option<MutexGuard<'_, TrigramIndex>> {
+        self.index.as_ref().map(|index| index.lock().recover())
+    }
+    fn inode(&self, id: InodeId) -> Option<Inode> {
+        self.nodes.read().recover().get(&id).cloned()
+    }
+    // A node read locked, don't ask for the same one twice while holding it
+    pub fn node(&self, id: InodeId) -> Option<NodeRef> {
+        self.inode(id).map(|inode| inode.node.read_arc())
+    }
+    // A node write locked without the rest of the tree, for its contents and
+    // times. Contents must change through set_data or write_inode so the
+    // index sees
+    pub fn node_mut(&self, id: InodeId) -> Option<NodeMut> {
+        self.changes.fetch_add(1, Ordering::Relaxed);
+        self.own(id)
+    }
+    // The tree's own copy of the node at id write locked. One that may be
+    // shared is copied and swapped in first, what it holds is still shared
+    // until that changes too
+    fn own(&self, id: InodeId) -> Option<NodeMut> {
+        loop {
+            let inode = self.inode(id)?;
+            let node = inode.node.write_arc();
+            // Swapped or freed while waiting for it. Neither can happen once
+            // it's held, both hold the node
+            if !Arc::ptr_eq(&self.inode(id)?.node, &inode.node) {
+                continue;
+            }
+            if inode.generation >= self.generation {
+                return Some(node);
+            }
+            let copy = Inode::new(FsLike::clone(&node), self.generation);
+            let owned = copy.node.write_arc();
+            self.nodes.write().recover().insert(id, copy);
+            return Some(owned);
+        }
+    }
+    // Holding the whole tree no node can be locked by anyone else
+    fn entry(&mut self, id: InodeId) -> Option<&mut FsLike> {
+        *self.changes.get_mut() += 1;
+        let generation = self.generation;
+        let inode = self.nodes.get_mut().recover().get_mut(&id)?;
+        // Nothing else may keep a node changed this way, not a snapshot nor
+        // a guard someone forgot to drop
+        if inode.generation < generation || Arc::get_mut(&mut inode.node).is_none() {
+            let node = FsLike::clone(&inode.node.read());
+            *inode = Inode::new(node, generation);
+        }
+        Arc::get_mut(&mut inode.node).map(NodeLock::get_mut)
+    }
+    // Whether the node at id may be a snapshot's too, so it's copied out
+    // before it changes. Stays set until then, even once the snapshot is gone
+    #[cfg(test)]
+    pub fn shared(&self, id: InodeId) -> bool {
+        self.inode(id)
+            .is_some_and(|inode| inode.generation < self.generation)
+    }
+    // A copy of the whole tree as it is now, for saving it without holding
+    // the tree. It shares every node, which are copied out of it as they
+    // change, like with a snapshot
+    pub fn share(&mut self) -> Trie {
+        let copy = Trie {
+            nodes: RwLock::new(self.nodes.get_mut().recover().clone()),
+            generation: self.generation,
+            snapshotted: self.snapshotted,
+            root: self.root,
+            index: None,
+            changes: AtomicU64::new(self.changes()),
+            snapshots: self.snapshots.clone(),
+            frozen: true,
+            entry_turns: entry_turns(),
         };
-        // Checks path to confirm it is a path of directories
-        let mut tree = self;
-        for path_part in iter {
-            match tree {
-                FsLike::DirectoryLike { .. } => {}
-                FsLike::FileLike { .. } => return Err("Cannot insert into a non directory"),
-            }
-            // If parent not found create it
-            match tree.get_mut(path_part) {
-                Some(..) => {}
-                None => {
-                    tree.insert(
-                        path_part,
-                        FsLike::DirectoryLike {
-                            children: HashMap::new(),
-                        },
-                    )?;
+        self.generation += 1;
+        copy
+    }
+    // Brings the index in line with the whole contents of id
+    fn reindex(&self, id: InodeId) {
+        // The node before the index, like a write takes them
+        if let (Some(node), Some(mut index)) = (self.node(id), self.index()) {
+            if let FsLike::FileLike { data, .. } = &*node {
+                index.set(id, data);
+            }
+        }
+    }
+    // Held while the entries of the directories dirs are checked and changed
+    // sharing the tree, which is all a change to its shape needs when it
+    // names nothing through a link and makes or removes no directory with
+    // anything in it. Taken in one order, so two callers can't each wait on
+    // the other. Directories share turns, that only makes them wait
+    pub fn entries(&self, dirs: &[InodeId]) -> Vec<MutexGuard<'_, ()>> {
+        let mut turns = dirs
+            .iter()
+            .map(|id| *id as usize % ENTRY_TURNS)
+            .collect::<Vec<_>>();
+        turns.sort_unstable();
+        turns.dedup();
+        turns
+            .into_iter()
+            .map(|turn| self.entry_turns[turn].lock().recover())
+            .collect()
+    }
+    // The file at path write locked, following links
+    fn file_mut(&self, path: &Path) -> Result<(InodeId, NodeMut), FsError> {
+        let id = self
+            .resolve_id(path, true)
+            .ok_or_else(|| FsError::NotFound(path.to_path_buf()))?;
+        let node = self
+            .node_mut(id)
+            .ok_or_else(|| FsError::NotFound(path.to_path_buf()))?;
+        match &*node {
+            FsLike::FileLike { .. } => Ok((id, node)),
+            _ => Err(FsError::IsADirectory(path.to_path_buf())),
+        }
+    }
+    // Replaces the contents of the file at path, following links
+    pub fn set_data(&self, path: &Path, contents: impl Into<Arc<Vec<u8>>>) -> Result<(), FsError> {
+        writable(path)?;
+        let (id, mut node) = self.file_mut(path)?;
+        let FsLike::FileLike { data, meta } = &mut *node else {
+            return Err(FsError::IsADirectory(path.to_path_buf()));
+        };
+        *data = contents.into();
+        meta.modified = SystemTime::now();
+        // Still holding the file, so the index can't see writes out of order
+        if let Some(mut index) = self.index() {
+            index.set(id, data);
+        }
+        Ok(())
+    }
+    // Cuts the file at path down to size bytes or zero fills it up to size
+    pub fn truncate(&self, path: &Path, size: u64) -> Result<(), FsError> {
+        if size > MAX_FILE_SIZE {
+            return Err(FsError::FileTooLarge(path.to_path_buf()));
+        }
+        writable(path)?;
+        let (id, mut node) = self.file_mut(path)?;
+        let FsLike::FileLike { data, meta } = &mut *node else {
+            return Err(FsError::IsADirectory(path.to_path_buf()));
+        };
+        let old_len = data.len();
+        Arc::make_mut(data).resize(size as usize, 0);
+        meta.modified = SystemTime::now();
+        // Stale trigrams past the cut are harmless, new zeros are not
+        if let Some(mut index) = self.index() {
+            index.add(id, &data[old_len.saturating_sub(2).min(data.len())..]);
+        }
+        Ok(())
+    }
+    // Writes bytes into the file at path at offset, see FsLike::write_at
+    pub fn write_at(&self, path: &Path, offset: u64, bytes: &[u8]) -> Result<(), FsError> {
+        writable(path)?;
+        let id = self
+            .resolve_id(path, true)
+            .ok_or_else(|| FsError::NotFound(path.to_path_buf()))?;
+        self.write_inode(id, Some(offset), bytes, path)?;
+        Ok(())
+    }
+    // Writes bytes after the end of the file at path
+    pub fn append(&self, path: &Path, bytes: &[u8]) -> Result<(), FsError> {
+        writable(path)?;
+        let id = self
+            .resolve_id(path, true)
+            .ok_or_else(|| FsError::NotFound(path.to_path_buf()))?;
+        self.write_inode(id, None, bytes, path)?;
+        Ok(())
+    }
+    // Like write_at for a file already looked up, path only names it in errors.
+    // No offset means the end as it is once the file is locked, so appends
+    // racing each other never land on the same bytes. Returns where the
+    // bytes went
+    pub fn write_inode(
+        &self,
+        id: InodeId,
+        offset: Option<u64>,
+        bytes: &[u8],
+        path: &Path,
+    ) -> Result<u64, FsError> {
+        let mut node = self
+            .node_mut(id)
+            .ok_or_else(|| FsError::NotFound(path.to_path_buf()))?;
+        let offset = offset.unwrap_or_else(|| node.size());
+        node.write_at(offset, bytes, path)?;
+        // Only trigrams overlapping the written bytes can be new
+        if let (Some(mut index), FsLike::FileLike { data, .. }) = (self.index(), &*node) {
+            let start = (offset as usize).saturating_sub(2);
+            let end = data.len().min(offset as usize + bytes.len() + 2);
+            index.add(id, &data[start..end]);
+        }
+        Ok(offset)
+    }
+    // Marks the file at id as just read. Like relatime only when it changed
+    // since the last read, so readers of a file nobody writes share it.
+    // Reading isn't a change, the time is saved with the next one. Left as
+    // is while a snapshot may share the file, it's only read. A copy being
+    // saved may see the new time, it's saved either way
+    pub fn accessed(&self, id: InodeId) {
+        let Some(inode) = self.inode(id) else {
+            return;
+        };
+        if self.frozen || inode.generation < self.snapshotted {
+            return;
+        }
+        let stale = {
+            let node = inode.node.read();
+            node.meta().accessed <= node.meta().modified
+        };
+        if stale {
+            inode.node.write().meta_mut().accessed = SystemTime::now();
+        }
+    }
+    // Plain lookup by name, links are nodes like any other
+    fn lookup(&self, path: &Path) -> Option<InodeId> {
+        let mut id = self.root;
+        for part in path.iter() {
+            // Support for those squirly .config dirs.
+            if part == OsStr::new("/") || part == OsStr::new(".") {
+                continue;
+            }
+            id = *self.node(id)?.children()?.get(Path::new(part))?;
+        }
+        Some(id)
+    }
+    // Path with every symlink along it expanded, the last component only when
+    // follow_last is set. Missing components are kept as is so the result can
+    // name something about to be created. Expects an absolute path
+    pub fn canonicalize(&self, path: &Path, follow_last: bool) -> Result<PathBuf, FsError> {
+        let mut pending = path.iter().rev().map(PathBuf::from).collect::<Vec<_>>();
+        let mut resolved = PathBuf::new();
+        // A link met twice with the same remainder can never finish
+        let mut seen = HashSet::new();
+        let mut followed = 0;
+        while let Some(part) = pending.pop() {
+            let candidate = resolved.join(&part);
+            let is_last = pending.is_empty();
+            let node = self.lookup(&candidate).and_then(|id| self.node(id));
+            match node.as_deref() {
+                Some(FsLike::SymlinkLike { target, .. }) if follow_last || !is_last => {
+                    followed += 1;
+                    if followed > MAX_LINK_DEPTH || !seen.insert((candidate.clone(), pending.len()))
+                    {
+                        return Err(FsError::LinkLoop(candidate));
+                    }
+                    let expanded = path::resolve(&resolved, &target.to_string_lossy());
+                    pending.extend(expanded.iter().rev().map(PathBuf::from));
+                    resolved.clear();
                 }
+                _ => resolved = candidate,
+            }
+        }
+        Ok(resolved)
+    }
+    // Inode at path following any links, or none if doesn't exist
+    pub fn resolve_id(&self, path: impl AsRef<Path>, follow_last: bool) -> Option<InodeId> {
+        let path = self.canonicalize(path.as_ref(), follow_last).ok()?;
+        self.lookup(&path)
+    }
+    // A name id can be found by, trying hint first since it usually still is
+    // one. None once every name is gone
+    pub fn path_of(&self, id: InodeId, hint: &Path) -> Option<PathBuf> {
+        if self.lookup(hint) == Some(id) {
+            return Some(hint.to_path_buf());
+        }
+        let mut pending = vec![(PathBuf::from("/"), self.root)];
+        while let Some((path, dir)) = pending.pop() {
+            let Some(node) = self.node(dir) else {
+                continue;
+            };
+            for (name, child) in node.children().into_iter().flatten() {
+                if *child == id {
+                    return Some(path.join(name));
+                }
+                pending.push((path.join(name), *child));
+            }
+        }
+        None
+    }
+    // Returns fs node at path following any links, or none if doesn't exist
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<NodeRef> {
+        let id = self.resolve_id(path, true)?;
+        self.node(id)
+    }
+    pub fn get_mut(&self, path: impl AsRef<Path>) -> Option<NodeMut> {
+        let id = self.resolve_id(path, true)?;
+        self.node_mut(id)
+    }
+    // Like get, but a link at the end of the path is returned itself
+    pub fn get_link(&self, path: impl AsRef<Path>) -> Option<NodeRef> {
+        let id = self.resolve_id(path, false)?;
+        self.node(id)
+    }
+    // Walks to the directory at path, making any that are missing
+    fn make_parents(&self, path: &Path, owner: &str) -> Result<InodeId, FsError> {
+        let mut id = self.root;
+        let mut walked = PathBuf::new();
+        for part in path.iter() {
+            walked.push(part);
+            if part == OsStr::new("/") {
+                continue;
+            }
+            let child = match self.node(id).as_deref().and_then(FsLike::children) {
+                Some(children) => children.get(Path::new(part)).copied(),
+                None => return Err(FsError::NotADirectory(walked)),
+            };
+            id = match child {
+                Some(child) => child,
+                // If parent not found create it
+                None => self.attach(id, Path::new(part), FsLike::directory(owner)),
+            };
+        }
+        if !self.is_dir(id) {
+            return Err(FsError::NotADirectory(walked));
+        }
+        Ok(id)
+    }
+    // Adds a brand new node under parent, which must be a directory
+    fn attach(&self, parent: InodeId, name: &Path, mut node: FsLike) -> InodeId {
+        let id = node.meta().inode;
+        node.meta_mut().links = 1;
+        self.changes.fetch_add(1, Ordering::Relaxed);
+        self.nodes
+            .write()
+            .recover()
+            .insert(id, Inode::new(node, self.generation));
+        self.reindex(id);
+        self.add_entry(parent, name, id);
+        id
+    }
+    fn add_entry(&self, parent: InodeId, name: &Path, id: InodeId) {
+        let mut parent = self.node_mut(parent);
+        if let Some(FsLike::DirectoryLike { children, meta }) = parent.as_deref_mut() {
+            Arc::make_mut(children).insert(name.into(), id);
+            meta.modified = SystemTime::now();
+        }
+    }
+    // Takes the name at path out of its directory, the inode is left alone
+    fn detach(&self, path: &Path) -> Result<InodeId, FsError> {
+        // If the user is trying to rm .. or / we need to stop that
+        let (Some(parent_path), Some(file_name)) = (path.parent(), path.file_name()) else {
+            return Err(FsError::InvalidPath(path.to_path_buf()));
+        };
+        let parent = self
+            .lookup(parent_path)
+            .ok_or_else(|| FsError::NotFound(path.to_path_buf()))?;
+        match self.node_mut(parent).as_deref_mut() {
+            Some(FsLike::DirectoryLike { children, meta }) => {
+                let id = Arc::make_mut(children)
+                    .remove(Path::new(file_name))
+                    .ok_or_else(|| FsError::NotFound(path.to_path_buf()))?;
+                meta.modified = SystemTime::now();
+                Ok(id)
+            }
+            _ => Err(FsError::NotFound(path.to_path_buf())),
+        }
+    }
+    // Drops one link to id, freeing it (and a directory's contents) at zero
+    fn release(&self, id: InodeId) {
+        let children = {
+            let Some(mut node) = self.node_mut(id) else {
+                return;
             };
-            tree = if let Some(tree) = tree.get_mut(path_part) {
-                tree
-            } else {
-                return Err("Nested Dir creation failed");
+            let meta = node.meta_mut();
+            meta.links = meta.links.saturating_sub(1);
+            if meta.links > 0 {
+                return;
             }
+            if let Some(mut index) = self.index() {
+                index.forget(id);
+            }
+            // Still holding it, so no one can be copying it back in, see own
+            self.nodes.write().recover().remove(&id);
+            node.children().cloned()
+        };
+        for child in children.iter().flat_map(|children| children.values()) {
+            self.release(*child);
         }
-        match tree {
-            FsLike::FileLike { .. } => return Err("Parent node isn't directory"),
-            FsLike::DirectoryLike { children } => {
-                if !children.contains_key(node_name.into()) {
-                    children.insert(node_name.into(), node);
+    }
+    // Puts the tree back in order after a panic may have left a change half
+    // done. Names of missing inodes and extra names of a directory are
+    // dropped, inodes no name reaches are freed, link counts are recounted
+    // and the index is rebuilt. Returns what had to be fixed
+    pub fn repair(&mut self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut names = HashMap::from([(self.root, 1)]);
+        let mut pending = vec![(PathBuf::from("/"), self.root)];
+        while let Some((path, id)) = pending.pop() {
+            let Some(FsLike::DirectoryLike { children, .. }) = self.entry(id) else {
+                continue;
+            };
+            // In name order, so which of two names a directory keeps doesn't
+            // change from one run to the next
+            let mut entries = children
+                .iter()
+                .map(|(name, child)| (name.clone(), *child))
+                .collect::<Vec<_>>();
+            entries.sort();
+            let mut dropped = Vec::new();
+            for (name, child) in entries {
+                let child_path = path.join(&name);
+                if !self.nodes.get_mut().recover().contains_key(&child) {
+                    problems.push(format!("{}: names a missing inode", child_path.display()));
+                    dropped.push(name);
+                } else if self.is_dir(child) && names.contains_key(&child) {
+                    problems.push(format!("{}: directory named twice", child_path.display()));
+                    dropped.push(name);
                 } else {
-                    match children.get(node_name.into()).unwrap() {
-                        FsLike::FileLike { data } => {
-                            match &node {
-                                FsLike::FileLike { data: node_data } => {
-                                    if node_data.len() > 0 {
-                                        children.insert(node_name.into(), node);
-                                    }
-                                    // else is no op touching a file that exists
-                                }
-                                _ => {}
-                            }
-                        }
-                        FsLike::DirectoryLike { .. } => {}
+                    let count = names.entry(child).or_insert(0);
+                    *count += 1;
+                    if *count == 1 && self.is_dir(child) {
+                        pending.push((child_path, child));
                     }
                 }
             }
+            if let Some(FsLike::DirectoryLike { children, .. }) = self.entry(id) {
+                Arc::make_mut(children).retain(|name, _| !dropped.contains(name));
+            }
+        }
+        let unreachable = self
+            .nodes
+            .get_mut()
+            .recover()
+            .keys()
+            .filter(|id| !names.contains_key(id))
+            .copied()
+            .collect::<Vec<_>>();
+        if !unreachable.is_empty() {
+            problems.push(format!("{} unreachable inodes freed", unreachable.len()));
+        }
+        for id in unreachable {
+            self.nodes.get_mut().recover().remove(&id);
+        }
+        for (id, count) in names {
+            let Some(meta) = self.entry(id).map(FsLike::meta_mut) else {
+                continue;
+            };
+            if meta.links != count {
+                problems.push(format!(
+                    "inode {}: {} links counted {}",
+                    id, meta.links, count
+                ));
+                meta.links = count;
+            }
+        }
+        self.rebuild_index();
+        problems
+    }
+    // Indexes every file over from scratch
+    fn rebuild_index(&mut self) {
+        if let Some(index) = &mut self.index {
+            let mut rebuilt = TrigramIndex::default();
+            for (id, inode) in self.nodes.get_mut().recover().iter() {
+                if let FsLike::FileLike { data, .. } = &*inode.node.read() {
+                    rebuilt.set(*id, data);
+                }
+            }
+            *index = Mutex::new(rebuilt);
+        }
+    }
+    // Whether id is a directory, nothing at id isn't
+    fn is_dir(&self, id: InodeId) -> bool {
+        matches!(self.node(id).as_deref(), Some(FsLike::DirectoryLike { .. }))
+    }
+    //Insert new directory
+    pub fn insert(&self, path: impl AsRef<Path>, node: FsLike) -> Result<(), FsError> {
+        // Links in the parents are followed, a link being replaced is not
+        let path = self.canonicalize(path.as_ref(), false)?;
+        writable(&path)?;
+        // Only root has no name, and it always exists
+        let (Some(parent_path), Some(node_name)) = (path.parent(), path.file_name()) else {
+            return Ok(());
+        };
+        let node_name = Path::new(node_name);
+        // Checks path to confirm it is a path of directories
+        // Parents made along the way belong to whoever made the node
+        let parent = self.make_parents(parent_path, &node.meta().owner.clone())?;
+        let existing = self
+            .node(parent)
+            .and_then(|parent| parent.children()?.get(node_name).copied());
+        match existing {
+            None => {
+                self.attach(parent, node_name, node);
+            }
+            // A new file takes the place of the old one, whatever it holds
+            Some(id)
+                if matches!(
+                    (self.node(id).as_deref(), &node),
+                    (Some(FsLike::FileLike { .. }), FsLike::FileLike { .. })
+                ) =>
+            {
+                self.release(id);
+                self.attach(parent, node_name, node);
+            }
+            // Anything else already there is left alone, like mkdir -p
+            Some(..) => {}
         }
         Ok(())
     }
-    // Returns fs node at path, or none if doesn't exist
-
-    pub fn get(&self, path: impl AsRef<Path>) -> Option<&Self> {
-        let path = path.as_ref();
-        let (first, rest) = {
-            let mut iter = path.iter();
-            let first: Option<&Path> = iter.next().map(OsStr::as_ref);
-            (first, iter.as_path())
-        };
-        let Some(first) = first else {
-            return Some(self);
-        };
-        if rest == Path::new(".") {
-            return self.get(rest);
-        }
-        self.children()?
-            .get(first)
-            .and_then(|child| child.get(rest))
-    }
-    pub fn get_mut(&mut self, path: impl AsRef<Path>) -> Option<&mut Self> {
-        let path = path.as_ref();
-        let (first, rest) = {
-            let mut iter = path.iter();
-            let first: Option<&Path> = iter.next().map(OsStr::as_ref);
-            (first, iter.as_path())
-        };
-        let Some(first) = first else {
-            return Some(self);
-        };
-        // Support for those squirly .config dirs.
-        if rest == Path::new(".") {
-            return self.get_mut(rest);
-        }
-        self.children_mut()?
-            .get_mut(first)
-            .and_then(|child| child.get_mut(rest))
-    }
-    pub fn remove(&mut self, path: PathBuf) -> Result<(), &'static str> {
-        if self.get(&path).is_none() {
-            return Err("Not Found, cannot delete");
-        }
-        // If full path is present, pretty reasonable to safely unwrap parent
-        // However if the user is trying to rm .. we need to stop that
-        let parent_path = match path.parent() {
-            Some(parent) => parent,
-            None => return Err("Cannot remove .. or /"),
-        };
-        // likewise with "filename" (is directory if directory)
-        let target_path = PathBuf::from(path.file_name().unwrap());
-        let parent_node = self.get_mut(parent_path).unwrap();
-
-        parent_node
-            .children_mut()
-            .and_then(|c| c.remove(&target_path));
-        return Ok(());
+    // Removes the name at path, a link is removed rather than what it points
+    // at. The inode goes once no other name refers to it
+    pub fn remove(&self, path: PathBuf) -> Result<(), FsError> {
+        let path = self.canonicalize(&path, false)?;
+        writable(&path)?;
+        if self.lookup(&path).is_none() {
+            return Err(FsError::NotFound(path));
+        }
+        let id = self.detach(&path)?;
+        self.release(id);
+        Ok(())
     }
-    pub fn children(&self) -> Option<&HashMap<PathBuf, FsLike>> {
-        match &self {
-            Self::DirectoryLike { children } => Some(children),
-            _ => None,
+    // Hard link, link becomes another name for the inode at target
+    pub fn link(&self, target: &Path, link: &Path) -> Result<(), FsError> {
+        let target = self.canonicalize(target, false)?;
+        let link = self.canonicalize(link, false)?;
+        writable(&link)?;
+        let id = self
+            .lookup(&target)
+            .ok_or_else(|| FsError::NotFound(target.clone()))?;
+        if self.is_dir(id) {
+            return Err(FsError::NotPermitted(target));
+        }
+        if self.lookup(&link).is_some() {
+            return Err(FsError::AlreadyExists(link));
+        }
+        let (Some(parent_path), Some(name)) = (link.parent(), link.file_name()) else {
+            return Err(FsError::InvalidPath(link));
+        };
+        let parent = self
+            .lookup(parent_path)
+            .ok_or_else(|| FsError::NotFound(parent_path.to_path_buf()))?;
+        if !self.is_dir(parent) {
+            return Err(FsError::NotADirectory(parent_path.to_path_buf()));
+        }
+        self.add_entry(parent, Path::new(name), id);
+        if let Some(mut node) = self.node_mut(id) {
+            node.meta_mut().links += 1;
         }
+        Ok(())
     }
-    pub fn children_mut(&mut self) -> Option<&mut HashMap<PathBuf, FsLike>> {
-        match self {
-            Self::DirectoryLike { children } => Some(children),
-            _ => None,
+    // Fresh copies of the node at id in source and everything under it, put
+    // in copies and not yet named. Links inside are copied as links
+    fn duplicate(source: &Trie, id: InodeId, owner: &str, copies: &mut Vec<FsLike>) -> InodeId {
+        let mut copy = match source.node(id).map(|node| FsLike::clone(&node)) {
+            // Contents stay shared until either file is written
+            Some(FsLike::FileLike { data, .. }) => FsLike::FileLike {
+                data,
+                meta: Metadata::new(owner, 0o644),
+            },
+            Some(FsLike::SymlinkLike { target, .. }) => FsLike::symlink(owner, target),
+            Some(FsLike::DirectoryLike { children, .. }) => FsLike::DirectoryLike {
+                children: Arc::new(
+                    children
+                        .iter()
+                        .map(|(name, child)| {
+                            (name.clone(), Self::duplicate(source, *child, owner, copies))
+                        })
+                        .collect(),
+                ),
+                meta: Metadata::new(owner, 0o755),
+            },
+            None => FsLike::directory(owner),
+        };
+        copy.meta_mut().links = 1;
+        let copy_id = copy.meta().inode;
+        copies.push(copy);
+        copy_id
+    }
+    // Deep copy of the node at src to dst, every copy is a new inode owned by
+    // owner. A file already at dst keeps its inode and takes the new contents.
+    // src is read from the named snapshot from when given
+    pub fn copy(
+        &self,
+        from: Option<&Trie>,
+        src: &Path,
+        dst: &Path,
+        owner: &str,
+    ) -> Result<(), FsError> {
+        writable(dst)?;
+        let source = from.unwrap_or(self);
+        let id = source
+            .lookup(src)
+            .ok_or_else(|| FsError::NotFound(src.to_path_buf()))?;
+        let is_dir = source.is_dir(id);
+        if from.is_none() && is_dir && dst.starts_with(src) {
+            return Err(FsError::IntoDescendant(dst.to_path_buf()));
+        }
+        let (Some(parent_path), Some(name)) = (dst.parent(), dst.file_name()) else {
+            return Err(FsError::InvalidPath(dst.to_path_buf()));
+        };
+        let existing = self.lookup(dst);
+        if from.is_none() && existing == Some(id) {
+            return Err(FsError::SameFile(dst.to_path_buf()));
         }
+        match (existing.map(|existing| self.is_dir(existing)), is_dir) {
+            (Some(true), _) => return Err(FsError::IsADirectory(dst.to_path_buf())),
+            (Some(false), true) => return Err(FsError::NotADirectory(dst.to_path_buf())),
+            (Some(false), false) => {
+                let data = match source.node(id).as_deref() {
+                    Some(FsLike::FileLike { data, .. }) => data.clone(),
+                    _ => return Err(FsError::InvalidPath(src.to_path_buf())),
+                };
+                return self.set_data(dst, data);
+            }
+            (None, _) => {}
+        }
+        let mut copies = Vec::new();
+        let copy = Self::duplicate(source, id, owner, &mut copies);
+        let parent = self.make_parents(parent_path, owner)?;
+        for node in copies {
+            let id = node.meta().inode;
+            self.nodes
+                .write()
+                .recover()
+                .insert(id, Inode::new(node, self.generation));
+            self.reindex(id);
+        }
+        self.add_entry(parent, Path::new(name), copy);
+        Ok(())
+    }
+    // Gives the node at src the name dst in one step, the way rename(2) does.
+    // A file or link at dst is replaced, so is an empty directory when src is
+    // one too. The node keeps its inode and metadata, a directory brings its
+    // whole subtree along. Everything is checked before anything changes
+    pub fn rename(&self, src: &Path, dst: &Path) -> Result<(), FsError> {
+        let src = self.canonicalize(src, false)?;
+        let dst = self.canonicalize(dst, false)?;
+        writable(&src)?;
+        writable(&dst)?;
+        let id = self
+            .lookup(&src)
+            .ok_or_else(|| FsError::NotFound(src.clone()))?;
+        // Already the same file, there is nothing to do
+        if src == dst || self.lookup(&dst) == Some(id) {
+            return Ok(());
+        }
+        let is_dir = self.is_dir(id);
+        if is_dir && dst.starts_with(&src) {
+            return Err(FsError::IntoDescendant(dst));
+        }
+        let (Some(..), Some(..)) = (src.parent(), src.file_name()) else {
+            return Err(FsError::InvalidPath(src));
+        };
+        let (Some(parent_path), Some(name)) = (dst.parent(), dst.file_name()) else {
+            return Err(FsError::InvalidPath(dst));
+        };
+        let parent = match self.lookup(parent_path) {
+            Some(parent) if self.is_dir(parent) => parent,
+            Some(..) => return Err(FsError::NotADirectory(parent_path.to_path_buf())),
+            None => return Err(FsError::NotFound(parent_path.to_path_buf())),
+        };
+        let existing = self.lookup(&dst);
+        let replace = match existing.and_then(|existing| self.node(existing)).as_deref() {
+            None => false,
+            Some(FsLike::DirectoryLike { children, .. }) if is_dir => {
+                if !children.is_empty() {
+                    return Err(FsError::NotEmpty(dst));
+                }
+                true
+            }
+            Some(FsLike::DirectoryLike { .. }) => return Err(FsError::IsADirectory(dst)),
+            Some(..) if is_dir => return Err(FsError::NotADirectory(dst)),
+            Some(..) => true,
+        };
+        if replace {
+            let replaced = self.detach(&dst)?;
+            self.release(replaced);
+        }
+        self.detach(&src)?;
+        self.add_entry(parent, Path::new(name), id);
+        Ok(())
+    }
+    // Named snapshots in name order
+    pub fn snapshots(&self) -> impl Iterator<Item = (&str, &Trie)> {
+        self.snapshots
+            .iter()
+            .map(|(name, snapshot)| (name.as_str(), &**snapshot))
+    }
+    // The named snapshot path is in, if any
+    pub fn snapshot_at(&self, path: &Path) -> Option<&Arc<Trie>> {
+        let name = path.strip_prefix(SNAPSHOTS).ok()?.iter().next()?;
+        self.snapshots.get(name.to_str()?)
+    }
+    // The tree path is read from, a named snapshot's when it's in one
+    pub fn view(&self, path: &Path) -> &Trie {
+        self.snapshot_at(path).map_or(self, |snapshot| snapshot)
+    }
+    // Keeps the tree as it is now as name. It shares every node with the
+    // tree, and they share their contents, until either side changes them.
+    // So it's cheap however big the tree is
+    pub fn create_snapshot(&mut self, name: &str) -> Result<(), FsError> {
+        let mount = mount_point(name)?;
+        if self.snapshots.contains_key(name) {
+            return Err(FsError::AlreadyExists(mount));
+        }
+        let snapshot = self.freeze(name);
+        self.generation += 1;
+        self.snapshotted = self.generation;
+        self.make_parents(&mount, "root")?;
+        self.snapshots.insert(name.to_string(), Arc::new(snapshot));
+        Ok(())
+    }
+    // Adds a snapshot read back from disk, it's no change to the tree
+    pub fn load_snapshot(&mut self, name: String, mut snapshot: Trie) {
+        snapshot.frozen = true;
+        self.snapshots.insert(name, Arc::new(snapshot));
+    }
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<(), FsError> {
+        let mount = mount_point(name)?;
+        if self.snapshots.remove(name).is_none() {
+            return Err(FsError::NotFound(mount));
+        }
+        if self.lookup(&mount).is_some() {
+            let id = self.detach(&mount)?;
+            self.release(id);
+        }
+        Ok(())
+    }
+    // Puts the tree back the way it was when name was taken, sharing the
+    // snapshot's nodes again. Every snapshot is kept. Only the index, when
+    // there is one, is built over
+    pub fn restore_snapshot(&mut self, name: &str) -> Result<(), FsError> {
+        let mount = mount_point(name)?;
+        let snapshot = self
+            .snapshots
+            .get(name)
+            .cloned()
+            .ok_or_else(|| FsError::NotFound(mount.clone()))?;
+        let root = snapshot
+            .lookup(&mount)
+            .ok_or_else(|| FsError::NotFound(mount.clone()))?;
+        let mut nodes = snapshot.nodes.read().recover().clone();
+        nodes.remove(&snapshot.root);
+        if let Some(mounts) = snapshot.lookup(Path::new(SNAPSHOTS)) {
+            nodes.remove(&mounts);
+        }
+        // The snapshots themselves stay where they are
+        let kept = self.lookup(Path::new(SNAPSHOTS));
+        for id in kept.map(|kept| self.subtree(kept)).unwrap_or_default() {
+            if let Some(inode) = self.inode(id) {
+                nodes.insert(id, inode);
+            }
+        }
+        *self.nodes.get_mut().recover() = nodes;
+        self.root = root;
+        self.generation += 1;
+        self.snapshotted = self.generation;
+        if let Some(kept) = kept {
+            if let Some(FsLike::DirectoryLike { children, .. }) = self.entry(root) {
+                Arc::make_mut(children).insert(SNAPSHOTS_NAME.into(), kept);
+            }
+        }
+        *self.changes.get_mut() += 1;
+        self.rebuild_index();
+        Ok(())
+    }
+    // The tree's nodes but the snapshots, with a root and /.snapshots of its
+    // own above the real root named name, so paths into it are the same as
+    // seen from the tree
+    fn freeze(&self, name: &str) -> Trie {
+        let mut nodes = self.nodes.read().recover().clone();
+        if let Some(hidden) = self.lookup(Path::new(SNAPSHOTS)) {
+            for id in self.subtree(hidden) {
+                nodes.remove(&id);
+            }
+        }
+        if let Some(mut root) = self.node(self.root).map(|node| FsLike::clone(&node)) {
+            if let FsLike::DirectoryLike { children, .. } = &mut root {
+                if children.contains_key(Path::new(SNAPSHOTS_NAME)) {
+                    Arc::make_mut(children).remove(Path::new(SNAPSHOTS_NAME));
+                }
+            }
+            nodes.insert(self.root, Inode::new(root, self.generation));
+        }
+        let mut above = self.root;
+        for name in [name, SNAPSHOTS_NAME] {
+            let mut dir = FsLike::DirectoryLike {
+                children: Arc::new(HashMap::from([(PathBuf::from(name), above)])),
+                meta: Metadata::new("root", 0o555),
+            };
+            dir.meta_mut().links = 1;
+            above = dir.meta().inode;
+            nodes.insert(above, Inode::new(dir, self.generation));
+        }
+        Trie {
+            nodes: RwLock::new(nodes),
+            generation: self.generation,
+            snapshotted: self.snapshotted,
+            root: above,
+            index: None,
+            changes: AtomicU64::new(0),
+            snapshots: BTreeMap::new(),
+            frozen: true,
+            entry_turns: entry_turns(),
+        }
+    }
+    // id and everything under it
+    fn subtree(&self, id: InodeId) -> HashSet<InodeId> {
+        let mut found = HashSet::new();
+        let mut pending = vec![id];
+        while let Some(id) = pending.pop() {
+            if !found.insert(id) {
+                continue;
+            }
+            if let Some(children) = self.node(id).as_deref().and_then(FsLike::children) {
+                pending.extend(children.values());
+            }
+        }
+        found
+    }
+}
+
+fn entry_turns() -> Vec<Mutex<()>> {
+    (0..ENTRY_TURNS).map(|_| Mutex::default()).collect()
+}
+
+// Named snapshots are only changed as a whole, never through their paths
+pub fn writable(path: &Path) -> Result<(), FsError> {
+    match path.starts_with(SNAPSHOTS) {
+        true => Err(FsError::ReadOnly(path.to_path_buf())),
+        false => Ok(()),
+    }
+}
+
+// Where the snapshot name is read, if it's a name that can be one
+fn mount_point(name: &str) -> Result<PathBuf, FsError> {
+    let mount = Path::new(SNAPSHOTS).join(name);
+    match name {
+        "" | "." | ".." => Err(FsError::InvalidPath(mount)),
+        _ if name.contains('/') => Err(FsError::InvalidPath(mount)),
+        _ => Ok(mount),
     }
 }
@@ -0,0 +1,355 @@
+/*
+Changes made since the last snapshot, replayed on top of it at start.
+`magic "EPHW" | version u8 | generation u64` then one record per change,
+`length u32 | crc32 u32 | user | working dir | opcode u8 | command payload`.
+A snapshot carries the generation of the log it took in and how far into
+it, so a log left behind by a crash between the two is known to be in it
+already up to there. Fields are encoded like frame payloads.
+ */
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use transport_layer::command::Command;
+use transport_layer::frame::{Frame, ProtocolError};
+use transport_layer::wire::{put_bytes, put_str, put_u32, put_u64, put_u8, Reader};
+
+use crate::snapshot;
+use crate::system::Recover;
+use crate::trie::InodeId;
+
+const MAGIC: &[u8; 4] = b"EPHW";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8;
+// Length and checksum ahead of every record
+const RECORD_HEADER_LEN: usize = 8;
+// Turns files take to be changed, see Log::turn
+const TURNS: usize = 64;
+
+// The log every session writes through
+pub type Journal = Arc<Log>;
+
+// A change is logged before it's made, by whoever holds what orders it
+// against the changes it could conflict with. Changes to a file's contents
+// share the tree and take that file's turn. Shape changes hold the whole
+// tree, or share it holding the entries of the directories they change and
+// the turns of the files they name. Either way the log has conflicting
+// changes in the order they were made, and is only held to append
+#[derive(Debug)]
+pub struct Log {
+    wal: Mutex<Wal>,
+    turns: Vec<Mutex<()>>,
+    saving: Mutex<()>,
+}
+impl Log {
+    pub fn new(wal: Wal) -> Self {
+        Self {
+            wal: Mutex::new(wal),
+            turns: (0..TURNS).map(|_| Mutex::default()).collect(),
+            saving: Mutex::default(),
+        }
+    }
+    // Take the tree first, a save holds both to take its checkpoint
+    pub fn wal(&self) -> MutexGuard<'_, Wal> {
+        self.wal.lock().recover()
+    }
+    // Held through a whole save, so the log is started over from one
+    // checkpoint at a time
+    pub fn saving(&self) -> MutexGuard<'_, ()> {
+        self.saving.lock().recover()
+    }
+    pub fn append(&self, records: &[Record]) -> io::Result<()> {
+        self.wal().append(records)
+    }
+    // Held from logging a change to the contents of file id until it's
+    // made, files share turns but that only makes them wait
+    pub fn turn(&self, id: InodeId) -> MutexGuard<'_, ()> {
+        self.turns[id as usize % TURNS].lock().recover()
+    }
+    // Turns of every file in ids, taken before any directory's entries and
+    // in one order, so two callers can't each wait on the other
+    pub fn turns(&self, ids: &[InodeId]) -> Vec<MutexGuard<'_, ()>> {
+        let mut turns = ids
+            .iter()
+            .map(|id| *id as usize % TURNS)
+            .collect::<Vec<_>>();
+        turns.sort_unstable();
+        turns.dedup();
+        turns
+            .into_iter()
+            .map(|turn| self.turns[turn].lock().recover())
+            .collect()
+    }
+}
+
+// A change as the session that made it saw it, relative paths name files
+// under dir
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub user: String,
+    pub dir: PathBuf,
+    pub command: Command,
+}
+impl Record {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_str(&mut out, &self.user);
+        put_bytes(&mut out, self.dir.as_os_str().as_bytes());
+        let frame = self.command.to_frame();
+        put_u8(&mut out, frame.opcode);
+        put_bytes(&mut out, &frame.payload);
+        out
+    }
+    fn decode(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(bytes);
+        let user = reader.string()?;
+        let dir = PathBuf::from(OsStr::from_bytes(&reader.bytes()?));
+        let opcode = reader.u8()?;
+        let command = Command::from_frame(&Frame::new(opcode, reader.bytes()?))?;
+        if !reader.is_empty() {
+            return Err(ProtocolError::Malformed("trailing bytes in record"));
+        }
+        Ok(Self { user, dir, command })
+    }
+}
+
+// Where a snapshot leaves off, the log it took in and how far into it.
+// Changes logged while it was being saved come after end
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Checkpoint {
+    // Of the log that starts where the snapshot ends
+    pub generation: u64,
+    // Bytes of the log before it the snapshot took in
+    pub end: u64,
+}
+impl Checkpoint {
+    // A snapshot that took in the whole log before it
+    pub fn whole(generation: u64) -> Self {
+        Self {
+            generation,
+            end: u64::MAX,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Wal {
+    file: File,
+    path: PathBuf,
+    generation: u64,
+    // Where the last whole record ends
+    len: u64,
+    // Set when a snapshot was saved but the log couldn't be started over,
+    // or records that failed to append couldn't be cut back off. The next
+    // start would drop or replay them, so nothing more may go in it
+    stale: bool,
+}
+impl Wal {
+    // Opens the log at path for a snapshot ending at checkpoint and returns
+    // the records to replay on top of it. A record cut short or failing its
+    // checksum is where a crash stopped a write, it and anything after it
+    // are cut off. One that checks out but can't be read is an error, the
+    // changes after it were acknowledged
+    pub fn open(path: &Path, checkpoint: Checkpoint) -> io::Result<(Self, Vec<Record>)> {
+        let generation = checkpoint.generation;
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok((Self::create(path, generation, &[])?, Vec::new()))
+            }
+            Err(err) => return Err(err),
+        };
+        let logged = header(&bytes)?;
+        if logged > generation {
+            return Err(invalid(&format!(
+                "log generation {logged} is newer than the snapshot's {generation}"
+            )));
+        }
+        if logged < generation {
+            // The snapshot took it in up to the checkpoint, whatever came
+            // after is carried over like a save would have
+            let start = if logged + 1 == generation {
+                usize::try_from(checkpoint.end).unwrap_or(usize::MAX)
+            } else {
+                usize::MAX
+            };
+            let (records, end) = records(&bytes, start)?;
+            let kept = bytes.get(start..end).unwrap_or_default();
+            return Ok((Self::create(path, generation, kept)?, records));
+        }
+        let (records, end) = records(&bytes, HEADER_LEN)?;
+        if end < bytes.len() {
+            println!(
+                "dropping {} bytes of torn log at the end of {}",
+                bytes.len() - end,
+                path.display()
+            );
+        }
+        let file = OpenOptions::new().append(true).open(path)?;
+        file.set_len(end as u64)?;
+        file.sync_all()?;
+        let wal = Self {
+            file,
+            path: path.to_path_buf(),
+            generation,
+            len: end as u64,
+            stale: false,
+        };
+        Ok((wal, records))
+    }
+    // A log holding the already encoded records, written aside and renamed
+    // into place like a snapshot
+    fn create(path: &Path, generation: u64, records: &[u8]) -> io::Result<Self> {
+        let mut bytes = MAGIC.to_vec();
+        put_u8(&mut bytes, VERSION);
+        put_u64(&mut bytes, generation);
+        bytes.extend_from_slice(records);
+        snapshot::write(path, &bytes)?;
+        Ok(Self {
+            file: OpenOptions::new().append(true).open(path)?,
+            path: path.to_path_buf(),
+            generation,
+            len: bytes.len() as u64,
+            stale: false,
+        })
+    }
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+    // Where the next record goes, a checkpoint taken now ends here
+    pub fn end(&self) -> u64 {
+        self.len
+    }
+    // Returns once the records are on disk. When they can't all be, none
+    // of them are kept, the changes they log mustn't be made
+    pub fn append(&mut self, records: &[Record]) -> io::Result<()> {
+        if self.stale {
+            return Err(io::Error::other("log is stale until the next save"));
+        }
+        let mut out = Vec::new();
+        for record in records {
+            let payload = record.encode();
+            put_u32(&mut out, payload.len() as u32);
+            put_u32(&mut out, crc32(&payload));
+            out.extend_from_slice(&payload);
+        }
+        let written = self
+            .file
+            .write_all(&out)
+            .and_then(|_| self.file.sync_data());
+        match written {
+            Ok(()) => {
+                self.len += out.len() as u64;
+                Ok(())
+            }
+            Err(err) => {
+                // Left in the log they would be replayed on the next start
+                if self.file.set_len(self.len).is_err() {
+                    self.stale = true;
+                }
+                Err(err)
+            }
+        }
+    }
+    // Starts the log over once a snapshot ending at checkpoint is saved,
+    // keeping only what was logged after it
+    pub fn compact(&mut self, checkpoint: Checkpoint) -> io::Result<()> {
+        let start = checkpoint.end.min(self.len) as usize;
+        let created = std::fs::read(&self.path).and_then(|bytes| {
+            let kept = bytes.get(start..self.len as usize).unwrap_or_default();
+            Self::create(&self.path, checkpoint.generation, kept)
+        });
+        match created {
+            Ok(wal) => {
+                *self = wal;
+                Ok(())
+            }
+            Err(err) => {
+                self.stale = true;
+                Err(err)
+            }
+        }
+    }
+}
+
+// The log kept beside data_file
+pub fn path_for(data_file: &Path) -> PathBuf {
+    let mut path = data_file.as_os_str().to_owned();
+    path.push(".wal");
+    PathBuf::from(path)
+}
+
+// Generation of the log in bytes
+fn header(bytes: &[u8]) -> io::Result<u64> {
+    let Some(bytes) = bytes.strip_prefix(MAGIC) else {
+        return Err(invalid("not a log"));
+    };
+    let mut reader = Reader::new(bytes);
+    let version = reader.u8().map_err(|_| invalid("not a log"))?;
+    if version != VERSION {
+        return Err(invalid(&format!("log version {version} unsupported")));
+    }
+    reader.u64().map_err(|_| invalid("not a log"))
+}
+
+// Whole records in the log from byte start on and where the last of them
+// ends, start past the end of the log finds none
+fn records(bytes: &[u8], start: usize) -> io::Result<(Vec<Record>, usize)> {
+    let mut records = Vec::new();
+    let mut end = start.min(bytes.len());
+    while let Some(payload) = payload(&bytes[end..]) {
+        let record = Record::decode(payload)
+            .map_err(|err| invalid(&format!("record at byte {end} unreadable: {err}")))?;
+        records.push(record);
+        end += RECORD_HEADER_LEN + payload.len();
+    }
+    Ok((records, end))
+}
+
+// Payload of the record at the start of bytes, none when it isn't all
+// there or doesn't match its checksum
+fn payload(bytes: &[u8]) -> Option<&[u8]> {
+    let mut reader = Reader::new(bytes);
+    let len = reader.u32().ok()? as usize;
+    let checksum = reader.u32().ok()?;
+    let payload = bytes.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)?;
+    (crc32(payload) == checksum).then_some(payload)
+}
+
+// CRC-32 as zip and ethernet compute it
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc = CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn invalid(why: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, why.to_string())
+}
@@ -1 +1 @@
-bash Ephie/target/release/ephied
\ No newline at end of file
+bash Ephie/target/release/ephied "$@"
\ No newline at end of file
@@ -1,22 +1,624 @@
-// TODO send writes as a 3 tuple instead
-pub const WRITE_DELIM: &str = "~%%~";
-#[derive(Debug, PartialEq, PartialOrd)]
+use crate::frame::{Frame, ProtocolError, MAX_PAYLOAD_LEN};
+use crate::response::NodeKind;
+use crate::wire::{put_bytes, put_str, put_u32, put_u64, put_u8, Reader};
+
+// Size of each piece a large file is streamed in, well under MAX_PAYLOAD_LEN
+pub const CHUNK_SIZE: usize = 64 * 1024;
+// Most bytes one ranged read returns, leaving room in the frame for the
+// rest of its reply
+pub const MAX_READ_LEN: u32 = MAX_PAYLOAD_LEN - 1024;
+
+// Flags of `ls`, sent as one bit each. Entries sort by name unless by_time
+// (newest first) or by_size (largest first) is set
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
+pub struct ListOptions {
+    // -a, include dot files along with . and ..
+    pub all: bool,
+    // -l
+    pub long: bool,
+    // -R, descend into subdirectories
+    pub recursive: bool,
+    // -t
+    pub by_time: bool,
+    // -S
+    pub by_size: bool,
+}
+impl ListOptions {
+    fn to_flags(self) -> u8 {
+        [
+            self.all,
+            self.long,
+            self.recursive,
+            self.by_time,
+            self.by_size,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |flags, (bit, set)| flags | ((*set as u8) << bit))
+    }
+    fn from_flags(flags: u8) -> Self {
+        let set = |bit: u8| flags & (1 << bit) != 0;
+        Self {
+            all: set(0),
+            long: set(1),
+            recursive: set(2),
+            by_time: set(3),
+            by_size: set(4),
+        }
+    }
+}
+
+// Arguments of `ls` as typed, flags may be bundled like `-la`. Anything not
+// starting with `-` is the path, None if a flag isn't known
+fn parse_ls(args: &str) -> Option<Command> {
+    let mut options = ListOptions::default();
+    let mut path = String::new();
+    for arg in args.split_whitespace() {
+        let Some(flags) = arg.strip_prefix('-') else {
+            path = arg.to_string();
+            continue;
+        };
+        for flag in flags.chars() {
+            match flag {
+                'a' => options.all = true,
+                'l' => options.long = true,
+                'R' => options.recursive = true,
+                't' => options.by_time = true,
+                'S' => options.by_size = true,
+                _ => return None,
+            }
+        }
+    }
+    Some(Command::LS { path, options })
+}
+
+// Numeric test of `find`, `+N` is more than N, `-N` less than N
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Compare {
+    Above(u64),
+    Below(u64),
+    Exactly(u64),
+}
+impl Compare {
+    pub fn test(&self, value: u64) -> bool {
+        match *self {
+            Self::Above(bound) => value > bound,
+            Self::Below(bound) => value < bound,
+            Self::Exactly(bound) => value == bound,
+        }
+    }
+    // Number with an optional sign, then an optional k, M or G suffix
+    fn parse(arg: &str) -> Option<Self> {
+        let (digits, unit) = match arg.chars().last()? {
+            'k' => (&arg[..arg.len() - 1], 1024),
+            'M' => (&arg[..arg.len() - 1], 1024 * 1024),
+            'G' => (&arg[..arg.len() - 1], 1024 * 1024 * 1024),
+            _ => (arg, 1),
+        };
+        let parse = |digits: &str| digits.parse::<u64>().ok()?.checked_mul(unit);
+        if let Some(rest) = digits.strip_prefix('+') {
+            Some(Self::Above(parse(rest)?))
+        } else if let Some(rest) = digits.strip_prefix('-') {
+            Some(Self::Below(parse(rest)?))
+        } else {
+            Some(Self::Exactly(parse(digits)?))
+        }
+    }
+    fn encode(compare: &Option<Self>, out: &mut Vec<u8>) {
+        let (tag, bound) = match compare {
+            None => (0, 0),
+            Some(Self::Above(bound)) => (1, *bound),
+            Some(Self::Below(bound)) => (2, *bound),
+            Some(Self::Exactly(bound)) => (3, *bound),
+        };
+        put_u8(out, tag);
+        put_u64(out, bound);
+    }
+    fn decode(reader: &mut Reader) -> Result<Option<Self>, ProtocolError> {
+        let tag = reader.u8()?;
+        let bound = reader.u64()?;
+        match tag {
+            0 => Ok(None),
+            1 => Ok(Some(Self::Above(bound))),
+            2 => Ok(Some(Self::Below(bound))),
+            3 => Ok(Some(Self::Exactly(bound))),
+            _ => Err(ProtocolError::Malformed("unknown comparison")),
+        }
+    }
+}
+
+// Predicates of `find`, every one that is set must hold for a match.
+// Size is in bytes and mtime in whole days since the last change
+#[derive(Debug, Default, Clone, PartialEq, PartialOrd)]
+pub struct FindQuery {
+    // Glob on the entry's own name
+    pub name: Option<String>,
+    pub kind: Option<NodeKind>,
+    pub size: Option<Compare>,
+    pub mtime: Option<Compare>,
+    pub max_depth: Option<u32>,
+    // Removes every match, deepest first
+    pub delete: bool,
+}
+impl FindQuery {
+    fn encode(&self, out: &mut Vec<u8>) {
+        put_u8(out, self.name.is_some() as u8);
+        put_str(out, self.name.as_deref().unwrap_or_default());
+        put_u8(out, self.kind.map_or(u8::MAX, |kind| kind.code()));
+        Compare::encode(&self.size, out);
+        Compare::encode(&self.mtime, out);
+        put_u8(out, self.max_depth.is_some() as u8);
+        put_u32(out, self.max_depth.unwrap_or_default());
+        put_u8(out, self.delete as u8);
+    }
+    fn decode(reader: &mut Reader) -> Result<Self, ProtocolError> {
+        let has_name = reader.u8()? != 0;
+        let name = reader.string()?;
+        let kind = match reader.u8()? {
+            u8::MAX => None,
+            code => Some(NodeKind::from_code(code)?),
+        };
+        let size = Compare::decode(reader)?;
+        let mtime = Compare::decode(reader)?;
+        let has_max_depth = reader.u8()? != 0;
+        let max_depth = reader.u32()?;
+        Ok(Self {
+            name: has_name.then_some(name),
+            kind,
+            size,
+            mtime,
+            max_depth: has_max_depth.then_some(max_depth),
+            delete: reader.u8()? != 0,
+        })
+    }
+}
+
+// Arguments of `find` as typed, a root followed by predicates. Without a
+// root the search starts in the working directory
+fn parse_find(args: &str) -> Option<Command> {
+    let mut args = args.split_whitespace().peekable();
+    let root = match args.peek() {
+        Some(arg) if !arg.starts_with('-') => args.next()?.to_string(),
+        _ => String::new(),
+    };
+    let mut query = FindQuery::default();
+    while let Some(arg) = args.next() {
+        match arg {
+            "-name" => query.name = Some(args.next()?.to_string()),
+            "-type" => {
+                query.kind = Some(match args.next()? {
+                    "f" => NodeKind::File,
+                    "d" => NodeKind::Directory,
+                    "l" => NodeKind::Symlink,
+                    _ => return None,
+                })
+            }
+            "-size" => query.size = Some(Compare::parse(args.next()?)?),
+            "-mtime" => query.mtime = Some(Compare::parse(args.next()?)?),
+            "-maxdepth" => query.max_depth = Some(args.next()?.parse().ok()?),
+            "-delete" => query.delete = true,
+            _ => return None,
+        }
+    }
+    Some(Command::FIND { root, query })
+}
+
+// Flags of `grep`, sent as one bit each like those of `ls`
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
+pub struct GrepOptions {
+    // -r, search every file below a directory
+    pub recursive: bool,
+    // -i
+    pub ignore_case: bool,
+    // -n, prefix each match with its line number
+    pub line_numbers: bool,
+}
+impl GrepOptions {
+    fn to_flags(self) -> u8 {
+        [self.recursive, self.ignore_case, self.line_numbers]
+            .iter()
+            .enumerate()
+            .fold(0, |flags, (bit, set)| flags | ((*set as u8) << bit))
+    }
+    fn from_flags(flags: u8) -> Self {
+        let set = |bit: u8| flags & (1 << bit) != 0;
+        Self {
+            recursive: set(0),
+            ignore_case: set(1),
+            line_numbers: set(2),
+        }
+    }
+}
+
+// Arguments of `grep` as typed, flags come before the pattern and may be
+// bundled like `-rn`. Without a path the files in the working directory
+// are searched
+fn parse_grep(args: &str) -> Option<Command> {
+    let mut options = GrepOptions::default();
+    let mut args = args.split_whitespace().peekable();
+    while let Some(flags) = args.peek().and_then(|arg| arg.strip_prefix('-')) {
+        for flag in flags.chars() {
+            match flag {
+                'r' => options.recursive = true,
+                'i' => options.ignore_case = true,
+                'n' => options.line_numbers = true,
+                _ => return None,
+            }
+        }
+        args.next();
+    }
+    let pattern = args.next()?.to_string();
+    let path = args.next().unwrap_or_default().to_string();
+    if args.next().is_some() {
+        return None;
+    }
+    Some(Command::GREP {
+        pattern,
+        path,
+        options,
+    })
+}
+
+// `echo <text> >> <path>`, appending text and a newline the way a shell would
+fn parse_echo(args: &str) -> Option<Command> {
+    let (text, path) = args.rsplit_once(">>")?;
+    let path = path.trim();
+    if path.is_empty() || path.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(Command::APPEND {
+        path: path.to_string(),
+        data: format!("{}\n", text.trim()).into_bytes(),
+    })
+}
+
+// How a handle is opened, one bit each on the wire. Reading or writing
+// through a handle opened without the matching flag fails
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    // Make the file if it's missing
+    pub create: bool,
+    // Empty the file as it's opened
+    pub truncate: bool,
+    // Every write goes to the end of the file
+    pub append: bool,
+}
+impl OpenOptions {
+    // Options of an fopen style mode, None if it isn't one
+    pub fn from_mode(mode: &str) -> Option<Self> {
+        let (read, write) = (true, true);
+        let options = match mode {
+            "r" => Self {
+                read,
+                ..Self::default()
+            },
+            "r+" => Self {
+                read,
+                write,
+                ..Self::default()
+            },
+            "w" | "w+" => Self {
+                read: mode == "w+",
+                write,
+                create: true,
+                truncate: true,
+                ..Self::default()
+            },
+            "a" | "a+" => Self {
+                read: mode == "a+",
+                write,
+                create: true,
+                append: true,
+                ..Self::default()
+            },
+            _ => return None,
+        };
+        Some(options)
+    }
+    fn to_flags(self) -> u8 {
+        [
+            self.read,
+            self.write,
+            self.create,
+            self.truncate,
+            self.append,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |flags, (bit, set)| flags | ((*set as u8) << bit))
+    }
+    fn from_flags(flags: u8) -> Self {
+        let set = |bit: u8| flags & (1 << bit) != 0;
+        Self {
+            read: set(0),
+            write: set(1),
+            create: set(2),
+            truncate: set(3),
+            append: set(4),
+        }
+    }
+}
+
+// What a seek offset counts from, as in lseek
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Whence {
+    Start,
+    Current,
+    End,
+}
+impl Whence {
+    fn code(self) -> u8 {
+        match self {
+            Self::Start => 0,
+            Self::Current => 1,
+            Self::End => 2,
+        }
+    }
+    fn from_code(code: u8) -> Result<Self, ProtocolError> {
+        match code {
+            0 => Ok(Self::Start),
+            1 => Ok(Self::Current),
+            2 => Ok(Self::End),
+            _ => Err(ProtocolError::Malformed("unknown seek origin")),
+        }
+    }
+}
+
+// `seek <handle> <offset> [set|cur|end]`, counting from the start by default
+fn parse_seek(handle: &str, args: &str) -> Option<Command> {
+    let mut args = args.split_whitespace();
+    let offset = args.next()?.parse().ok()?;
+    let whence = match args.next() {
+        None | Some("set") => Whence::Start,
+        Some("cur") => Whence::Current,
+        Some("end") => Whence::End,
+        Some(..) => return None,
+    };
+    if args.next().is_some() {
+        return None;
+    }
+    Some(Command::SEEK {
+        handle: handle.parse().ok()?,
+        offset,
+        whence,
+    })
+}
+
+// Advisory lock modes, any number of shared holders or one exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+pub enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+// What a lock is taken on, either way it's the file and not its name
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum LockTarget {
+    Path(String),
+    Handle(u32),
+}
+impl LockTarget {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::Path(path) => {
+                put_u8(out, 0);
+                put_str(out, path);
+            }
+            Self::Handle(handle) => {
+                put_u8(out, 1);
+                put_u32(out, *handle);
+            }
+        }
+    }
+    fn decode(reader: &mut Reader) -> Result<Self, ProtocolError> {
+        match reader.u8()? {
+            0 => Ok(Self::Path(reader.string()?)),
+            1 => Ok(Self::Handle(reader.u32()?)),
+            _ => Err(ProtocolError::Malformed("unknown lock target")),
+        }
+    }
+}
+
+// `lock [-s|-x] [-n] [-w <secs>] <path>` and `unlock <path>`, like flock(1)
+// exclusive unless -s. `-f <handle>` names an open handle instead of a path
+fn parse_lock(args: &str, unlock: bool) -> Option<Command> {
+    let mut kind = LockKind::Exclusive;
+    let mut wait_ms = None;
+    let mut target = None;
+    let mut args = args.split_whitespace();
+    while let Some(arg) = args.next() {
+        match arg {
+            "-s" if !unlock => kind = LockKind::Shared,
+            "-x" if !unlock => kind = LockKind::Exclusive,
+            "-n" if !unlock => wait_ms = Some(0),
+            "-w" if !unlock => {
+                let secs = args.next()?.parse::<f64>().ok()?;
+                if !(0.0..=u32::MAX as f64 / 1000.0).contains(&secs) {
+                    return None;
+                }
+                wait_ms = Some((secs * 1000.0) as u32);
+            }
+            "-f" => target = Some(LockTarget::Handle(args.next()?.parse().ok()?)),
+            _ if arg.starts_with('-') => return None,
+            path => target = Some(LockTarget::Path(path.to_string())),
+        }
+    }
+    let target = target?;
+    if unlock {
+        return Some(Command::UNLOCK(target));
+    }
+    Some(Command::LOCK {
+        target,
+        kind,
+        wait_ms,
+    })
+}
+
+// What `snapshot` does, every action but list names a snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+pub enum SnapshotAction {
+    Create,
+    List,
+    Delete,
+    Restore,
+}
+impl SnapshotAction {
+    fn code(self) -> u8 {
+        match self {
+            Self::Create => 0,
+            Self::List => 1,
+            Self::Delete => 2,
+            Self::Restore => 3,
+        }
+    }
+    fn from_code(code: u8) -> Result<Self, ProtocolError> {
+        match code {
+            0 => Ok(Self::Create),
+            1 => Ok(Self::List),
+            2 => Ok(Self::Delete),
+            3 => Ok(Self::Restore),
+            _ => Err(ProtocolError::Malformed("unknown snapshot action")),
+        }
+    }
+}
+
+// `snapshot create|delete|restore <name>` and `snapshot list`
+fn parse_snapshot(action: &str, name: &str) -> Option<Command> {
+    let name = name.trim();
+    let action = match action {
+        "list" if name.is_empty() => SnapshotAction::List,
+        "create" => SnapshotAction::Create,
+        "delete" => SnapshotAction::Delete,
+        "restore" => SnapshotAction::Restore,
+        _ => return None,
+    };
+    if action != SnapshotAction::List && (name.is_empty() || name.contains(char::is_whitespace)) {
+        return None;
+    }
+    Some(Command::SNAPSHOT {
+        action,
+        name: name.to_string(),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Command {
     // Place holder for serialization
     UNKNOWN,
     CD(String),
     MKDIR(String),
-    LS,
+    // An empty path lists the working directory
+    LS {
+        path: String,
+        options: ListOptions,
+    },
     PWD,
     WHO,
     RM(String),
     TOUCH(String),
     READ(String),
-    WRITE(String),
-    FIND(String),
-    CP(String),
-    MV(String),
+    WRITE {
+        path: String,
+        data: Vec<u8>,
+    },
+    // Walks down from root, links are not followed
+    FIND {
+        root: String,
+        query: FindQuery,
+    },
+    CP {
+        src: String,
+        dst: String,
+        recursive: bool,
+    },
+    MV {
+        src: String,
+        dst: String,
+    },
     SU(String),
+    // Chunked transfers: UPLOAD opens a transfer into a path, CHUNKs land at
+    // their offset in any order and COMMIT closes it. DOWNLOAD reads a range
+    UPLOAD(String),
+    CHUNK {
+        transfer: u32,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    COMMIT(u32),
+    DOWNLOAD {
+        path: String,
+        offset: u64,
+        len: u32,
+    },
+    STAT(String),
+    // `ln -s target link`, target is stored as given and not checked
+    SYMLINK {
+        target: String,
+        link: String,
+    },
+    READLINK(String),
+    // `ln target link`, a hard link sharing target's inode
+    LINK {
+        target: String,
+        link: String,
+    },
+    // Lines of the files at path matching the regex pattern
+    GREP {
+        pattern: String,
+        path: String,
+        options: GrepOptions,
+    },
+    // Writes that keep what is already in the file. A missing file is
+    // created first, like the shell's `>>` and GNU truncate do
+    APPEND {
+        path: String,
+        data: Vec<u8>,
+    },
+    TRUNCATE {
+        path: String,
+        size: u64,
+    },
+    // Writes data at offset, zero filling any gap past the end
+    PWRITE {
+        path: String,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    // Open handles belong to the connection that opened them. They follow
+    // the file, not the name, so a rename doesn't disturb them
+    OPEN {
+        path: String,
+        options: OpenOptions,
+    },
+    FDREAD {
+        handle: u32,
+        len: u32,
+    },
+    FDWRITE {
+        handle: u32,
+        data: Vec<u8>,
+    },
+    SEEK {
+        handle: u32,
+        offset: i64,
+        whence: Whence,
+    },
+    CLOSE(u32),
+    // Advisory locks held by the connection until unlocked or it goes away.
+    // Without wait_ms the server waits as long as it allows
+    LOCK {
+        target: LockTarget,
+        kind: LockKind,
+        wait_ms: Option<u32>,
+    },
+    UNLOCK(LockTarget),
+    // Named copies of the whole tree, each readable under /.snapshots/<name>
+    SNAPSHOT {
+        action: SnapshotAction,
+        name: String,
+    },
 }
 impl Command {
     pub fn opt_code(&self) -> u8 {
@@ -25,49 +627,262 @@ impl Command {
             Self::CD(..) => 1,
             Self::MKDIR(..) => 2,
             Self::PWD => 3,
-            Self::LS => 4,
+            Self::LS { .. } => 4,
             Self::WHO => 5,
             Self::RM(..) => 6,
             Self::TOUCH(..) => 7,
             Self::READ(..) => 8,
-            Self::WRITE(..) => 9,
-            Self::FIND(..) => 10,
-            Self::CP(..) => 11,
-            Self::MV(..) => 12,
+            Self::WRITE { .. } => 9,
+            Self::FIND { .. } => 10,
+            Self::CP { .. } => 11,
+            Self::MV { .. } => 12,
             Self::SU(..) => 13,
+            Self::UPLOAD(..) => 14,
+            Self::CHUNK { .. } => 15,
+            Self::COMMIT(..) => 16,
+            Self::DOWNLOAD { .. } => 17,
+            Self::STAT(..) => 18,
+            Self::SYMLINK { .. } => 19,
+            Self::READLINK(..) => 20,
+            Self::LINK { .. } => 21,
+            Self::GREP { .. } => 22,
+            Self::APPEND { .. } => 23,
+            Self::TRUNCATE { .. } => 24,
+            Self::PWRITE { .. } => 25,
+            Self::OPEN { .. } => 26,
+            Self::FDREAD { .. } => 27,
+            Self::FDWRITE { .. } => 28,
+            Self::SEEK { .. } => 29,
+            Self::CLOSE(..) => 30,
+            Self::LOCK { .. } => 31,
+            Self::UNLOCK(..) => 32,
+            Self::SNAPSHOT { .. } => 33,
         }
     }
-    // Bytes sent on the wire
-    // Format opt<u8>payloadlen<usize>payload
-    pub fn to_bytes(&self, user_id: u8) -> Vec<u8> {
+    // Frame sent on the wire, the opt code rides in the header and the
+    // arguments are the payload, each as a length prefixed field so file
+    // contents can hold any bytes
+    pub fn to_frame(&self) -> Frame {
         let mut payload = Vec::new();
-        payload.push(user_id);
         match self {
-            Self::LS => {
-                payload.push(self.opt_code());
-                payload.push(0u8);
-            }
-            Self::PWD | Self::WHO => {
-                payload.push(self.opt_code());
-                payload.push(0u8);
-            }
             Self::TOUCH(target)
             | Self::RM(target)
             | Self::MKDIR(target)
             | Self::CD(target)
             | Self::READ(target)
-            | Self::WRITE(target)
-            | Self::FIND(target)
-            | Self::CP(target)
-            | Self::MV(target) => {
-                payload.push(self.opt_code());
-                payload.push(target.len().try_into().unwrap());
-                payload.extend(target.as_bytes().into_iter().clone());
+            | Self::SU(target)
+            | Self::UPLOAD(target)
+            | Self::STAT(target)
+            | Self::READLINK(target) => put_str(&mut payload, target),
+            Self::LS { path, options } => {
+                put_str(&mut payload, path);
+                put_u8(&mut payload, options.to_flags());
             }
-
-            Self::UNKNOWN | Self::SU(..) => {}
+            Self::WRITE { path, data } | Self::APPEND { path, data } => {
+                put_str(&mut payload, path);
+                put_bytes(&mut payload, data);
+            }
+            Self::TRUNCATE { path, size } => {
+                put_str(&mut payload, path);
+                put_u64(&mut payload, *size);
+            }
+            Self::PWRITE { path, offset, data } => {
+                put_str(&mut payload, path);
+                put_u64(&mut payload, *offset);
+                put_bytes(&mut payload, data);
+            }
+            Self::FIND { root, query } => {
+                put_str(&mut payload, root);
+                query.encode(&mut payload);
+            }
+            Self::CP {
+                src,
+                dst,
+                recursive,
+            } => {
+                put_str(&mut payload, src);
+                put_str(&mut payload, dst);
+                put_u8(&mut payload, *recursive as u8);
+            }
+            Self::MV { src, dst }
+            | Self::SYMLINK {
+                target: src,
+                link: dst,
+            }
+            | Self::LINK {
+                target: src,
+                link: dst,
+            } => {
+                put_str(&mut payload, src);
+                put_str(&mut payload, dst);
+            }
+            Self::GREP {
+                pattern,
+                path,
+                options,
+            } => {
+                put_str(&mut payload, pattern);
+                put_str(&mut payload, path);
+                put_u8(&mut payload, options.to_flags());
+            }
+            Self::CHUNK {
+                transfer,
+                offset,
+                data,
+            } => {
+                put_u32(&mut payload, *transfer);
+                put_u64(&mut payload, *offset);
+                put_bytes(&mut payload, data);
+            }
+            Self::COMMIT(transfer) | Self::CLOSE(transfer) => put_u32(&mut payload, *transfer),
+            Self::LOCK {
+                target,
+                kind,
+                wait_ms,
+            } => {
+                target.encode(&mut payload);
+                put_u8(&mut payload, (*kind == LockKind::Exclusive) as u8);
+                put_u8(&mut payload, wait_ms.is_some() as u8);
+                put_u32(&mut payload, wait_ms.unwrap_or_default());
+            }
+            Self::UNLOCK(target) => target.encode(&mut payload),
+            Self::SNAPSHOT { action, name } => {
+                put_u8(&mut payload, action.code());
+                put_str(&mut payload, name);
+            }
+            Self::OPEN { path, options } => {
+                put_str(&mut payload, path);
+                put_u8(&mut payload, options.to_flags());
+            }
+            Self::FDREAD { handle, len } => {
+                put_u32(&mut payload, *handle);
+                put_u32(&mut payload, *len);
+            }
+            Self::FDWRITE { handle, data } => {
+                put_u32(&mut payload, *handle);
+                put_bytes(&mut payload, data);
+            }
+            Self::SEEK {
+                handle,
+                offset,
+                whence,
+            } => {
+                put_u32(&mut payload, *handle);
+                // Two's complement, read back as signed
+                put_u64(&mut payload, *offset as u64);
+                put_u8(&mut payload, whence.code());
+            }
+            Self::DOWNLOAD { path, offset, len } => {
+                put_str(&mut payload, path);
+                put_u64(&mut payload, *offset);
+                put_u32(&mut payload, *len);
+            }
+            Self::PWD | Self::WHO | Self::UNKNOWN => {}
+        };
+        Frame::new(self.opt_code(), payload)
+    }
+    pub fn from_frame(frame: &Frame) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(&frame.payload);
+        let command = match frame.opcode {
+            9 => Command::WRITE {
+                path: reader.string()?,
+                data: reader.bytes()?,
+            },
+            11 => Command::CP {
+                src: reader.string()?,
+                dst: reader.string()?,
+                recursive: reader.u8()? != 0,
+            },
+            12 => Command::MV {
+                src: reader.string()?,
+                dst: reader.string()?,
+            },
+            15 => Command::CHUNK {
+                transfer: reader.u32()?,
+                offset: reader.u64()?,
+                data: reader.bytes()?,
+            },
+            16 => Command::COMMIT(reader.u32()?),
+            26 => Command::OPEN {
+                path: reader.string()?,
+                options: OpenOptions::from_flags(reader.u8()?),
+            },
+            27 => Command::FDREAD {
+                handle: reader.u32()?,
+                len: reader.u32()?,
+            },
+            28 => Command::FDWRITE {
+                handle: reader.u32()?,
+                data: reader.bytes()?,
+            },
+            29 => Command::SEEK {
+                handle: reader.u32()?,
+                offset: reader.u64()? as i64,
+                whence: Whence::from_code(reader.u8()?)?,
+            },
+            30 => Command::CLOSE(reader.u32()?),
+            31 => Command::LOCK {
+                target: LockTarget::decode(&mut reader)?,
+                kind: match reader.u8()? {
+                    0 => LockKind::Shared,
+                    _ => LockKind::Exclusive,
+                },
+                wait_ms: match (reader.u8()?, reader.u32()?) {
+                    (0, _) => None,
+                    (_, wait_ms) => Some(wait_ms),
+                },
+            },
+            32 => Command::UNLOCK(LockTarget::decode(&mut reader)?),
+            33 => Command::SNAPSHOT {
+                action: SnapshotAction::from_code(reader.u8()?)?,
+                name: reader.string()?,
+            },
+            17 => Command::DOWNLOAD {
+                path: reader.string()?,
+                offset: reader.u64()?,
+                len: reader.u32()?,
+            },
+            4 => Command::LS {
+                path: reader.string()?,
+                options: ListOptions::from_flags(reader.u8()?),
+            },
+            19 => Command::SYMLINK {
+                target: reader.string()?,
+                link: reader.string()?,
+            },
+            21 => Command::LINK {
+                target: reader.string()?,
+                link: reader.string()?,
+            },
+            10 => Command::FIND {
+                root: reader.string()?,
+                query: FindQuery::decode(&mut reader)?,
+            },
+            23 => Command::APPEND {
+                path: reader.string()?,
+                data: reader.bytes()?,
+            },
+            24 => Command::TRUNCATE {
+                path: reader.string()?,
+                size: reader.u64()?,
+            },
+            25 => Command::PWRITE {
+                path: reader.string()?,
+                offset: reader.u64()?,
+                data: reader.bytes()?,
+            },
+            22 => Command::GREP {
+                pattern: reader.string()?,
+                path: reader.string()?,
+                options: GrepOptions::from_flags(reader.u8()?),
+            },
+            1 | 2 | 6 | 7 | 8 | 14 | 18 | 20 => Command::from((frame.opcode, reader.string()?)),
+            opcode => Command::from((opcode, String::new())),
         };
-        return payload;
+        if !reader.is_empty() {
+            return Err(ProtocolError::Malformed("trailing bytes after arguments"));
+        }
+        Ok(command)
     }
 }
 impl From<(&str, &str)> for Command {
@@ -75,15 +890,143 @@ impl From<(&str, &str)> for Command {
         match value.0 {
             "cd" => Command::CD(value.1.to_string()),
             "mkdir" => Command::MKDIR(value.1.to_string()),
-            "ls" => Command::LS,
+            "ls" => parse_ls(value.1).unwrap_or(Command::UNKNOWN),
             "rm" => Command::RM(value.1.to_string()),
             "read" => Command::READ(value.1.to_string()),
             "touch" => Command::TOUCH(value.1.to_string()),
-            "write" => Command::WRITE(value.1.to_string()),
-            "find" => Command::FIND(value.1.to_string()),
-            "cp" => Command::CP(value.1.to_string()),
-            "mv" => Command::MV(value.1.to_string()),
+            "find" => parse_find(value.1).unwrap_or(Command::UNKNOWN),
+            "grep" => parse_grep(value.1).unwrap_or(Command::UNKNOWN),
+            "echo" => parse_echo(value.1).unwrap_or(Command::UNKNOWN),
+            "lock" => parse_lock(value.1, false).unwrap_or(Command::UNKNOWN),
+            "unlock" => parse_lock(value.1, true).unwrap_or(Command::UNKNOWN),
+            "snapshot" => parse_snapshot(value.1, "").unwrap_or(Command::UNKNOWN),
+            "open" => Command::OPEN {
+                path: value.1.to_string(),
+                options: OpenOptions::from_mode("r").unwrap(),
+            },
+            "close" => match value.1.parse() {
+                Ok(handle) => Command::CLOSE(handle),
+                Err(..) => Command::UNKNOWN,
+            },
             "su" => Command::SU(value.1.to_string()),
+            "stat" => Command::STAT(value.1.to_string()),
+            "readlink" => Command::READLINK(value.1.to_string()),
+            _ => Command::UNKNOWN,
+        }
+    }
+}
+impl From<(&str, &str, &str)> for Command {
+    fn from(value: (&str, &str, &str)) -> Self {
+        match value.0 {
+            "write" => Command::WRITE {
+                path: value.1.to_string(),
+                data: value.2.as_bytes().to_vec(),
+            },
+            "ls" => parse_ls(&format!("{} {}", value.1, value.2)).unwrap_or(Command::UNKNOWN),
+            "find" => parse_find(&format!("{} {}", value.1, value.2)).unwrap_or(Command::UNKNOWN),
+            "grep" => parse_grep(&format!("{} {}", value.1, value.2)).unwrap_or(Command::UNKNOWN),
+            "echo" => parse_echo(&format!("{} {}", value.1, value.2)).unwrap_or(Command::UNKNOWN),
+            "open" => match OpenOptions::from_mode(value.2) {
+                Some(options) => Command::OPEN {
+                    path: value.1.to_string(),
+                    options,
+                },
+                None => Command::UNKNOWN,
+            },
+            "fread" => match (value.1.parse(), value.2.parse()) {
+                (Ok(handle), Ok(len)) => Command::FDREAD { handle, len },
+                _ => Command::UNKNOWN,
+            },
+            "fwrite" => match value.1.parse() {
+                Ok(handle) => Command::FDWRITE {
+                    handle,
+                    data: value.2.as_bytes().to_vec(),
+                },
+                Err(..) => Command::UNKNOWN,
+            },
+            "seek" => parse_seek(value.1, value.2).unwrap_or(Command::UNKNOWN),
+            "lock" => {
+                parse_lock(&format!("{} {}", value.1, value.2), false).unwrap_or(Command::UNKNOWN)
+            }
+            "unlock" => {
+                parse_lock(&format!("{} {}", value.1, value.2), true).unwrap_or(Command::UNKNOWN)
+            }
+            "snapshot" => parse_snapshot(value.1, value.2).unwrap_or(Command::UNKNOWN),
+            "append" => Command::APPEND {
+                path: value.1.to_string(),
+                data: value.2.as_bytes().to_vec(),
+            },
+            "truncate" => match value.2.parse() {
+                Ok(size) => Command::TRUNCATE {
+                    path: value.1.to_string(),
+                    size,
+                },
+                Err(..) => Command::UNKNOWN,
+            },
+            "pwrite" => match value
+                .2
+                .split_once(' ')
+                .map(|(offset, text)| (offset.parse(), text))
+            {
+                Some((Ok(offset), text)) => Command::PWRITE {
+                    path: value.1.to_string(),
+                    offset,
+                    data: text.as_bytes().to_vec(),
+                },
+                _ => Command::UNKNOWN,
+            },
+            // Partial reads, `head -c <count> <path>` and `read <path> <offset> <len>`
+            "head" if value.1 == "-c" => match value.2.split_once(' ') {
+                Some((len, path)) => match len.parse() {
+                    Ok(len) => Command::DOWNLOAD {
+                        path: path.trim().to_string(),
+                        offset: 0,
+                        len,
+                    },
+                    Err(..) => Command::UNKNOWN,
+                },
+                None => Command::UNKNOWN,
+            },
+            "read" => match value.2.split_once(' ') {
+                Some((offset, len)) => match (offset.parse(), len.trim().parse()) {
+                    (Ok(offset), Ok(len)) => Command::DOWNLOAD {
+                        path: value.1.to_string(),
+                        offset,
+                        len,
+                    },
+                    _ => Command::UNKNOWN,
+                },
+                None => Command::UNKNOWN,
+            },
+            "cp" if value.1 == "-r" => match value.2.split_once(' ') {
+                Some((src, dst)) => Command::CP {
+                    src: src.to_string(),
+                    dst: dst.trim().to_string(),
+                    recursive: true,
+                },
+                None => Command::UNKNOWN,
+            },
+            "cp" => Command::CP {
+                src: value.1.to_string(),
+                dst: value.2.to_string(),
+                recursive: false,
+            },
+            "mv" => Command::MV {
+                src: value.1.to_string(),
+                dst: value.2.to_string(),
+            },
+            // The client hands over `-s` and the rest of the line
+            "ln" if value.1 == "-s" => match value.2.split_once(' ') {
+                Some((target, link)) => Command::SYMLINK {
+                    target: target.to_string(),
+                    link: link.trim().to_string(),
+                },
+                None => Command::UNKNOWN,
+            },
+            "ln" => Command::LINK {
+                target: value.1.to_string(),
+                link: value.2.to_string(),
+            },
             _ => Command::UNKNOWN,
         }
     }
@@ -93,7 +1036,14 @@ impl From<&str> for Command {
         match value {
             "pwd" => Command::PWD,
             "whoami" => Command::WHO,
-            "ls" => Command::LS,
+            "ls" => Command::LS {
+                path: String::new(),
+                options: ListOptions::default(),
+            },
+            "find" => Command::FIND {
+                root: String::new(),
+                query: FindQuery::default(),
+            },
             _ => Command::UNKNOWN,
         }
     }
@@ -104,15 +1054,21 @@ impl From<(u8, String)> for Command {
             1 => Command::CD(value.1),
             2 => Command::MKDIR(value.1),
             3 => Command::PWD,
-            4 => Command::LS,
+            4 => Command::LS {
+                path: value.1,
+                options: ListOptions::default(),
+            },
             5 => Command::WHO,
             6 => Command::RM(value.1),
             7 => Command::TOUCH(value.1),
             8 => Command::READ(value.1),
-            9 => Command::WRITE(value.1),
-            10 => Command::FIND(value.1),
-            11 => Command::CP(value.1),
-            12 => Command::MV(value.1),
+            10 => Command::FIND {
+                root: value.1,
+                query: FindQuery::default(),
+            },
+            14 => Command::UPLOAD(value.1),
+            18 => Command::STAT(value.1),
+            20 => Command::READLINK(value.1),
             _ => Command::UNKNOWN,
         }
     }
@@ -0,0 +1,181 @@
+/*
+Framing shared by ephied and ephie-client.
+
+Every message on the wire is a frame:
+magic<[u8; 4]>version<u8>opcode<u8>flags<u8>requestid<u32 BE>payloadlen<u32 BE>payload
+The magic and version always lead the header so any future revision can
+still recognise, and cleanly reject, a peer speaking another version.
+Responses echo the request id of the frame they answer, which lets a client
+pipeline requests on one connection and match replies arriving out of order.
+ */
+use std::fmt;
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const MAGIC: [u8; 4] = *b"EPHI";
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 15;
+// Guards the server against allocating whatever a corrupt length claims
+pub const MAX_PAYLOAD_LEN: u32 = 16 * 1024 * 1024;
+
+// Handshake opcodes live at the top of the range, away from commands
+pub const OP_HELLO: u8 = 0xF0;
+pub const OP_REJECT: u8 = 0xF1;
+
+// Set on every frame sent by the server in reply to a request
+pub const FLAG_RESPONSE: u8 = 0b0000_0001;
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    // Peer isn't speaking the framed protocol at all (e.g. a pre-framing client)
+    BadMagic,
+    UnsupportedVersion(u8),
+    PayloadTooLarge(u32),
+    Malformed(&'static str),
+    Rejected(String),
+}
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "io error: {err}"),
+            Self::BadMagic => write!(f, "peer is not speaking the ephie protocol"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported protocol version {version}, expected {PROTOCOL_VERSION}"
+            ),
+            Self::PayloadTooLarge(len) => {
+                write!(f, "payload of {len} bytes exceeds {MAX_PAYLOAD_LEN}")
+            }
+            Self::Malformed(reason) => write!(f, "malformed frame: {reason}"),
+            Self::Rejected(reason) => write!(f, "rejected by server: {reason}"),
+        }
+    }
+}
+impl std::error::Error for ProtocolError {}
+impl From<io::Error> for ProtocolError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub version: u8,
+    pub opcode: u8,
+    pub flags: u8,
+    pub request_id: u32,
+    pub payload: Vec<u8>,
+}
+impl Frame {
+    pub fn new(opcode: u8, payload: Vec<u8>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            opcode,
+            flags: 0,
+            request_id: 0,
+            payload,
+        }
+    }
+    pub fn with_request_id(self, request_id: u32) -> Self {
+        Self { request_id, ..self }
+    }
+    pub fn response(opcode: u8, payload: Vec<u8>) -> Self {
+        Self {
+            flags: FLAG_RESPONSE,
+            ..Self::new(opcode, payload)
+        }
+    }
+    // Opens a connection, binding it to the session of `user_id`
+    pub fn hello(user_id: u8) -> Self {
+        Self::new(OP_HELLO, vec![user_id])
+    }
+    pub fn reject(reason: &str) -> Self {
+        Self::response(OP_REJECT, reason.as_bytes().to_vec())
+    }
+    pub fn is_response(&self) -> bool {
+        self.flags & FLAG_RESPONSE != 0
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        out.extend(MAGIC);
+        out.push(self.version);
+        out.push(self.opcode);
+        out.push(self.flags);
+        out.extend(self.request_id.to_be_bytes());
+        out.extend((self.payload.len() as u32).to_be_bytes());
+        out.extend(&self.payload);
+        out
+    }
+}
+
+// Frames a reply the way clients from before framing expect: len<u8>message
+pub fn legacy_rejection(reason: &str) -> Vec<u8> {
+    let bytes = &reason.as_bytes()[..reason.len().min(u8::MAX as usize)];
+    let mut out = vec![bytes.len() as u8];
+    out.extend(bytes);
+    out
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Frame, ProtocolError> {
+    // Magic is checked byte by byte so a legacy peer that sent fewer bytes
+    // than a header is caught instead of leaving us waiting on read_exact
+    for expected in MAGIC {
+        if reader.read_u8().await? != expected {
+            return Err(ProtocolError::BadMagic);
+        }
+    }
+    // Versions may differ in header length, so nothing past the version
+    // byte is read until we know the peer speaks ours
+    let version = reader.read_u8().await?;
+    if version != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(version));
+    }
+    let mut header = [0u8; HEADER_LEN - MAGIC.len() - 1];
+    reader.read_exact(&mut header).await?;
+    let request_id = u32::from_be_bytes([header[2], header[3], header[4], header[5]]);
+    let len = u32::from_be_bytes([header[6], header[7], header[8], header[9]]);
+    if len > MAX_PAYLOAD_LEN {
+        return Err(ProtocolError::PayloadTooLarge(len));
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    Ok(Frame {
+        version,
+        opcode: header[0],
+        flags: header[1],
+        request_id,
+        payload,
+    })
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    frame: &Frame,
+) -> Result<(), ProtocolError> {
+    if frame.payload.len() > MAX_PAYLOAD_LEN as usize {
+        let len = u32::try_from(frame.payload.len()).unwrap_or(u32::MAX);
+        return Err(ProtocolError::PayloadTooLarge(len));
+    }
+    writer.write_all(&frame.encode()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+// Client half of the version handshake, the server answers a hello with an
+// empty response or a reject carrying the reason
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    user_id: u8,
+) -> Result<(), ProtocolError> {
+    write_frame(stream, &Frame::hello(user_id)).await?;
+    let reply = read_frame(stream).await?;
+    match reply.opcode {
+        OP_HELLO => Ok(()),
+        OP_REJECT => Err(ProtocolError::Rejected(
+            String::from_utf8_lossy(&reply.payload).into_owned(),
+        )),
+        _ => Err(ProtocolError::Malformed("expected handshake reply")),
+    }
+}
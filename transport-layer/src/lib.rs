@@ -1,26 +1,592 @@
 pub mod command;
+pub mod frame;
+pub mod response;
+pub mod wire;
 
 #[cfg(test)]
 mod tests {
-    use crate::command::Command;
+    use crate::command::{
+        Command, Compare, FindQuery, GrepOptions, ListOptions, LockKind, LockTarget, OpenOptions,
+        SnapshotAction, Whence, CHUNK_SIZE,
+    };
+    use crate::frame::{
+        legacy_rejection, read_frame, write_frame, Frame, ProtocolError, HEADER_LEN, MAGIC,
+        MAX_PAYLOAD_LEN, PROTOCOL_VERSION,
+    };
+    use crate::response::{Body, NodeKind, Response, Stat, Status};
 
     #[test]
-    fn test_cd_to_bytes() {
+    fn test_cd_to_frame() {
         let target_dir = "Documents";
-        let user = 100u8;
         let command = Command::CD(target_dir.to_string());
-        let out = command.to_bytes(user);
-        let mut expected = vec![user, command.opt_code(), target_dir.len() as u8];
+        let out = command.to_frame().encode();
+        let mut expected = MAGIC.to_vec();
+        expected.extend([PROTOCOL_VERSION, command.opt_code(), 0]);
+        expected.extend(0u32.to_be_bytes());
+        expected.extend((target_dir.len() as u32 + 4).to_be_bytes());
+        expected.extend((target_dir.len() as u32).to_be_bytes());
         expected.extend(target_dir.as_bytes());
         assert_eq!(out, expected)
     }
     #[test]
-    fn test_ls_to_bytes() {
-        let command = Command::LS;
-        let user = 100u8;
-        let out = command.to_bytes(user);
-        let expected = vec![user, command.opt_code(), 0];
-        assert_eq!(out, expected)
+    fn test_ls_to_frame() {
+        let command = Command::LS {
+            path: String::new(),
+            options: ListOptions {
+                long: true,
+                ..ListOptions::default()
+            },
+        };
+        let out = command.to_frame().encode();
+        assert_eq!(out.len(), HEADER_LEN + 5);
+        assert_eq!(&out[HEADER_LEN - 4..], &[0, 0, 0, 5, 0, 0, 0, 0, 2])
+    }
+    #[test]
+    fn test_command_round_trip() {
+        let command = Command::READ("Downloads/test.hello".to_string());
+        let out = Command::from_frame(&command.to_frame()).unwrap();
+        assert_eq!(out, command)
+    }
+    #[test]
+    fn test_multi_argument_round_trip() {
+        let commands = vec![
+            Command::WRITE {
+                path: "notes.txt".to_string(),
+                // Used to be the argument delimiter, and isn't valid utf-8
+                data: b"a~%%~b\xff\x00".to_vec(),
+            },
+            Command::CP {
+                src: "Downloads/test.hello".to_string(),
+                dst: "Documents/test.hello".to_string(),
+                recursive: false,
+            },
+            Command::CP {
+                src: "Documents".to_string(),
+                dst: "Backup".to_string(),
+                recursive: true,
+            },
+            Command::MV {
+                src: "a b".to_string(),
+                dst: "c".to_string(),
+            },
+            Command::UPLOAD("big.bin".to_string()),
+            Command::CHUNK {
+                transfer: 3,
+                offset: 5 * CHUNK_SIZE as u64,
+                data: vec![7u8; CHUNK_SIZE],
+            },
+            Command::COMMIT(3),
+            Command::DOWNLOAD {
+                path: "big.bin".to_string(),
+                offset: u64::MAX,
+                len: CHUNK_SIZE as u32,
+            },
+            Command::LS {
+                path: "..".to_string(),
+                options: ListOptions::default(),
+            },
+            Command::FIND {
+                root: String::new(),
+                query: FindQuery::default(),
+            },
+            Command::FIND {
+                root: "/Documents".to_string(),
+                query: FindQuery {
+                    name: Some("*.txt".to_string()),
+                    kind: Some(NodeKind::File),
+                    size: Some(Compare::Above(1024)),
+                    mtime: Some(Compare::Below(7)),
+                    max_depth: Some(0),
+                    delete: true,
+                },
+            },
+            Command::APPEND {
+                path: "log.txt".to_string(),
+                data: b"line\n".to_vec(),
+            },
+            Command::OPEN {
+                path: "log.txt".to_string(),
+                options: OpenOptions::from_mode("a+").unwrap(),
+            },
+            Command::FDREAD { handle: 3, len: 64 },
+            Command::FDWRITE {
+                handle: 3,
+                data: b"more".to_vec(),
+            },
+            Command::SEEK {
+                handle: 3,
+                offset: -8,
+                whence: Whence::End,
+            },
+            Command::CLOSE(3),
+            Command::LOCK {
+                target: LockTarget::Path("log.txt".to_string()),
+                kind: LockKind::Shared,
+                wait_ms: None,
+            },
+            Command::LOCK {
+                target: LockTarget::Handle(3),
+                kind: LockKind::Exclusive,
+                wait_ms: Some(1500),
+            },
+            Command::UNLOCK(LockTarget::Handle(3)),
+            Command::SNAPSHOT {
+                action: SnapshotAction::Restore,
+                name: "before".to_string(),
+            },
+            Command::SNAPSHOT {
+                action: SnapshotAction::List,
+                name: String::new(),
+            },
+            Command::TRUNCATE {
+                path: "log.txt".to_string(),
+                size: 1 << 40,
+            },
+            Command::PWRITE {
+                path: "log.txt".to_string(),
+                offset: 12,
+                data: vec![0, 1, 2],
+            },
+            Command::GREP {
+                pattern: "fn [a-z]+".to_string(),
+                path: "src".to_string(),
+                options: GrepOptions {
+                    recursive: true,
+                    ignore_case: false,
+                    line_numbers: true,
+                },
+            },
+            Command::LS {
+                path: "/Documents".to_string(),
+                options: ListOptions {
+                    all: true,
+                    long: true,
+                    recursive: true,
+                    by_time: false,
+                    by_size: true,
+                },
+            },
+            Command::STAT("Downloads/test.hello".to_string()),
+            Command::SYMLINK {
+                target: "../Downloads".to_string(),
+                link: "dl".to_string(),
+            },
+            Command::READLINK("dl".to_string()),
+            Command::LINK {
+                target: "a".to_string(),
+                link: "b".to_string(),
+            },
+        ];
+        for command in commands {
+            let out = Command::from_frame(&command.to_frame()).unwrap();
+            assert_eq!(out, command)
+        }
+    }
+    #[test]
+    fn test_from_three_parts() {
+        let out = Command::from(("write", "notes.txt", "hello world"));
+        assert_eq!(
+            out,
+            Command::WRITE {
+                path: "notes.txt".to_string(),
+                data: b"hello world".to_vec()
+            }
+        )
+    }
+    #[test]
+    fn test_ln_s_from_parts() {
+        let out = Command::from(("ln", "-s", "/Downloads/test.hello hello"));
+        assert_eq!(
+            out,
+            Command::SYMLINK {
+                target: "/Downloads/test.hello".to_string(),
+                link: "hello".to_string(),
+            }
+        );
+        assert_eq!(Command::from(("ln", "-s", "missing")), Command::UNKNOWN);
+        assert_eq!(
+            Command::from(("ln", "a", "b")),
+            Command::LINK {
+                target: "a".to_string(),
+                link: "b".to_string(),
+            }
+        )
+    }
+    #[test]
+    fn test_cp_r_from_parts() {
+        let out = Command::from(("cp", "-r", "Documents Backup"));
+        assert_eq!(
+            out,
+            Command::CP {
+                src: "Documents".to_string(),
+                dst: "Backup".to_string(),
+                recursive: true,
+            }
+        )
+    }
+    #[test]
+    fn test_ls_from_parts() {
+        let out = Command::from(("ls", "-la", "-S /Documents"));
+        assert_eq!(
+            out,
+            Command::LS {
+                path: "/Documents".to_string(),
+                options: ListOptions {
+                    all: true,
+                    long: true,
+                    by_size: true,
+                    ..ListOptions::default()
+                },
+            }
+        );
+        let out = Command::from(("ls", ".."));
+        assert_eq!(
+            out,
+            Command::LS {
+                path: "..".to_string(),
+                options: ListOptions::default(),
+            }
+        );
+        assert_eq!(Command::from(("ls", "-z")), Command::UNKNOWN)
+    }
+    #[test]
+    fn test_find_from_parts() {
+        let out = Command::from(("find", "/", "-name *.txt -type d -size +2k -mtime 3"));
+        assert_eq!(
+            out,
+            Command::FIND {
+                root: "/".to_string(),
+                query: FindQuery {
+                    name: Some("*.txt".to_string()),
+                    kind: Some(NodeKind::Directory),
+                    size: Some(Compare::Above(2048)),
+                    mtime: Some(Compare::Exactly(3)),
+                    ..FindQuery::default()
+                },
+            }
+        );
+        let out = Command::from(("find", "-maxdepth", "1 -delete"));
+        assert_eq!(
+            out,
+            Command::FIND {
+                root: String::new(),
+                query: FindQuery {
+                    max_depth: Some(1),
+                    delete: true,
+                    ..FindQuery::default()
+                },
+            }
+        );
+        assert_eq!(Command::from(("find", "/", "-size")), Command::UNKNOWN);
+        assert_eq!(Command::from(("find", "/", "-type x")), Command::UNKNOWN)
+    }
+    #[test]
+    fn test_writes_from_parts() {
+        assert_eq!(
+            Command::from(("append", "log.txt", "more text")),
+            Command::APPEND {
+                path: "log.txt".to_string(),
+                data: b"more text".to_vec(),
+            }
+        );
+        assert_eq!(
+            Command::from(("echo", "hello", "world >> log.txt")),
+            Command::APPEND {
+                path: "log.txt".to_string(),
+                data: b"hello world\n".to_vec(),
+            }
+        );
+        assert_eq!(
+            Command::from(("echo", "hi>>log.txt")),
+            Command::APPEND {
+                path: "log.txt".to_string(),
+                data: b"hi\n".to_vec(),
+            }
+        );
+        assert_eq!(
+            Command::from(("truncate", "log.txt", "10")),
+            Command::TRUNCATE {
+                path: "log.txt".to_string(),
+                size: 10,
+            }
+        );
+        assert_eq!(
+            Command::from(("pwrite", "log.txt", "4 two words")),
+            Command::PWRITE {
+                path: "log.txt".to_string(),
+                offset: 4,
+                data: b"two words".to_vec(),
+            }
+        );
+        assert_eq!(Command::from(("echo", "no redirect")), Command::UNKNOWN);
+        assert_eq!(
+            Command::from(("truncate", "log.txt", "big")),
+            Command::UNKNOWN
+        );
+        assert_eq!(Command::from(("pwrite", "log.txt", "4")), Command::UNKNOWN)
+    }
+    #[test]
+    fn test_partial_reads_from_parts() {
+        assert_eq!(
+            Command::from(("head", "-c", "16 log.txt")),
+            Command::DOWNLOAD {
+                path: "log.txt".to_string(),
+                offset: 0,
+                len: 16,
+            }
+        );
+        assert_eq!(
+            Command::from(("read", "log.txt", "8 4")),
+            Command::DOWNLOAD {
+                path: "log.txt".to_string(),
+                offset: 8,
+                len: 4,
+            }
+        );
+        assert_eq!(
+            Command::from(("head", "-c", "lots log.txt")),
+            Command::UNKNOWN
+        )
+    }
+    #[test]
+    fn test_handles_from_parts() {
+        assert_eq!(
+            Command::from(("open", "log.txt")),
+            Command::OPEN {
+                path: "log.txt".to_string(),
+                options: OpenOptions {
+                    read: true,
+                    ..OpenOptions::default()
+                },
+            }
+        );
+        assert_eq!(
+            Command::from(("open", "log.txt", "w")),
+            Command::OPEN {
+                path: "log.txt".to_string(),
+                options: OpenOptions {
+                    write: true,
+                    create: true,
+                    truncate: true,
+                    ..OpenOptions::default()
+                },
+            }
+        );
+        assert_eq!(
+            Command::from(("fread", "3", "16")),
+            Command::FDREAD { handle: 3, len: 16 }
+        );
+        assert_eq!(
+            Command::from(("fwrite", "3", "some text")),
+            Command::FDWRITE {
+                handle: 3,
+                data: b"some text".to_vec(),
+            }
+        );
+        assert_eq!(
+            Command::from(("seek", "3", "-4 end")),
+            Command::SEEK {
+                handle: 3,
+                offset: -4,
+                whence: Whence::End,
+            }
+        );
+        assert_eq!(
+            Command::from(("seek", "3", "10")),
+            Command::SEEK {
+                handle: 3,
+                offset: 10,
+                whence: Whence::Start,
+            }
+        );
+        assert_eq!(Command::from(("close", "3")), Command::CLOSE(3));
+        assert_eq!(Command::from(("open", "log.txt", "x")), Command::UNKNOWN);
+        assert_eq!(Command::from(("seek", "3", "1 far")), Command::UNKNOWN);
+        assert_eq!(Command::from(("close", "three")), Command::UNKNOWN)
+    }
+    #[test]
+    fn test_snapshot_from_parts() {
+        assert_eq!(
+            Command::from(("snapshot", "create", "before")),
+            Command::SNAPSHOT {
+                action: SnapshotAction::Create,
+                name: "before".to_string(),
+            }
+        );
+        assert_eq!(
+            Command::from(("snapshot", "list")),
+            Command::SNAPSHOT {
+                action: SnapshotAction::List,
+                name: String::new(),
+            }
+        );
+        assert_eq!(Command::from(("snapshot", "delete")), Command::UNKNOWN);
+        assert_eq!(
+            Command::from(("snapshot", "restore", "two words")),
+            Command::UNKNOWN
+        );
+        assert_eq!(
+            Command::from(("snapshot", "list", "before")),
+            Command::UNKNOWN
+        );
+        assert_eq!(
+            Command::from(("snapshot", "take", "before")),
+            Command::UNKNOWN
+        )
+    }
+    #[test]
+    fn test_lock_from_parts() {
+        assert_eq!(
+            Command::from(("lock", "log.txt")),
+            Command::LOCK {
+                target: LockTarget::Path("log.txt".to_string()),
+                kind: LockKind::Exclusive,
+                wait_ms: None,
+            }
+        );
+        assert_eq!(
+            Command::from(("lock", "-s", "-w 1.5 log.txt")),
+            Command::LOCK {
+                target: LockTarget::Path("log.txt".to_string()),
+                kind: LockKind::Shared,
+                wait_ms: Some(1500),
+            }
+        );
+        assert_eq!(
+            Command::from(("lock", "-n", "-f 3")),
+            Command::LOCK {
+                target: LockTarget::Handle(3),
+                kind: LockKind::Exclusive,
+                wait_ms: Some(0),
+            }
+        );
+        assert_eq!(
+            Command::from(("unlock", "log.txt")),
+            Command::UNLOCK(LockTarget::Path("log.txt".to_string()))
+        );
+        assert_eq!(
+            Command::from(("unlock", "-f", "3")),
+            Command::UNLOCK(LockTarget::Handle(3))
+        );
+        assert_eq!(
+            Command::from(("lock", "-w", "soon log.txt")),
+            Command::UNKNOWN
+        );
+        assert_eq!(Command::from(("lock", "-s")), Command::UNKNOWN);
+        assert_eq!(Command::from(("unlock", "-s", "log.txt")), Command::UNKNOWN)
+    }
+    #[test]
+    fn test_grep_from_parts() {
+        let out = Command::from(("grep", "-rn", "-i todo src"));
+        assert_eq!(
+            out,
+            Command::GREP {
+                pattern: "todo".to_string(),
+                path: "src".to_string(),
+                options: GrepOptions {
+                    recursive: true,
+                    ignore_case: true,
+                    line_numbers: true,
+                },
+            }
+        );
+        let out = Command::from(("grep", "hello"));
+        assert_eq!(
+            out,
+            Command::GREP {
+                pattern: "hello".to_string(),
+                path: String::new(),
+                options: GrepOptions::default(),
+            }
+        );
+        assert_eq!(Command::from(("grep", "-x", "a b")), Command::UNKNOWN);
+        assert_eq!(Command::from(("grep", "-r")), Command::UNKNOWN);
+        assert_eq!(Command::from(("grep", "a", "b c")), Command::UNKNOWN)
+    }
+
+    #[test]
+    fn test_find_size_not_ascii() {
+        for size in ["é", "+é", "é5", "5é", "-ék"] {
+            let args = format!("-size {size}");
+            let out = Command::from(("find", "/", args.as_str()));
+            assert_eq!(out, Command::UNKNOWN, "{size}");
+        }
+    }
+    #[test]
+    fn test_compare() {
+        assert!(Compare::Above(3).test(4));
+        assert!(!Compare::Below(3).test(3));
+        assert!(Compare::Exactly(3).test(3))
+    }
+    #[test]
+    fn test_command_truncated() {
+        let mut frame = Command::CP {
+            src: "a".to_string(),
+            dst: "b".to_string(),
+            recursive: false,
+        }
+        .to_frame();
+        frame.payload.pop();
+        assert!(matches!(
+            Command::from_frame(&frame),
+            Err(ProtocolError::Malformed(..))
+        ))
+    }
+    #[tokio::test]
+    async fn test_frame_round_trip_past_u8() {
+        // Anything over 255 bytes used to be truncated by the u8 length
+        let frame = Frame::response(8, vec![b'a'; 70_000]);
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &frame).await.unwrap();
+        let out = read_frame(&mut wire.as_slice()).await.unwrap();
+        assert_eq!(out, frame);
+        assert!(out.is_response())
+    }
+    #[tokio::test]
+    async fn test_frames_keep_request_ids() {
+        let mut wire = Vec::new();
+        for id in [7u32, 3, u32::MAX] {
+            let frame = Command::PWD.to_frame().with_request_id(id);
+            write_frame(&mut wire, &frame).await.unwrap();
+        }
+        let mut reader = wire.as_slice();
+        for id in [7u32, 3, u32::MAX] {
+            let out = read_frame(&mut reader).await.unwrap();
+            assert_eq!(out.request_id, id);
+        }
+    }
+    #[tokio::test]
+    async fn test_read_frame_bad_magic() {
+        // What a pre-framing client sends for `ls` as user 1
+        let wire = vec![1u8, 4, 0];
+        let out = read_frame(&mut wire.as_slice()).await;
+        assert!(matches!(out, Err(ProtocolError::BadMagic)))
+    }
+    #[tokio::test]
+    async fn test_read_frame_wrong_version() {
+        let mut frame = Command::PWD.to_frame();
+        frame.version = PROTOCOL_VERSION + 1;
+        let wire = frame.encode();
+        let out = read_frame(&mut wire.as_slice()).await;
+        assert!(matches!(
+            out,
+            Err(ProtocolError::UnsupportedVersion(v)) if v == PROTOCOL_VERSION + 1
+        ))
+    }
+    #[tokio::test]
+    async fn test_read_frame_too_large() {
+        let mut wire = MAGIC.to_vec();
+        wire.extend([PROTOCOL_VERSION, 8, 0]);
+        wire.extend(0u32.to_be_bytes());
+        wire.extend((MAX_PAYLOAD_LEN + 1).to_be_bytes());
+        let out = read_frame(&mut wire.as_slice()).await;
+        assert!(matches!(out, Err(ProtocolError::PayloadTooLarge(..))))
+    }
+    #[test]
+    fn test_legacy_rejection() {
+        let out = legacy_rejection("upgrade");
+        assert_eq!(out[0] as usize, "upgrade".len());
+        assert_eq!(&out[1..], "upgrade".as_bytes())
     }
     #[test]
     fn test_from_opt() {
@@ -36,8 +602,89 @@ mod tests {
         ];
         let out = opts
             .into_iter()
-            .map(|o| Command::from(o))
+            .map(Command::from)
             .collect::<Vec<Command>>();
         assert_eq!(expected, out)
     }
+    #[test]
+    fn test_response_round_trip() {
+        let responses = vec![
+            Response::ok(),
+            Response::with_body(Body::Listing(vec![
+                "Documents".to_string(),
+                "Downloads".to_string(),
+            ])),
+            Response::with_body(Body::Bytes(vec![0, 159, 146, 150])),
+            Response::with_body(Body::Path("/Documents".to_string())),
+            Response::with_body(Body::User("TestUser".to_string())),
+            Response::with_body(Body::Handle(42)),
+            Response::with_body(Body::Stats(vec![test_stat()])),
+            Response::with_body(Body::Position(u64::MAX)),
+            Response::error(Status::NotFound, "Directory not found"),
+            Response::error(
+                Status::WouldBlock,
+                "log.txt: Resource temporarily unavailable",
+            ),
+            Response::error(
+                Status::ReadOnly,
+                "/.snapshots/before/log.txt: Read-only file system",
+            ),
+        ];
+        for response in responses {
+            let out = Response::from_frame(&response.to_frame(1)).unwrap();
+            assert_eq!(out, response)
+        }
+    }
+    fn test_stat() -> Stat {
+        Stat {
+            name: "test.hello".to_string(),
+            kind: NodeKind::File,
+            inode: 7,
+            links: 2,
+            size: 11,
+            owner: "Liz".to_string(),
+            group: "Liz".to_string(),
+            mode: 0o644,
+            created: 1,
+            modified: u64::MAX,
+            accessed: 3,
+            target: String::new(),
+        }
+    }
+    #[test]
+    fn test_mode_string() {
+        let mut stat = test_stat();
+        assert_eq!(stat.mode_string(), "-rw-r--r--");
+        stat.kind = NodeKind::Directory;
+        stat.mode = 0o751;
+        assert_eq!(stat.mode_string(), "drwxr-x--x");
+        stat.kind = NodeKind::Symlink;
+        stat.mode = 0o777;
+        assert_eq!(stat.mode_string(), "lrwxrwxrwx");
+        assert_eq!(stat.marked_name(), "test.hello@")
+    }
+    #[test]
+    fn test_response_file_content_is_not_an_error() {
+        // A file holding an error message still comes back as an ok body
+        let response = Response::with_body(Body::Bytes(b"Directory not found".to_vec()));
+        let out = Response::from_frame(&response.to_frame(8)).unwrap();
+        assert!(out.status.is_ok());
+        assert!(out.message.is_empty())
+    }
+    #[test]
+    fn test_empty_file_differs_from_empty_success() {
+        let empty_file = Response::with_body(Body::Bytes(Vec::new()));
+        let out = Response::from_frame(&empty_file.to_frame(8)).unwrap();
+        assert_eq!(out.body, Some(Body::Bytes(Vec::new())));
+        assert_ne!(out, Response::ok())
+    }
+    #[test]
+    fn test_response_truncated() {
+        let mut frame = Response::error(Status::NotFound, "missing").to_frame(1);
+        frame.payload.truncate(3);
+        assert!(matches!(
+            Response::from_frame(&frame),
+            Err(ProtocolError::Malformed(..))
+        ))
+    }
 }
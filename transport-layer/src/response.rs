@@ -0,0 +1,311 @@
+use std::fmt;
+
+use crate::frame::{Frame, ProtocolError};
+use crate::wire::{put_bytes, put_str, put_u32, put_u64, put_u8, Reader};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    PermissionDenied,
+    InvalidPath,
+    AlreadyExists,
+    InvalidInput,
+    Unsupported,
+    TooLarge,
+    TooManyLinks,
+    // A lock couldn't be had in time, or waiting for it could never end
+    WouldBlock,
+    // Writes under a snapshot
+    ReadOnly,
+    Internal,
+}
+impl Status {
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Ok => 0,
+            Self::NotFound => 1,
+            Self::NotADirectory => 2,
+            Self::IsADirectory => 3,
+            Self::PermissionDenied => 4,
+            Self::InvalidPath => 5,
+            Self::AlreadyExists => 6,
+            Self::InvalidInput => 7,
+            Self::Unsupported => 8,
+            Self::TooLarge => 9,
+            Self::TooManyLinks => 10,
+            Self::WouldBlock => 11,
+            Self::ReadOnly => 12,
+            Self::Internal => u8::MAX,
+        }
+    }
+    pub fn is_ok(&self) -> bool {
+        *self == Self::Ok
+    }
+}
+impl TryFrom<u8> for Status {
+    type Error = ProtocolError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Ok),
+            1 => Ok(Self::NotFound),
+            2 => Ok(Self::NotADirectory),
+            3 => Ok(Self::IsADirectory),
+            4 => Ok(Self::PermissionDenied),
+            5 => Ok(Self::InvalidPath),
+            6 => Ok(Self::AlreadyExists),
+            7 => Ok(Self::InvalidInput),
+            8 => Ok(Self::Unsupported),
+            9 => Ok(Self::TooLarge),
+            10 => Ok(Self::TooManyLinks),
+            11 => Ok(Self::WouldBlock),
+            12 => Ok(Self::ReadOnly),
+            u8::MAX => Ok(Self::Internal),
+            _ => Err(ProtocolError::Malformed("unknown status code")),
+        }
+    }
+}
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Ok => "ok",
+            Self::NotFound => "not found",
+            Self::NotADirectory => "not a directory",
+            Self::IsADirectory => "is a directory",
+            Self::PermissionDenied => "permission denied",
+            Self::InvalidPath => "invalid path",
+            Self::AlreadyExists => "already exists",
+            Self::InvalidInput => "invalid input",
+            Self::Unsupported => "unsupported",
+            Self::TooLarge => "too large",
+            Self::TooManyLinks => "too many links",
+            Self::WouldBlock => "would block",
+            Self::ReadOnly => "read-only",
+            Self::Internal => "internal error",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+pub enum NodeKind {
+    File,
+    Directory,
+    Symlink,
+}
+impl NodeKind {
+    pub(crate) fn code(&self) -> u8 {
+        match self {
+            Self::File => 0,
+            Self::Directory => 1,
+            Self::Symlink => 2,
+        }
+    }
+    pub(crate) fn from_code(code: u8) -> Result<Self, ProtocolError> {
+        match code {
+            0 => Ok(Self::File),
+            1 => Ok(Self::Directory),
+            2 => Ok(Self::Symlink),
+            _ => Err(ProtocolError::Malformed("unknown node kind")),
+        }
+    }
+}
+
+// Metadata of one node, times are nanoseconds since the unix epoch.
+// Links carry what they point at, other kinds leave target empty
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stat {
+    pub name: String,
+    pub kind: NodeKind,
+    pub inode: u64,
+    pub links: u32,
+    pub size: u64,
+    pub owner: String,
+    pub group: String,
+    pub mode: u32,
+    pub created: u64,
+    pub modified: u64,
+    pub accessed: u64,
+    pub target: String,
+}
+impl Stat {
+    // Permissions the way `ls -l` prints them, e.g. drwxr-xr-x
+    pub fn mode_string(&self) -> String {
+        let mut out = String::with_capacity(10);
+        out.push(match self.kind {
+            NodeKind::File => '-',
+            NodeKind::Directory => 'd',
+            NodeKind::Symlink => 'l',
+        });
+        for shift in [6, 3, 0] {
+            let bits = (self.mode >> shift) & 0o7;
+            out.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+            out.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+            out.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+        }
+        out
+    }
+    // Name with the `ls -F` style marker of its kind
+    pub fn marked_name(&self) -> String {
+        match self.kind {
+            NodeKind::File => self.name.clone(),
+            NodeKind::Directory => format!("{}/", self.name),
+            NodeKind::Symlink => format!("{}@", self.name),
+        }
+    }
+    fn encode(&self, out: &mut Vec<u8>) {
+        put_str(out, &self.name);
+        put_u8(out, self.kind.code());
+        put_u64(out, self.inode);
+        put_u32(out, self.links);
+        put_u64(out, self.size);
+        put_str(out, &self.owner);
+        put_str(out, &self.group);
+        put_u32(out, self.mode);
+        put_u64(out, self.created);
+        put_u64(out, self.modified);
+        put_u64(out, self.accessed);
+        put_str(out, &self.target);
+    }
+    fn decode(reader: &mut Reader) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            name: reader.string()?,
+            kind: NodeKind::from_code(reader.u8()?)?,
+            inode: reader.u64()?,
+            links: reader.u32()?,
+            size: reader.u64()?,
+            owner: reader.string()?,
+            group: reader.string()?,
+            mode: reader.u32()?,
+            created: reader.u64()?,
+            modified: reader.u64()?,
+            accessed: reader.u64()?,
+            target: reader.string()?,
+        })
+    }
+}
+
+// Typed result data, kept apart from the message so file contents can
+// never be mistaken for an error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Listing(Vec<String>),
+    Bytes(Vec<u8>),
+    Path(String),
+    User(String),
+    // Server side handle, such as an open transfer
+    Handle(u32),
+    Stats(Vec<Stat>),
+    // Offset into an open file, such as where a seek landed
+    Position(u64),
+}
+impl Body {
+    fn tag(&self) -> u8 {
+        match self {
+            Self::Listing(..) => 1,
+            Self::Bytes(..) => 2,
+            Self::Path(..) => 3,
+            Self::User(..) => 4,
+            Self::Handle(..) => 5,
+            Self::Stats(..) => 6,
+            Self::Position(..) => 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: Status,
+    pub body: Option<Body>,
+    pub message: String,
+}
+impl Response {
+    pub fn ok() -> Self {
+        Self {
+            status: Status::Ok,
+            body: None,
+            message: String::new(),
+        }
+    }
+    pub fn with_body(body: Body) -> Self {
+        Self {
+            body: Some(body),
+            ..Self::ok()
+        }
+    }
+    pub fn error(status: Status, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            body: None,
+            message: message.into(),
+        }
+    }
+    // Format status<u8>message<str>tag<u8>body, tag 0 is no body
+    pub fn to_frame(&self, opcode: u8) -> Frame {
+        let mut payload = Vec::new();
+        put_u8(&mut payload, self.status.code());
+        put_str(&mut payload, &self.message);
+        match &self.body {
+            None => put_u8(&mut payload, 0),
+            Some(body) => {
+                put_u8(&mut payload, body.tag());
+                match body {
+                    Body::Listing(entries) => {
+                        put_u32(&mut payload, entries.len() as u32);
+                        for entry in entries {
+                            put_str(&mut payload, entry);
+                        }
+                    }
+                    Body::Bytes(data) => put_bytes(&mut payload, data),
+                    Body::Path(value) | Body::User(value) => put_str(&mut payload, value),
+                    Body::Handle(handle) => put_u32(&mut payload, *handle),
+                    Body::Position(offset) => put_u64(&mut payload, *offset),
+                    Body::Stats(stats) => {
+                        put_u32(&mut payload, stats.len() as u32);
+                        for stat in stats {
+                            stat.encode(&mut payload);
+                        }
+                    }
+                }
+            }
+        }
+        Frame::response(opcode, payload)
+    }
+    pub fn from_frame(frame: &Frame) -> Result<Self, ProtocolError> {
+        let mut reader = Reader::new(&frame.payload);
+        let status = Status::try_from(reader.u8()?)?;
+        let message = reader.string()?;
+        let body = match reader.u8()? {
+            0 => None,
+            1 => {
+                let count = reader.u32()?;
+                let mut entries = Vec::new();
+                for _ in 0..count {
+                    entries.push(reader.string()?);
+                }
+                Some(Body::Listing(entries))
+            }
+            2 => Some(Body::Bytes(reader.bytes()?)),
+            3 => Some(Body::Path(reader.string()?)),
+            4 => Some(Body::User(reader.string()?)),
+            5 => Some(Body::Handle(reader.u32()?)),
+            6 => {
+                let count = reader.u32()?;
+                let mut stats = Vec::new();
+                for _ in 0..count {
+                    stats.push(Stat::decode(&mut reader)?);
+                }
+                Some(Body::Stats(stats))
+            }
+            7 => Some(Body::Position(reader.u64()?)),
+            _ => return Err(ProtocolError::Malformed("unknown body tag")),
+        };
+        Ok(Self {
+            status,
+            body,
+            message,
+        })
+    }
+}
@@ -0,0 +1,61 @@
+/*
+Field encoding used inside frame payloads.
+Integers are big endian, variable length fields are prefixed by a u32 length.
+ */
+use crate::frame::ProtocolError;
+
+pub fn put_u8(out: &mut Vec<u8>, value: u8) {
+    out.push(value);
+}
+pub fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend(value.to_be_bytes());
+}
+pub fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend(value.to_be_bytes());
+}
+pub fn put_bytes(out: &mut Vec<u8>, value: &[u8]) {
+    put_u32(out, value.len() as u32);
+    out.extend(value);
+}
+pub fn put_str(out: &mut Vec<u8>, value: &str) {
+    put_bytes(out, value.as_bytes());
+}
+
+// Cursor over a payload, every read fails cleanly on truncated input
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.buf.len() < len {
+            return Err(ProtocolError::Malformed("payload truncated"));
+        }
+        let (head, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(head)
+    }
+    pub fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn u32(&mut self) -> Result<u32, ProtocolError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+    pub fn u64(&mut self) -> Result<u64, ProtocolError> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+    pub fn bytes(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+    pub fn string(&mut self) -> Result<String, ProtocolError> {
+        String::from_utf8(self.bytes()?).map_err(|_| ProtocolError::Malformed("field is not utf-8"))
+    }
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}